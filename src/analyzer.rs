@@ -0,0 +1,450 @@
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use deunicode::deunicode_char;
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Serialize, Deserialize};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Only the start of a long text is looked at to detect its language
+const LANGUAGE_DETECTION_BYTES: usize = 2048;
+
+// Languages with both a stop word list and a Snowball stemmer
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Language {
+    Arabic,
+    Danish,
+    Dutch,
+    English,
+    Finnish,
+    French,
+    German,
+    Greek,
+    Hungarian,
+    Italian,
+    Norwegian,
+    Portuguese,
+    Romanian,
+    Russian,
+    Spanish,
+    Swedish,
+    Turkish,
+}
+
+impl Language {
+    const ALL: [Language; 17] = [
+        Language::Arabic, Language::Danish, Language::Dutch, Language::English, Language::Finnish,
+        Language::French, Language::German, Language::Greek, Language::Hungarian, Language::Italian,
+        Language::Norwegian, Language::Portuguese, Language::Romanian, Language::Russian, Language::Spanish,
+        Language::Swedish, Language::Turkish,
+    ];
+
+    // The language of the ISO 639-1 code, None if it has no stop words and stemmer
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.to_lowercase();
+        Self::ALL.into_iter().find(|language| language.code() == code)
+    }
+
+    fn detected(lang: whatlang::Lang) -> Option<Self> {
+        match lang {
+            whatlang::Lang::Ara => Some(Language::Arabic),
+            whatlang::Lang::Dan => Some(Language::Danish),
+            whatlang::Lang::Nld => Some(Language::Dutch),
+            whatlang::Lang::Eng => Some(Language::English),
+            whatlang::Lang::Fin => Some(Language::Finnish),
+            whatlang::Lang::Fra => Some(Language::French),
+            whatlang::Lang::Deu => Some(Language::German),
+            whatlang::Lang::Ell => Some(Language::Greek),
+            whatlang::Lang::Hun => Some(Language::Hungarian),
+            whatlang::Lang::Ita => Some(Language::Italian),
+            whatlang::Lang::Nob => Some(Language::Norwegian),
+            whatlang::Lang::Por => Some(Language::Portuguese),
+            whatlang::Lang::Ron => Some(Language::Romanian),
+            whatlang::Lang::Rus => Some(Language::Russian),
+            whatlang::Lang::Spa => Some(Language::Spanish),
+            whatlang::Lang::Swe => Some(Language::Swedish),
+            whatlang::Lang::Tur => Some(Language::Turkish),
+            _ => None,
+        }
+    }
+
+    // ISO 639-1 code, the key of the stop-words lists
+    fn code(self) -> &'static str {
+        match self {
+            Language::Arabic => "ar",
+            Language::Danish => "da",
+            Language::Dutch => "nl",
+            Language::English => "en",
+            Language::Finnish => "fi",
+            Language::French => "fr",
+            Language::German => "de",
+            Language::Greek => "el",
+            Language::Hungarian => "hu",
+            Language::Italian => "it",
+            Language::Norwegian => "no",
+            Language::Portuguese => "pt",
+            Language::Romanian => "ro",
+            Language::Russian => "ru",
+            Language::Spanish => "es",
+            Language::Swedish => "sv",
+            Language::Turkish => "tr",
+        }
+    }
+
+    fn algorithm(self) -> Algorithm {
+        match self {
+            Language::Arabic => Algorithm::Arabic,
+            Language::Danish => Algorithm::Danish,
+            Language::Dutch => Algorithm::Dutch,
+            Language::English => Algorithm::English,
+            Language::Finnish => Algorithm::Finnish,
+            Language::French => Algorithm::French,
+            Language::German => Algorithm::German,
+            Language::Greek => Algorithm::Greek,
+            Language::Hungarian => Algorithm::Hungarian,
+            Language::Italian => Algorithm::Italian,
+            Language::Norwegian => Algorithm::Norwegian,
+            Language::Portuguese => Algorithm::Portuguese,
+            Language::Romanian => Algorithm::Romanian,
+            Language::Russian => Algorithm::Russian,
+            Language::Spanish => Algorithm::Spanish,
+            Language::Swedish => Algorithm::Swedish,
+            Language::Turkish => Algorithm::Turkish,
+        }
+    }
+}
+
+// One step of the analysis of a word, the filters of an analyzer are applied in order
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenFilter {
+    Lowercase,
+    // Latin letters with diacritics and ligatures to their ASCII spelling, e.g. "Æsir" to "AEsir"
+    AsciiFolding,
+    // Drop the stop words of the language, compared with the token as the previous filters left it
+    StopWords(Language),
+    // Snowball stemmer of the language, the English one is Porter2, the revised Porter stemmer
+    Stemmer(Language),
+    // Stop words of the language of the text, see Analyzer::language
+    LanguageStopWords,
+    // Stemmer of the language of the text
+    LanguageStemmer,
+    // Drop the numbers below the value, such as list numbering
+    MinNumber(f64),
+    // Drop the tokens with fewer characters
+    MinLength(usize),
+    // Drop the tokens with more characters
+    MaxLength(usize),
+}
+
+// How the text of the documents and queries is turned into terms. It is saved in the index config, queries
+// have to be analyzed exactly like the documents were for their terms to match.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AnalyzerConfig {
+    pub filters: Vec<TokenFilter>,
+    // Turn the runs of Chinese and Japanese characters and the Korean words, which are not separated like the
+    // words of other scripts, into overlapping pairs of characters
+    #[serde(default)]
+    pub cjk_bigrams: bool,
+    // Language of the text whose language is not declared and cannot be detected reliably
+    #[serde(default = "default_language")]
+    pub default_language: Language,
+}
+
+fn default_language() -> Language {
+    Language::English
+}
+
+impl Default for AnalyzerConfig {
+    // The stop words and stemmer of the language of every document and query, so that "running" matches
+    // "runs" and "corriendo" matches "correr"
+    fn default() -> Self {
+        Self {
+            filters: vec![
+                TokenFilter::Lowercase,
+                TokenFilter::LanguageStopWords,
+                TokenFilter::MinNumber(10.0),
+                TokenFilter::LanguageStemmer,
+            ],
+            cjk_bigrams: true,
+            default_language: default_language(),
+        }
+    }
+}
+
+enum Filter {
+    Lowercase,
+    AsciiFolding,
+    StopWords(HashSet<String>),
+    Stemmer(Stemmer),
+    LanguageStopWords,
+    LanguageStemmer,
+    MinNumber(f64),
+    MinLength(usize),
+    MaxLength(usize),
+}
+
+// The filters of an AnalyzerConfig, with their stop word lists and stemmers loaded. Words are the Unicode
+// words of the NFKC normalized text, with dots, underscores, hyphens and quotes as delimiters too.
+pub struct Analyzer {
+    filters: Vec<Filter>,
+    cjk_bigrams: bool,
+    default_language: Language,
+    // Stop words of the languages met by the language filters, loaded on first use
+    language_stop_words: [OnceLock<HashSet<String>>; Language::ALL.len()],
+}
+
+impl Analyzer {
+    pub fn new(config: &AnalyzerConfig) -> Self {
+        let filters = config.filters.iter()
+            .map(|filter| match *filter {
+                TokenFilter::Lowercase => Filter::Lowercase,
+                TokenFilter::AsciiFolding => Filter::AsciiFolding,
+                TokenFilter::StopWords(language) => Filter::StopWords(stop_words::get(language.code()).into_iter().collect()),
+                TokenFilter::Stemmer(language) => Filter::Stemmer(Stemmer::create(language.algorithm())),
+                TokenFilter::LanguageStopWords => Filter::LanguageStopWords,
+                TokenFilter::LanguageStemmer => Filter::LanguageStemmer,
+                TokenFilter::MinNumber(min) => Filter::MinNumber(min),
+                TokenFilter::MinLength(length) => Filter::MinLength(length),
+                TokenFilter::MaxLength(length) => Filter::MaxLength(length),
+            })
+            .collect();
+
+        Self {
+            filters,
+            cjk_bigrams: config.cjk_bigrams,
+            default_language: config.default_language,
+            language_stop_words: std::array::from_fn(|_| OnceLock::new()),
+        }
+    }
+
+    pub fn cjk_bigrams(&self) -> bool {
+        self.cjk_bigrams
+    }
+
+    // Language of the text for the language filters: the declared one, else the detected one, else the default
+    // when the detection is not reliable. None for a language without stop words and stemmer, such as Chinese.
+    pub fn language(&self, text: &str, declared: Option<Language>) -> Option<Language> {
+        let uses_language = self.filters.iter().any(|filter| matches!(filter, Filter::LanguageStopWords | Filter::LanguageStemmer));
+        if declared.is_some() || !uses_language {
+            return declared;
+        }
+
+        let mut end = text.len().min(LANGUAGE_DETECTION_BYTES);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        match whatlang::detect(&text[..end]) {
+            Some(info) if info.is_reliable() => Language::detected(info.lang()),
+            _ => Some(self.default_language),
+        }
+    }
+
+    // Terms of the text in the language, in order
+    pub fn analyze(&self, text: &str, language: Option<Language>) -> Vec<String> {
+        // Normalize the text to NFKC (Normalization Form KC: Compatibility Composition)
+        let normalized = text.nfkc().collect::<String>();
+
+        // Replace "." and "_" and "-" and quotation marks with whitespace to treat them as delimiters
+        let replaced = normalized.replace(".", " ")
+            .replace("_", " ")
+            .replace("-", " ")
+            .replace("\"", " ")
+            .replace("\'", " ");
+
+        // Tokenize into words using unicode segmentation. Every Chinese character is a word of its own,
+        // the consecutive CJK words are collected and replaced by their pairs of characters.
+        let mut terms = Vec::new();
+        let mut cjk_run = String::new();
+        let mut cjk_run_end = 0;
+        for (start, word) in replaced.unicode_word_indices() {
+            if self.cjk_bigrams && word.chars().all(is_cjk) {
+                if start != cjk_run_end {
+                    self.push_bigrams(&mut cjk_run, language, &mut terms);
+                }
+                cjk_run.push_str(word);
+                cjk_run_end = start + word.len();
+            } else {
+                self.push_bigrams(&mut cjk_run, language, &mut terms);
+                terms.extend(self.filter(word, language));
+            }
+        }
+        self.push_bigrams(&mut cjk_run, language, &mut terms);
+        terms
+    }
+
+    // The overlapping character pairs of the run, or its only character, then clear it
+    fn push_bigrams(&self, run: &mut String, language: Option<Language>, terms: &mut Vec<String>) {
+        let chars: Vec<char> = run.chars().collect();
+        if chars.len() == 1 {
+            terms.extend(self.filter(run, language));
+        }
+        for pair in chars.windows(2) {
+            terms.extend(self.filter(&pair.iter().collect::<String>(), language));
+        }
+        run.clear();
+    }
+
+    // The term of the word, None if a filter drops it
+    fn filter(&self, word: &str, language: Option<Language>) -> Option<String> {
+        let mut token = word.to_string();
+        for filter in &self.filters {
+            match filter {
+                Filter::Lowercase => token = token.to_lowercase(),
+                Filter::AsciiFolding => token = fold_to_ascii(&token),
+                Filter::StopWords(stop_words) => {
+                    if stop_words.contains(&token) {
+                        return None;
+                    }
+                },
+                Filter::Stemmer(stemmer) => token = stemmer.stem(&token).into_owned(),
+                Filter::LanguageStopWords => {
+                    if language.is_some_and(|language| self.stop_words(language).contains(&token)) {
+                        return None;
+                    }
+                },
+                Filter::LanguageStemmer => {
+                    if let Some(language) = language {
+                        token = Stemmer::create(language.algorithm()).stem(&token).into_owned();
+                    }
+                },
+                Filter::MinNumber(min) => {
+                    // NaN, which "nan" parses to, is not above any minimum either
+                    if token.parse::<f64>().is_ok_and(|number| number < *min || number.is_nan()) {
+                        return None;
+                    }
+                },
+                Filter::MinLength(length) => {
+                    if token.chars().count() < *length {
+                        return None;
+                    }
+                },
+                Filter::MaxLength(length) => {
+                    if token.chars().count() > *length {
+                        return None;
+                    }
+                },
+            }
+        }
+        Some(token)
+    }
+
+    fn stop_words(&self, language: Language) -> &HashSet<String> {
+        self.language_stop_words[language as usize]
+            .get_or_init(|| stop_words::get(language.code()).into_iter().collect())
+    }
+}
+
+// Chinese characters, the Japanese kana and the Korean Hangul
+pub fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11FF}' | '\u{2E80}'..='\u{2FDF}' | '\u{3005}'..='\u{3007}' | '\u{3021}'..='\u{3029}'
+        | '\u{3040}'..='\u{30FF}' | '\u{3130}'..='\u{318F}' | '\u{31F0}'..='\u{31FF}' | '\u{3400}'..='\u{4DBF}'
+        | '\u{4E00}'..='\u{9FFF}' | '\u{AC00}'..='\u{D7AF}' | '\u{F900}'..='\u{FAFF}' | '\u{20000}'..='\u{2FA1F}')
+}
+
+// Only the letters of the Latin blocks are folded, the words of other scripts are kept as they are
+fn fold_to_ascii(token: &str) -> String {
+    let mut folded = String::with_capacity(token.len());
+    for c in token.chars() {
+        match c {
+            // Latin-1 Supplement, Latin Extended-A and B, and Latin Extended Additional
+            '\u{00C0}'..='\u{024F}' | '\u{1E00}'..='\u{1EFF}' if c.is_alphabetic() => match deunicode_char(c) {
+                Some(ascii) => folded.push_str(ascii),
+                None => folded.push(c),
+            },
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn english(text: &str) -> Vec<String> {
+        Analyzer::new(&AnalyzerConfig::default()).analyze(text, Some(Language::English))
+    }
+
+    fn analyze(filters: Vec<TokenFilter>, text: &str) -> Vec<String> {
+        let config = AnalyzerConfig { filters, cjk_bigrams: false, default_language: Language::English };
+        Analyzer::new(&config).analyze(text, None)
+    }
+
+    #[test]
+    fn inflections_stem_to_the_same_term() {
+        assert_eq!(english("running"), english("runs"));
+        assert_eq!(english("Connections connected"), ["connect", "connect"]);
+        assert_eq!(
+            Analyzer::new(&AnalyzerConfig::default()).analyze("corriendo", Some(Language::Spanish)),
+            Analyzer::new(&AnalyzerConfig::default()).analyze("corren", Some(Language::Spanish)),
+        );
+    }
+
+    #[test]
+    fn stop_words_and_small_numbers_are_dropped() {
+        assert_eq!(english("The jaguar and the walnut"), ["jaguar", "walnut"]);
+        assert_eq!(english("chapter 3 of 1984"), ["chapter", "1984"]);
+        // Dots, underscores, hyphens and quotes separate words
+        assert_eq!(english("blue-green \"sea_walnut\" v2.zephyr"), ["blue", "green", "sea", "walnut", "v2", "zephyr"]);
+    }
+
+    #[test]
+    fn filters_are_applied_in_order() {
+        // The stop words are compared with the token as the previous filters left it
+        assert_eq!(analyze(vec![TokenFilter::StopWords(Language::English)], "The the"), ["The"]);
+        assert!(analyze(vec![TokenFilter::Lowercase, TokenFilter::StopWords(Language::English)], "The the").is_empty());
+        assert_eq!(analyze(vec![TokenFilter::Stemmer(Language::English)], "jumping"), ["jump"]);
+        assert_eq!(analyze(vec![TokenFilter::AsciiFolding], "Æsir café Ελλάδα"), ["AEsir", "cafe", "Ελλάδα"]);
+        assert_eq!(analyze(vec![TokenFilter::MinNumber(10.0)], "7 10 nan 2024"), ["10", "2024"]);
+        assert_eq!(analyze(vec![TokenFilter::MinLength(3), TokenFilter::MaxLength(5)], "a an ant antle antler"), ["ant", "antle"]);
+        // Without language filters, the text is analyzed the same whatever its language
+        assert_eq!(analyze(vec![TokenFilter::Lowercase], "Running runs"), ["running", "runs"]);
+    }
+
+    #[test]
+    fn cjk_runs_become_overlapping_bigrams() {
+        let analyzer = Analyzer::new(&AnalyzerConfig::default());
+        assert_eq!(analyzer.analyze("北京大学", None), ["北京", "京大", "大学"]);
+        // A run ends at any other word or punctuation, a lone character is a term of its own
+        assert_eq!(analyzer.analyze("東京タワー zephyr 山。川の", None), ["東京", "京タ", "タワ", "ワー", "zephyr", "山", "川の"]);
+        assert_eq!(analyzer.analyze("한국어 사전", None), ["한국", "국어", "사전"]);
+
+        let config = AnalyzerConfig { cjk_bigrams: false, ..AnalyzerConfig::default() };
+        assert_eq!(Analyzer::new(&config).analyze("北京大学", None), ["北", "京", "大", "学"]);
+    }
+
+    #[test]
+    fn the_language_is_declared_detected_or_the_default() {
+        let analyzer = Analyzer::new(&AnalyzerConfig::default());
+        let spanish = "El perro corre por el parque todos los días mientras los niños juegan con la pelota";
+        let german = "Der Hund läuft jeden Tag durch den Park, während die Kinder mit dem Ball spielen";
+        assert_eq!(analyzer.language(spanish, None), Some(Language::Spanish));
+        assert_eq!(analyzer.language(german, None), Some(Language::German));
+        assert_eq!(analyzer.language(german, Some(Language::Dutch)), Some(Language::Dutch));
+        // Too short to be detected reliably
+        assert_eq!(analyzer.language("zephyr", None), Some(Language::English));
+        // A reliably detected language without stop words and stemmer
+        assert_eq!(analyzer.language("北京大学是中国最著名的大学之一，位于北京市海淀区", None), None);
+
+        // The stop words and stemmer are the ones of the detected language
+        assert_eq!(analyzer.analyze("Los niños juegan", analyzer.language(spanish, None)), ["niñ", "jueg"]);
+
+        // Without language filters the language is not needed
+        let config = AnalyzerConfig { filters: vec![TokenFilter::Lowercase], ..AnalyzerConfig::default() };
+        assert_eq!(Analyzer::new(&config).language(spanish, None), None);
+    }
+
+    #[test]
+    fn configs_round_trip_through_toml() {
+        let config = AnalyzerConfig {
+            filters: vec![TokenFilter::Lowercase, TokenFilter::StopWords(Language::German), TokenFilter::MinLength(2)],
+            cjk_bigrams: false,
+            default_language: Language::French,
+        };
+        let saved = toml::to_string(&config).unwrap();
+        assert_eq!(toml::from_str::<AnalyzerConfig>(&saved).unwrap(), config);
+    }
+}
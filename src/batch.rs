@@ -0,0 +1,82 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use log::warn;
+use crate::term_query_processor::{QueryMode, TermQueryProcessor};
+
+// Evaluate every query of the TSV file (qid, text) and write the top depth documents of each as a TREC run file,
+// one `qid Q0 docno rank score tag` line per document
+pub fn run_queries(processor: &TermQueryProcessor, queries_path: &Path, output_path: &Path, mode: QueryMode,
+                   depth: usize, tag: &str, threads: Option<usize>) -> io::Result<()> {
+    let rankings = retrieve(processor, queries_path, mode, depth, threads)?;
+
+    let mut output = BufWriter::new(File::create(output_path)?);
+    for (qid, ranking) in &rankings {
+        for (rank, (docno, score)) in ranking.iter().enumerate() {
+            writeln!(output, "{} Q0 {} {} {:.6} {}", qid, docno, rank + 1, score, tag)?;
+        }
+    }
+    output.flush()?;
+
+    let retrieved: usize = rankings.iter().map(|(_, ranking)| ranking.len()).sum();
+    println!("Ran {} queries, {} documents retrieved", rankings.len(), retrieved);
+    Ok(())
+}
+
+// The (docno, score) pairs retrieved for a query, best first
+pub type Ranking = Vec<(String, f32)>;
+
+// (qid, ranking) of every query of the TSV file in file order, each ranking holding the top depth documents.
+// Queries are spread over threads.
+pub fn retrieve(processor: &TermQueryProcessor, queries_path: &Path, mode: QueryMode, depth: usize,
+                threads: Option<usize>) -> io::Result<Vec<(String, Ranking)>> {
+    let queries = read_queries(&fs::read_to_string(queries_path)?);
+    let threads = threads
+        .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+        .max(1);
+    let chunk_size = queries.len().div_ceil(threads).max(1);
+
+    let rankings = thread::scope(|scope| {
+        let workers: Vec<_> = queries.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || {
+                chunk.iter()
+                    .map(|(qid, text)| match processor.top_k(mode, text, depth, None, None) {
+                        Ok(top_docs) => top_docs.docs,
+                        Err(e) => {
+                            warn!("Query {} failed, it gets no results: {}", qid, e);
+                            Vec::new()
+                        },
+                    })
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+
+    Ok(queries.into_iter()
+        .zip(rankings)
+        .map(|((qid, _), ranking)| {
+            let ranking = ranking.into_iter()
+                // Documents without a DOCNO are identified by their docid
+                .map(|(doc_id, score)| (processor.docno(doc_id).map_or_else(|| doc_id.to_string(), str::to_string), score))
+                .collect();
+            (qid, ranking)
+        })
+        .collect())
+}
+
+// (qid, text) pairs of the TSV lines, blank and malformed lines are skipped
+fn read_queries(contents: &str) -> Vec<(String, String)> {
+    contents.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .filter_map(|(number, line)| match line.split_once('\t') {
+            Some((qid, text)) if !qid.trim().is_empty() => Some((qid.trim().to_string(), text.trim().to_string())),
+            _ => {
+                warn!("Skipping line {} of the queries file, expected qid<TAB>text", number + 1);
+                None
+            },
+        })
+        .collect()
+}
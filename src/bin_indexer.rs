@@ -6,18 +6,28 @@ extern crate bincode;
 
 use std::fs::File;
 use std::io::Read;
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+#[cfg(feature = "debug_unicode")]
+use std::io::BufRead;
+#[cfg(feature = "debug_unicode")]
 use serde_json::Value;
 
 use stream_vbyte::{
     encode::encode,
     scalar::Scalar
 };
-use crate::utils::DIRECTORY_NTH_TERM;
+use crate::config::IndexConfig;
+use crate::doc_table::DocTable;
+#[cfg(not(feature = "debug_unicode"))]
+use crate::external_sorter::read_record;
+use crate::fields::{Field, FIELDS};
+use crate::scoring::{CollectionStatistics, TermStatistics};
 
 
-const BLOCK_SIZE: usize = 64;
+// Byte offsets of the lexicon header fields
+pub const LEXICON_HAS_POSITIONS: u64 = 4;
+pub const LEXICON_OFFSET_TABLE_POSITION: u64 = 8;
 
 #[derive(Clone, Debug)]
 pub struct TermMetadata {
@@ -28,90 +38,128 @@ pub struct TermMetadata {
     pub(crate) num_blocks: u32,
     pub(crate) num_posting_in_last_block: u32,
     pub(crate) last_doc_id: u32,
+    // Highest score of the term in any document, for dynamic pruning, see BoundParameters
+    pub(crate) max_score: f32,
     pub(crate) compressed_docids_per_block: Vec<u64>,
     pub(crate) block_offsets: Vec<u64>,
     pub(crate) block_maxima: Vec<u32>,
+    pub(crate) block_max_scores: Vec<f32>,
+    // Empty when the index was built without positions
+    pub(crate) compressed_positions_per_block: Vec<u64>,
 }
 
-pub fn build_bin_index(posting_path: &str, index_path: &str, lexicon_path: &str, directory_path: &str) -> std::io::Result<()> {
-    let mut file = File::open(posting_path)?;
-    let mut index_file = BufWriter::new(File::create(index_path)?);
-    let mut lexicon_file = BufWriter::new(File::create(lexicon_path)?);
-    let mut directory_file = BufWriter::new(File::create(directory_path)?);
+// Score of a posting from the positions of the term in the document, the field of a field term, the statistics
+// of the term and the docid
+type PostingScore<'a> = dyn Fn(&[u32], Option<Field>, &TermStatistics, u32) -> f32 + 'a;
 
-    // A placeholder for the total number of terms processed
-    directory_file.write_u32::<LittleEndian>(0)?;
-    lexicon_file.write_u32::<LittleEndian>(0)?;
+struct IndexFiles {
+    index_file: BufWriter<File>,
+    lexicon_file: BufWriter<File>,
+    directory_file: BufWriter<File>,
+    // Lexicon offset of every term, written as an offset table after the entries
+    lexicon_offsets: Vec<u64>,
+}
+
+pub fn build_bin_index(posting_path: &str, index_path: &str, lexicon_path: &str, directory_path: &str,
+                       doc_table: &DocTable, statistics_path: &str, config: &IndexConfig) -> std::io::Result<()> {
+    let file = File::open(posting_path)?;
+    let mut files = IndexFiles {
+        index_file: BufWriter::new(File::create(index_path)?),
+        lexicon_file: BufWriter::new(File::create(lexicon_path)?),
+        directory_file: BufWriter::new(File::create(directory_path)?),
+        lexicon_offsets: Vec::new(),
+    };
+
+    // Document and field lengths are needed to compute the maximum scores per term and per block, with the
+    // scorer the queries are ranked with by default when it is BM25F
+    let mut statistics = CollectionStatistics::new(doc_table, config);
+    let scorer = statistics.bounds.scorer.scorer(config);
+    let score = |positions: &[u32], field: Option<Field>, term: &TermStatistics, doc_id: u32| {
+        match doc_table.field_lengths(doc_id).filter(|_| scorer.weighs_fields()) {
+            Some(lengths) => {
+                let tf = field_frequencies(positions, field, &lengths);
+                scorer.field_score(&tf, &lengths, term, &statistics)
+            },
+            None => {
+                let doc_len = doc_table.length(doc_id).unwrap_or(0);
+                scorer.score(positions.len() as u32, doc_len, term, &statistics)
+            },
+        }
+    };
+
+    // The directory header is (total_directories, terms per directory entry)
+    files.directory_file.write_u32::<LittleEndian>(0)?;
+    files.directory_file.write_u32::<LittleEndian>(config.directory_nth_term)?;
+
+    // The lexicon header is (total_terms, has_positions, offset table position)
+    files.lexicon_file.write_u32::<LittleEndian>(0)?;
+    // Whether every block carries a position stream after its frequencies
+    files.lexicon_file.write_u32::<LittleEndian>(config.store_positions as u32)?;
+    files.lexicon_file.write_u64::<LittleEndian>(0)?;
 
     let mut total_terms = 0;
 
     #[cfg(feature = "debug_unicode")]
     {
-        let mut reader = BufReader::new(file);
+        let reader = BufReader::new(file);
         for line in reader.lines() {
             let line = line?;
             let data: Value = serde_json::from_str(&line)?;
             let term = data[0].as_str().unwrap();
-            let postings: Vec<(u32, u32)> = data[1].as_array().unwrap().iter().map(|x| {
+            let postings: Vec<(u32, Vec<u32>)> = data[1].as_array().unwrap().iter().map(|x| {
                 let docid = x[0].as_u64().unwrap() as u32;
-                let freq = x[1].as_u64().unwrap() as u32;
-                (docid, freq)
+                let positions = x[1].as_array().unwrap().iter()
+                    .map(|p| p.as_u64().unwrap() as u32)
+                    .collect();
+                (docid, positions)
             }).collect();
 
-            index_postings(&mut index_file, &mut lexicon_file, &mut directory_file, term, postings, &mut total_terms)?;
+            index_postings(&mut files, term, postings, &mut total_terms, config, &score)?;
         }
     }
 
     #[cfg(not(feature = "debug_unicode"))]
     {
         let mut reader = BufReader::new(file);
-
-        loop {
-            // Read the length of the serialized tuple
-            let mut length_buffer = [0u8; 8];
-            if reader.read_exact(&mut length_buffer).is_err() {
-                break; // Exit loop if we reach the end of the file or encounter any error
-            }
-
-            let length = u64::from_le_bytes(length_buffer);
-
-            let mut buffer = vec![0u8; length as usize];
-            if reader.read_exact(&mut buffer).is_err() {
-                break; // Exit loop if we can't read the full serialized tuple or encounter any error
-            }
-
-            match bincode::deserialize::<(String, Vec<(u32, u32)>)>(&buffer) {
-                Ok((term, postings)) => {
-                    index_postings(&mut index_file, &mut lexicon_file, &mut directory_file, &term, postings, &mut total_terms)?;
-                },
-                Err(e) => {
-                    eprintln!("(bin indexer) Failed to deserialize binary data: {}", e);
-                }
-            }
+        while let Some((term, postings)) = read_record::<(String, Vec<(u32, Vec<u32>)>)>(&mut reader)? {
+            index_postings(&mut files, &term, postings, &mut total_terms, config, &score)?;
         }
     }
 
-    lexicon_file.seek(SeekFrom::Start(0))?;
-    lexicon_file.write_u32::<LittleEndian>(total_terms)?;
+    // Offset table for binary searching the lexicon
+    let offset_table_position = files.lexicon_file.stream_position()?;
+    for &offset in &files.lexicon_offsets {
+        files.lexicon_file.write_u64::<LittleEndian>(offset)?;
+    }
 
-    let total_directories = (total_terms + DIRECTORY_NTH_TERM - 1) / DIRECTORY_NTH_TERM;
-    directory_file.seek(SeekFrom::Start(0))?;
-    directory_file.write_u32::<LittleEndian>(total_directories)?;
-    Ok(())
+    files.lexicon_file.seek(SeekFrom::Start(0))?;
+    files.lexicon_file.write_u32::<LittleEndian>(total_terms)?;
+    files.lexicon_file.seek(SeekFrom::Start(LEXICON_OFFSET_TABLE_POSITION))?;
+    files.lexicon_file.write_u64::<LittleEndian>(offset_table_position)?;
+
+    let total_directories = total_terms.div_ceil(config.directory_nth_term);
+    files.directory_file.seek(SeekFrom::Start(0))?;
+    files.directory_file.write_u32::<LittleEndian>(total_directories)?;
+
+    // The scores of the bounds above are only reproducible with these exact statistics
+    statistics.unique_terms = total_terms;
+    statistics.save(statistics_path)
 }
 
 // For each term
 fn index_postings(
-    index_file: &mut BufWriter<File>,
-    lexicon_file: &mut BufWriter<File>,
-    directory_file: &mut BufWriter<File>,
+    files: &mut IndexFiles,
     term: &str,
-    postings: Vec<(u32, u32)>,
+    postings: Vec<(u32, Vec<u32>)>,
     total_terms: &mut u32,
+    config: &IndexConfig,
+    score: &PostingScore,
 ) -> std::io::Result<()> {
+    let IndexFiles { index_file, lexicon_file, directory_file, lexicon_offsets } = files;
+    let block_size = config.block_size;
 
     // Add new directory entry when necessary
-    if *total_terms % DIRECTORY_NTH_TERM == 0 {
+    if total_terms.is_multiple_of(config.directory_nth_term) {
         directory_file.write_u32::<LittleEndian>(term.len() as u32)?;
         directory_file.write_all(term.as_bytes())?;
         directory_file.write_u64::<LittleEndian>(lexicon_file.stream_position()?)?;
@@ -121,34 +169,45 @@ fn index_postings(
     let mut metadata = TermMetadata {
         term_id: *total_terms,
         doc_freq: postings.len() as u32,
-        total_term_freq: postings.iter().map(|&(_, freq)| freq).sum(),
+        total_term_freq: postings.iter().map(|(_, positions)| positions.len() as u32).sum(),
         term_start_pointer: index_file.stream_position()?,
-        num_blocks: (postings.len() as f32 / BLOCK_SIZE as f32).ceil() as u32,
-        num_posting_in_last_block: match postings.len() % BLOCK_SIZE {
+        num_blocks: postings.len().div_ceil(block_size) as u32,
+        num_posting_in_last_block: match postings.len() % block_size {
             0 => {
-                if postings.len() / BLOCK_SIZE == 0 {
+                if postings.len() / block_size == 0 {
                     postings.len() as u32 // Only one block which is not full
                 } else {
-                    BLOCK_SIZE as u32 // The last block is exactly BLOCK_SIZE
+                    block_size as u32 // The last block is exactly block_size
                 }
             },
             remainder => remainder as u32, // Partial last block
         },
         last_doc_id: postings.last().unwrap().0,
+        max_score: 0.0,
         compressed_docids_per_block: Vec::new(),
         block_offsets: Vec::new(),
         block_maxima: Vec::new(),
+        block_max_scores: Vec::new(),
+        compressed_positions_per_block: Vec::new(),
     };
 
+    let field = Field::split_term(term).map(|(field, _)| field);
+    let term_statistics = TermStatistics {
+        doc_freq: metadata.doc_freq,
+        collection_freq: metadata.total_term_freq as u64,
+    };
     let mut last_doc_id = 0;
     let mut actual_max_doc_id = 0;
 
     // Processing each block
-    for block in postings.chunks(BLOCK_SIZE) {
+    for block in postings.chunks(block_size) {
         let mut block_docids: Vec<u32> = Vec::with_capacity(block.len());
         let mut block_freqs: Vec<u32> = Vec::with_capacity(block.len());
+        let mut block_positions: Vec<u32> = Vec::new();
+        let mut block_max_score: f32 = 0.0;
 
-        for &(docid, freq) in block {
+        for (docid, positions) in block {
+            let docid = *docid;
             // Update the actual max doc ID
             actual_max_doc_id = actual_max_doc_id.max(docid);
 
@@ -160,12 +219,22 @@ fn index_postings(
             block_docids.push(delta);
 
             // Store frequency
-            block_freqs.push(freq);
+            block_freqs.push(positions.len() as u32);
+            block_max_score = block_max_score.max(score(positions, field, &term_statistics, docid));
+
+            // Store positions as gaps within the document
+            let mut last_position = 0;
+            for &position in positions {
+                block_positions.push(position - last_position);
+                last_position = position;
+            }
         }
 
         metadata.block_offsets.push(index_file.stream_position()?);
         // Store the actual maximum docid of the block
         metadata.block_maxima.push(actual_max_doc_id);
+        metadata.block_max_scores.push(block_max_score);
+        metadata.max_score = metadata.max_score.max(block_max_score);
 
         // Compress and write docids for the block
         let mut compressed_docids = vec![0u8; block_docids.len() * 5];
@@ -180,9 +249,19 @@ fn index_postings(
             index_file.write_u32::<LittleEndian>(freq)?;
         }
 
+        // Compress and write positions for the block, right after the frequencies
+        if config.store_positions {
+            let mut compressed_positions = vec![0u8; block_positions.len() * 5];
+            let bytes_written = encode::<Scalar>(&block_positions, &mut compressed_positions);
+            compressed_positions.truncate(bytes_written);
+            index_file.write_all(&compressed_positions)?;
+
+            metadata.compressed_positions_per_block.push(bytes_written as u64);
+        }
     }
 
     // Write metadata to lexicon
+    lexicon_offsets.push(lexicon_file.stream_position()?);
     lexicon_file.write_u32::<LittleEndian>(term.len() as u32)?;
     lexicon_file.write_all(term.as_bytes())?;
     lexicon_file.write_u32::<LittleEndian>(metadata.term_id)?;
@@ -192,6 +271,7 @@ fn index_postings(
     lexicon_file.write_u32::<LittleEndian>(metadata.num_blocks)?;
     lexicon_file.write_u32::<LittleEndian>(metadata.num_posting_in_last_block)?;
     lexicon_file.write_u32::<LittleEndian>(metadata.last_doc_id)?;
+    lexicon_file.write_f32::<LittleEndian>(metadata.max_score)?;
     for &size in &metadata.compressed_docids_per_block {
         lexicon_file.write_u64::<LittleEndian>(size)?;
     }
@@ -201,9 +281,215 @@ fn index_postings(
     for &max in &metadata.block_maxima {
         lexicon_file.write_u32::<LittleEndian>(max)?;
     }
+    for &max_score in &metadata.block_max_scores {
+        lexicon_file.write_f32::<LittleEndian>(max_score)?;
+    }
+    for &size in &metadata.compressed_positions_per_block {
+        lexicon_file.write_u64::<LittleEndian>(size)?;
+    }
 
 
     *total_terms += 1;
 
     Ok(())
 }
+
+// Frequencies of the term in every field of a document, from its positions for a plain term. The query processor
+// reads the same ones from the postings of the field terms.
+fn field_frequencies(positions: &[u32], field: Option<Field>, lengths: &[u32; FIELDS]) -> [u32; FIELDS] {
+    let mut tf = [0; FIELDS];
+    match field {
+        Some(field) => tf[field as usize] = positions.len() as u32,
+        None => for &position in positions {
+            tf[Field::of_position(position, lengths) as usize] += 1;
+        },
+    }
+    tf
+}
+
+// Read one lexicon entry, the inverse of the writing at the end of index_postings
+pub fn read_term_metadata<R: Read>(reader: &mut R, has_positions: bool) -> std::io::Result<(String, TermMetadata)> {
+    let term_length = reader.read_u32::<LittleEndian>()? as usize;
+    let mut term_buffer = vec![0u8; term_length];
+    reader.read_exact(&mut term_buffer)?;
+    let term = String::from_utf8(term_buffer)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let term_id = reader.read_u32::<LittleEndian>()?;
+    let doc_freq = reader.read_u32::<LittleEndian>()?;
+    let total_term_freq = reader.read_u32::<LittleEndian>()?;
+    let term_start_pointer = reader.read_u64::<LittleEndian>()?;
+    let num_blocks = reader.read_u32::<LittleEndian>()?;
+    let num_posting_in_last_block = reader.read_u32::<LittleEndian>()?;
+    let last_doc_id = reader.read_u32::<LittleEndian>()?;
+    let max_score = reader.read_f32::<LittleEndian>()?;
+
+    let mut compressed_docids_per_block = vec![0u64; num_blocks as usize];
+    for size in &mut compressed_docids_per_block {
+        *size = reader.read_u64::<LittleEndian>()?;
+    }
+
+    let mut block_offsets = vec![0u64; num_blocks as usize];
+    for offset in &mut block_offsets {
+        *offset = reader.read_u64::<LittleEndian>()?;
+    }
+
+    let mut block_maxima = vec![0u32; num_blocks as usize];
+    for max in &mut block_maxima {
+        *max = reader.read_u32::<LittleEndian>()?;
+    }
+
+    let mut block_max_scores = vec![0f32; num_blocks as usize];
+    for max_score in &mut block_max_scores {
+        *max_score = reader.read_f32::<LittleEndian>()?;
+    }
+
+    let mut compressed_positions_per_block = Vec::new();
+    if has_positions {
+        compressed_positions_per_block = vec![0u64; num_blocks as usize];
+        for size in &mut compressed_positions_per_block {
+            *size = reader.read_u64::<LittleEndian>()?;
+        }
+    }
+
+    Ok((term, TermMetadata {
+        term_id,
+        doc_freq,
+        total_term_freq,
+        term_start_pointer,
+        num_blocks,
+        num_posting_in_last_block,
+        last_doc_id,
+        max_score,
+        compressed_docids_per_block,
+        block_offsets,
+        block_maxima,
+        block_max_scores,
+        compressed_positions_per_block,
+    }))
+}
+
+// Load the sparse directory: the terms per directory entry and the (term, lexicon offset) entries
+pub fn load_directory(directory_path: &str) -> std::io::Result<(u32, Vec<(String, u64)>)> {
+    let mut reader = BufReader::new(File::open(directory_path)?);
+    let total_directories = reader.read_u32::<LittleEndian>()?;
+    let stride = reader.read_u32::<LittleEndian>()?;
+
+    let mut directory = Vec::with_capacity(total_directories as usize);
+    for _ in 0..total_directories {
+        let term_length = reader.read_u32::<LittleEndian>()? as usize;
+        let mut term_buffer = vec![0u8; term_length];
+        reader.read_exact(&mut term_buffer)?;
+        let term = String::from_utf8(term_buffer)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let lexicon_position = reader.read_u64::<LittleEndian>()?;
+        directory.push((term, lexicon_position));
+    }
+
+    Ok((stride, directory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::posting_cursor::{PostingCursor, END_OF_POSTINGS};
+
+    // Postings of a term in n documents, with docids spread over gaps of growing size and 1 to 3 positions each
+    fn postings(n: u32) -> Vec<(u32, Vec<u32>)> {
+        (0..n).map(|i| (3 + i * i, (0..=i % 3).map(|p| i + 5 * p).collect())).collect()
+    }
+
+    // Write the postings of one term as the indexer does and read its lexicon entry back
+    fn write_term(postings: &[(u32, Vec<u32>)], block_size: usize) -> (Vec<u8>, TermMetadata) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = IndexConfig { block_size, store_positions: true, ..IndexConfig::default() };
+        let mut files = IndexFiles {
+            index_file: BufWriter::new(File::create(dir.path().join("index")).unwrap()),
+            lexicon_file: BufWriter::new(File::create(dir.path().join("lexicon")).unwrap()),
+            directory_file: BufWriter::new(File::create(dir.path().join("directory")).unwrap()),
+            lexicon_offsets: Vec::new(),
+        };
+        // The frequency as score, to check the block bounds
+        let score = |positions: &[u32], _: Option<Field>, _: &TermStatistics, _: u32| positions.len() as f32;
+        let mut total_terms = 0;
+        index_postings(&mut files, "term", postings.to_vec(), &mut total_terms, &config, &score).unwrap();
+        files.index_file.flush().unwrap();
+        files.lexicon_file.flush().unwrap();
+
+        let mut lexicon = BufReader::new(File::open(dir.path().join("lexicon")).unwrap());
+        let (term, metadata) = read_term_metadata(&mut lexicon, true).unwrap();
+        assert_eq!(term, "term");
+        (std::fs::read(dir.path().join("index")).unwrap(), metadata)
+    }
+
+    #[test]
+    fn cursor_decodes_the_written_postings() {
+        // A single partial block, exactly full blocks and a partial last block
+        for n in [1, 3, 8, 23] {
+            let postings = postings(n);
+            let (index, metadata) = write_term(&postings, 4);
+            assert_eq!(metadata.doc_freq, n);
+            assert_eq!(metadata.num_blocks, n.div_ceil(4));
+            assert_eq!(metadata.last_doc_id, postings.last().unwrap().0);
+
+            let mut cursor = PostingCursor::new(&index, metadata, 4).unwrap();
+            let mut decoded = Vec::new();
+            while cursor.doc() != END_OF_POSTINGS {
+                assert_eq!(cursor.freq() as usize, cursor.positions().unwrap().len());
+                decoded.push((cursor.doc(), cursor.positions().unwrap().to_vec()));
+                cursor.next().unwrap();
+            }
+            assert_eq!(decoded, postings);
+        }
+    }
+
+    #[test]
+    fn next_geq_moves_to_the_first_docid_not_smaller() {
+        let postings = postings(23);
+        let (index, metadata) = write_term(&postings, 4);
+        let last_doc_id = postings.last().unwrap().0;
+
+        for target in 0..=last_doc_id + 2 {
+            let expected = postings.iter().find(|(doc_id, _)| *doc_id >= target);
+            let mut cursor = PostingCursor::new(&index, metadata.clone(), 4).unwrap();
+            cursor.next_geq(target).unwrap();
+            match expected {
+                Some((doc_id, positions)) => {
+                    assert_eq!(cursor.doc(), *doc_id);
+                    assert_eq!(cursor.positions().unwrap(), positions.as_slice());
+                },
+                None => assert_eq!(cursor.doc(), END_OF_POSTINGS),
+            }
+        }
+
+        // From block to block on the same cursor, never moving back
+        let mut cursor = PostingCursor::new(&index, metadata, 4).unwrap();
+        cursor.next_geq(100).unwrap();
+        assert_eq!(cursor.doc(), 103);
+        cursor.next_geq(50).unwrap();
+        assert_eq!(cursor.doc(), 103);
+        cursor.next_geq(104).unwrap();
+        assert_eq!(cursor.doc(), 124);
+        cursor.next().unwrap();
+        assert_eq!(cursor.doc(), 147);
+        cursor.next_geq(last_doc_id).unwrap();
+        assert_eq!(cursor.doc(), last_doc_id);
+        cursor.next().unwrap();
+        assert_eq!(cursor.doc(), END_OF_POSTINGS);
+    }
+
+    #[test]
+    fn block_bounds_cover_the_postings_of_their_block() {
+        let postings = postings(23);
+        let (index, metadata) = write_term(&postings, 4);
+        assert_eq!(metadata.max_score, 3.0);
+
+        let cursor = PostingCursor::new(&index, metadata, 4).unwrap();
+        for block in postings.chunks(4) {
+            let max_freq = block.iter().map(|(_, positions)| positions.len()).max().unwrap();
+            let last_doc_id = block.last().unwrap().0;
+            assert_eq!(cursor.block_bound(block[0].0), (max_freq as f32, last_doc_id));
+        }
+        assert_eq!(cursor.block_bound(postings.last().unwrap().0 + 1), (0.0, END_OF_POSTINGS));
+    }
+}
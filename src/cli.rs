@@ -0,0 +1,390 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use clap::{Args, Parser, Subcommand};
+use crate::analyzer::TokenFilter;
+use crate::config::IndexConfig;
+use crate::scoring::ScoringFunction;
+use crate::sources::{open_source, DocumentSource, InputFormat, JsonlFields};
+use crate::term_query_processor::{QueryMode, DEFAULT_PAGE_SIZE};
+
+#[derive(Parser)]
+#[command(about = "Build, serve and query a compressed inverted index")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Index a collection, TREC documents by default
+    Build {
+        #[arg(long)]
+        input: PathBuf,
+        #[command(flatten)]
+        source: SourceArgs,
+        #[arg(long, default_value = "data")]
+        out_dir: PathBuf,
+        /// TOML file with the index parameters, overridden by the flags below
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[command(flatten)]
+        overrides: BuildOverrides,
+    },
+    /// Index more documents into a new segment of an existing index
+    Add {
+        #[arg(long)]
+        input: PathBuf,
+        #[command(flatten)]
+        source: SourceArgs,
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        #[command(flatten)]
+        overrides: AddOverrides,
+    },
+    /// Mark documents as deleted, they are skipped by queries until the next compaction
+    Delete {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        #[arg(required_unless_present = "docnos")]
+        doc_ids: Vec<u32>,
+        /// Delete the documents with this DOCNO
+        #[arg(long = "docno")]
+        docnos: Vec<String>,
+    },
+    /// Merge all segments into one, dropping the postings of deleted documents
+    Compact {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+    },
+    /// Check that the lexicon, directory and postings of every segment are consistent, printing every problem
+    Verify {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+    },
+    /// Serve the search page and the query endpoints over HTTP
+    Serve {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        bind: String,
+        #[arg(long, default_value = "static")]
+        static_dir: PathBuf,
+        /// Number of server worker threads, one per CPU core by default
+        #[arg(long)]
+        workers: Option<usize>,
+        #[command(flatten)]
+        overrides: QueryOverrides,
+    },
+    /// Print the docid of the document with the DOCNO as JSON
+    Lookup {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        docno: String,
+    },
+    /// Print the stored URL, title and body of the document as JSON
+    Document {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        doc_id: u32,
+    },
+    /// Run a single query and print the results as JSON
+    Query {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = QueryMode::Search)]
+        mode: QueryMode,
+        /// Number of results to skip
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Number of results to print
+        #[arg(short, long, default_value_t = DEFAULT_PAGE_SIZE)]
+        k: usize,
+        /// ISO 639-1 code of the language of the query, such as "es", detected by default
+        #[arg(long)]
+        lang: Option<String>,
+        #[command(flatten)]
+        overrides: QueryOverrides,
+        query: String,
+    },
+    /// Run the queries of a TSV file (qid, text) and write the results as a TREC run file
+    Run {
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        #[arg(long)]
+        queries: PathBuf,
+        #[arg(long)]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = QueryMode::Or)]
+        mode: QueryMode,
+        /// Number of documents retrieved per query
+        #[arg(long, default_value_t = 1000)]
+        depth: usize,
+        /// Run tag written in the last column
+        #[arg(long, default_value = "inverted-index")]
+        tag: String,
+        /// Number of query threads, all available cores by default
+        #[arg(long)]
+        threads: Option<usize>,
+        #[command(flatten)]
+        overrides: QueryOverrides,
+    },
+    /// Score a run against TREC relevance judgments, optionally compared with a baseline run
+    Evaluate {
+        /// TREC qrels file
+        #[arg(long)]
+        qrels: PathBuf,
+        /// TREC run file to evaluate
+        #[arg(long, required_unless_present = "queries", conflicts_with = "queries")]
+        run: Option<PathBuf>,
+        /// Evaluate the queries of this TSV file (qid, text) on the index instead of a run file
+        #[arg(long)]
+        queries: Option<PathBuf>,
+        #[arg(long, default_value = "data")]
+        index_dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = QueryMode::Or)]
+        mode: QueryMode,
+        /// Number of documents retrieved per query
+        #[arg(long, default_value_t = 1000)]
+        depth: usize,
+        /// Number of query threads, all available cores by default
+        #[arg(long)]
+        threads: Option<usize>,
+        #[command(flatten)]
+        overrides: QueryOverrides,
+        /// TREC run file to compare with, with a paired randomization test per metric
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+        /// Cutoffs k of P@k and Recall@k
+        #[arg(long, value_delimiter = ',', default_values_t = [10, 100, 1000])]
+        cutoffs: Vec<usize>,
+        /// Print the metrics of every query too
+        #[arg(long, conflicts_with = "baseline")]
+        per_query: bool,
+    },
+}
+
+// Format of the input of a build or an add, segments of an index may come from different formats
+#[derive(Args)]
+pub struct SourceArgs {
+    /// Format of the input, gzipped or not for the single file ones
+    #[arg(long, value_enum, default_value_t = InputFormat::Trec)]
+    format: InputFormat,
+    /// JSONL field of the document identifier
+    #[arg(long, default_value = "id")]
+    id_field: String,
+    /// JSONL field of the document text
+    #[arg(long, default_value = "text")]
+    text_field: String,
+    /// JSONL field of the document title, if any
+    #[arg(long)]
+    title_field: Option<String>,
+    /// JSONL field of the document URL, if any
+    #[arg(long)]
+    url_field: Option<String>,
+}
+
+impl SourceArgs {
+    pub fn open(&self, input: &Path) -> io::Result<Box<dyn DocumentSource>> {
+        let fields = JsonlFields {
+            id: self.id_field.clone(),
+            text: self.text_field.clone(),
+            title: self.title_field.clone(),
+            url: self.url_field.clone(),
+        };
+        open_source(input, self.format, &fields)
+    }
+}
+
+#[derive(Args)]
+pub struct BuildOverrides {
+    #[arg(long)]
+    batch_size: Option<usize>,
+    #[arg(long)]
+    block_size: Option<usize>,
+    /// One directory entry every N lexicon terms
+    #[arg(long)]
+    directory_stride: Option<u32>,
+    #[arg(long)]
+    bm25_k1: Option<f32>,
+    #[arg(long)]
+    bm25_b: Option<f32>,
+    /// Do not store term positions, which disables phrase queries
+    #[arg(long)]
+    no_positions: bool,
+    /// Do not store the document text, which disables result snippets
+    #[arg(long)]
+    no_doc_store: bool,
+    /// Do not stem the terms, the analyzer of a config file can be edited for finer changes
+    #[arg(long)]
+    no_stemming: bool,
+    /// Keep the stop words
+    #[arg(long)]
+    no_stop_words: bool,
+    /// Stop after this many documents
+    #[arg(long)]
+    doc_limit: Option<usize>,
+    /// Number of indexing threads, all available cores by default
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+impl BuildOverrides {
+    pub fn apply(&self, config: &mut IndexConfig) {
+        if let Some(batch_size) = self.batch_size {
+            config.batch_size = batch_size;
+        }
+        if let Some(block_size) = self.block_size {
+            config.block_size = block_size;
+        }
+        if let Some(directory_stride) = self.directory_stride {
+            config.directory_nth_term = directory_stride;
+        }
+        if let Some(k1) = self.bm25_k1 {
+            config.bm25_k1 = k1;
+        }
+        if let Some(b) = self.bm25_b {
+            config.bm25_b = b;
+        }
+        if self.no_positions {
+            config.store_positions = false;
+        }
+        if self.no_doc_store {
+            config.store_documents = false;
+        }
+        if self.no_stemming {
+            config.analyzer.filters.retain(|filter| !matches!(filter, TokenFilter::Stemmer(_) | TokenFilter::LanguageStemmer));
+        }
+        if self.no_stop_words {
+            config.analyzer.filters.retain(|filter| !matches!(filter, TokenFilter::StopWords(_) | TokenFilter::LanguageStopWords));
+        }
+        if self.doc_limit.is_some() {
+            config.doc_limit = self.doc_limit;
+        }
+        if self.threads.is_some() {
+            config.threads = self.threads;
+        }
+    }
+}
+
+// Parameters of an add that may differ from the initial build. The others are taken from the index,
+// all segments have to be decoded and scored the same way.
+#[derive(Args)]
+pub struct AddOverrides {
+    #[arg(long)]
+    batch_size: Option<usize>,
+    /// Stop after this many documents
+    #[arg(long)]
+    doc_limit: Option<usize>,
+    /// Number of indexing threads, all available cores by default
+    #[arg(long)]
+    threads: Option<usize>,
+}
+
+impl AddOverrides {
+    pub fn apply(&self, config: &mut IndexConfig) {
+        if let Some(batch_size) = self.batch_size {
+            config.batch_size = batch_size;
+        }
+        config.doc_limit = self.doc_limit;
+        if self.threads.is_some() {
+            config.threads = self.threads;
+        }
+    }
+}
+
+// Parameters that only affect query processing. The score upper bounds stored in the lexicon were computed with
+// the parameters of the build, queries scored with other ones prune with the looser bound of their scorer.
+#[derive(Args)]
+pub struct QueryOverrides {
+    /// Use plain WAND instead of Block-Max WAND for disjunctive queries
+    #[arg(long)]
+    no_block_max: bool,
+    /// Scoring function of the queries, the one saved with the index by default
+    #[arg(long, value_enum)]
+    scorer: Option<ScoringFunction>,
+    #[arg(long)]
+    bm25_k1: Option<f32>,
+    #[arg(long)]
+    bm25_b: Option<f32>,
+    #[arg(long)]
+    bm25_plus_delta: Option<f32>,
+    #[arg(long)]
+    bm25l_delta: Option<f32>,
+    #[arg(long)]
+    dirichlet_mu: Option<f32>,
+    #[arg(long)]
+    jelinek_mercer_lambda: Option<f32>,
+    #[arg(long)]
+    pl2_c: Option<f32>,
+    /// BM25F weight of the matches in the URL
+    #[arg(long)]
+    url_weight: Option<f32>,
+    /// BM25F weight of the matches in the title
+    #[arg(long)]
+    title_weight: Option<f32>,
+    /// BM25F weight of the matches in the headings of HTML pages
+    #[arg(long)]
+    heading_weight: Option<f32>,
+    /// BM25F weight of the matches in the body
+    #[arg(long)]
+    body_weight: Option<f32>,
+    #[arg(long)]
+    bm25f_url_b: Option<f32>,
+    #[arg(long)]
+    bm25f_title_b: Option<f32>,
+    #[arg(long)]
+    bm25f_heading_b: Option<f32>,
+}
+
+impl QueryOverrides {
+    pub fn apply(&self, config: &mut IndexConfig) {
+        if self.no_block_max {
+            config.block_max_wand = false;
+        }
+        if let Some(scorer) = self.scorer {
+            config.scorer = scorer;
+        }
+        if let Some(k1) = self.bm25_k1 {
+            config.bm25_k1 = k1;
+        }
+        if let Some(b) = self.bm25_b {
+            config.bm25_b = b;
+        }
+        if let Some(delta) = self.bm25_plus_delta {
+            config.bm25_plus_delta = delta;
+        }
+        if let Some(delta) = self.bm25l_delta {
+            config.bm25l_delta = delta;
+        }
+        if let Some(mu) = self.dirichlet_mu {
+            config.dirichlet_mu = mu;
+        }
+        if let Some(lambda) = self.jelinek_mercer_lambda {
+            config.jelinek_mercer_lambda = lambda;
+        }
+        if let Some(c) = self.pl2_c {
+            config.pl2_c = c;
+        }
+        if let Some(weight) = self.url_weight {
+            config.bm25f_url_weight = weight;
+        }
+        if let Some(weight) = self.title_weight {
+            config.bm25f_title_weight = weight;
+        }
+        if let Some(weight) = self.heading_weight {
+            config.bm25f_heading_weight = weight;
+        }
+        if let Some(weight) = self.body_weight {
+            config.bm25f_body_weight = weight;
+        }
+        if let Some(b) = self.bm25f_url_b {
+            config.bm25f_url_b = b;
+        }
+        if let Some(b) = self.bm25f_title_b {
+            config.bm25f_title_b = b;
+        }
+        if let Some(b) = self.bm25f_heading_b {
+            config.bm25f_heading_b = b;
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use bimap::BiMap;
+use crate::bin_indexer::build_bin_index;
+use crate::config::{IndexConfig, IndexPaths};
+use crate::doc_store::DocStoreWriter;
+use crate::doc_table::{DocTable, DocTableWriter};
+use crate::disk_io::{merge_sorted_postings, write_lexicon_to_disk, write_posting_to_disk};
+use crate::posting_cursor::{PostingCursor, END_OF_POSTINGS};
+use crate::segment::Segment;
+use crate::tombstones::Tombstones;
+
+// Number of postings to decode before dumping them as a sorted batch
+const COMPACTION_BATCH_POSTINGS: usize = 1_000_000;
+
+// Rewrite all the segments of the index into a single one without the deleted documents.
+// The postings are decoded from the binary index, dumped in sorted batches without the tombstoned docids
+// and go through the same external merge and binary index build as a fresh index. Docids are kept.
+pub fn compact_index(paths: &IndexPaths, config: &IndexConfig) -> std::io::Result<()> {
+    let tombstones = Tombstones::load(&paths.deleted_docs())?;
+    let compaction = paths.compaction();
+    if compaction.dir().exists() {
+        fs::remove_dir_all(compaction.dir())?;
+    }
+    fs::create_dir_all(compaction.dir())?;
+
+    let mut doc_table = DocTableWriter::create(&compaction)?;
+    let mut term_ids: BiMap<String, u32> = BiMap::new();
+    let mut postings: HashMap<u32, HashMap<u32, Vec<u32>>> = HashMap::new();
+    let mut buffered_postings = 0;
+    let mut batch_number = 0;
+    let mut doc_store = if config.store_documents {
+        Some(DocStoreWriter::create(&compaction.doc_store(), &compaction.doc_store_index())?)
+    } else {
+        None
+    };
+
+    for segment_paths in paths.segments()? {
+        let segment = Segment::open(&segment_paths)?;
+        for (doc_id, doc) in segment.doc_table().documents() {
+            if !tombstones.contains(doc_id) {
+                doc_table.add(doc_id, &doc)?;
+            }
+        }
+
+        // The stores of the segments are in docid order too, a segment built without one adds nothing
+        if let (Some(doc_store), Some(segment_store)) = (&mut doc_store, segment.doc_store()) {
+            for block in segment_store.documents() {
+                for (doc_id, text) in block? {
+                    if !tombstones.contains(doc_id) {
+                        doc_store.add(doc_id, &text)?;
+                    }
+                }
+            }
+        }
+
+        for term_index in 0..segment.total_terms() as usize {
+            let (term, metadata) = segment.term_at(term_index)?;
+            let mut cursor = PostingCursor::new(segment.index(), metadata, config.block_size)?;
+
+            let mut term_postings = HashMap::new();
+            while cursor.doc() != END_OF_POSTINGS {
+                if !tombstones.contains(cursor.doc()) {
+                    // Without stored positions, placeholders carry the frequency through the rebuild
+                    let positions = if segment.has_positions() {
+                        cursor.positions()?.to_vec()
+                    } else {
+                        (0..cursor.freq()).collect()
+                    };
+                    term_postings.insert(cursor.doc(), positions);
+                }
+                cursor.next()?;
+            }
+            if term_postings.is_empty() {
+                // Only deleted documents contained the term
+                continue;
+            }
+
+            let next_term_id = term_ids.len() as u32;
+            let term_id = *term_ids.get_by_left(&term).unwrap_or(&next_term_id);
+            if term_id == next_term_id {
+                term_ids.insert(term, term_id);
+            }
+            buffered_postings += term_postings.len();
+            postings.entry(term_id).or_default().extend(term_postings);
+
+            if buffered_postings >= COMPACTION_BATCH_POSTINGS {
+                write_posting_to_disk(&postings, &term_ids, &compaction.postings_dir(), batch_number);
+                postings.clear();
+                buffered_postings = 0;
+                batch_number += 1;
+            }
+        }
+    }
+    write_posting_to_disk(&postings, &term_ids, &compaction.postings_dir(), batch_number);
+    if let Some(doc_store) = doc_store {
+        doc_store.finish()?;
+    }
+
+    write_lexicon_to_disk(&term_ids, &compaction.term_ids());
+    let doc_count = doc_table.doc_count();
+    doc_table.finish()?;
+
+    // An index without any posting left still needs an (empty) batch directory to merge
+    fs::create_dir_all(compaction.postings_dir())?;
+    merge_sorted_postings(&compaction.postings_dir(), &compaction.merged_postings())?;
+    build_bin_index(&compaction.merged_postings(), &compaction.index(), &compaction.lexicon(),
+                    &compaction.directory(), &DocTable::open(&compaction)?, &compaction.statistics(), config)?;
+    config.save(Path::new(&compaction.config()))?;
+
+    println!("Compacted {} documents, {} deleted ones dropped", doc_count, tombstones.len());
+    replace_index(paths, &compaction)
+}
+
+// Publish the compacted index as a segment replacing the ones it was built from, see IndexPaths::segments.
+// Renaming its complete directory into a segment is the single step switching the index over: before it the
+// index is the one that was compacted, after it the files of the replaced segments are ignored until removed.
+fn replace_index(paths: &IndexPaths, compaction: &IndexPaths) -> std::io::Result<()> {
+    let replaced = paths.segments()?;
+    // Past the interrupted adds too, whose directories may still be there
+    let number = paths.added_segments()?.last().map_or(1, |&(number, _)| number + 1);
+    fs::write(compaction.compacted(), "")?;
+    fs::rename(compaction.dir(), paths.segment(number).dir())?;
+
+    for segment in replaced {
+        if segment.dir() == paths.dir() {
+            remove_segment_files(paths)?;
+        } else {
+            fs::remove_dir_all(segment.dir())?;
+        }
+    }
+
+    // The deleted documents are gone from the postings
+    remove_file(&paths.deleted_docs())
+}
+
+// Remove the segment held by the index directory itself, the index config stays with the directory
+fn remove_segment_files(paths: &IndexPaths) -> std::io::Result<()> {
+    let files = [
+        paths.index(), paths.lexicon(), paths.directory(), paths.merged_postings(), paths.term_ids(),
+        paths.doc_lengths(), paths.doc_string_offsets(), paths.doc_strings(), paths.docno_index(),
+        paths.doc_field_lengths(), paths.doc_store(), paths.doc_store_index(), paths.statistics(),
+    ];
+    for file in &files {
+        remove_file(file)?;
+    }
+
+    let postings = paths.postings_dir();
+    if Path::new(&postings).exists() {
+        fs::remove_dir_all(postings)?;
+    }
+    Ok(())
+}
+
+fn remove_file(path: &str) -> std::io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use crate::analyzer::AnalyzerConfig;
+use crate::scoring::ScoringFunction;
+use crate::utils;
+
+// Parameters of an index build. They are saved next to the index, so that the query processor
+// decodes blocks and computes scores with exactly the values the index was built with.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct IndexConfig {
+    // Number of documents to process before dumping postings to disk
+    pub batch_size: usize,
+    // Number of postings per compressed block
+    pub block_size: usize,
+    // One directory entry every directory_nth_term lexicon terms
+    pub directory_nth_term: u32,
+    pub store_positions: bool,
+    // Keep the compressed text of the documents, for result snippets
+    pub store_documents: bool,
+    pub bm25_k1: f32,
+    pub bm25_b: f32,
+    // Stop indexing after this many documents, useful for quick experiments
+    pub doc_limit: Option<usize>,
+    // Number of indexing threads, all available cores if unset
+    pub threads: Option<usize>,
+    // Use Block-Max WAND rather than plain WAND for disjunctive queries
+    pub block_max_wand: bool,
+    // Scoring function of queries that do not ask for one. The score bounds stored in the index are
+    // BM25F ones when it is BM25F and BM25 ones otherwise, see BoundParameters; the other functions prune
+    // disjunctive queries with their own looser bounds.
+    pub scorer: ScoringFunction,
+    pub bm25_plus_delta: f32,
+    pub bm25l_delta: f32,
+    pub dirichlet_mu: f32,
+    pub jelinek_mercer_lambda: f32,
+    pub pl2_c: f32,
+    pub bm25f_url_weight: f32,
+    pub bm25f_title_weight: f32,
+    pub bm25f_body_weight: f32,
+    pub bm25f_url_b: f32,
+    pub bm25f_title_b: f32,
+    pub bm25f_heading_weight: f32,
+    pub bm25f_heading_b: f32,
+    // Analysis of the document and query text into terms
+    pub analyzer: AnalyzerConfig,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: utils::BATCH_SIZE,
+            block_size: utils::BLOCK_SIZE,
+            directory_nth_term: utils::DIRECTORY_NTH_TERM,
+            store_positions: utils::STORE_POSITIONS,
+            store_documents: utils::STORE_DOCUMENTS,
+            bm25_k1: utils::BM25_K1,
+            bm25_b: utils::BM25_B,
+            doc_limit: None,
+            threads: None,
+            block_max_wand: utils::BLOCK_MAX_WAND,
+            scorer: ScoringFunction::Bm25F,
+            bm25_plus_delta: utils::BM25_PLUS_DELTA,
+            bm25l_delta: utils::BM25L_DELTA,
+            dirichlet_mu: utils::DIRICHLET_MU,
+            jelinek_mercer_lambda: utils::JELINEK_MERCER_LAMBDA,
+            pl2_c: utils::PL2_C,
+            bm25f_url_weight: utils::BM25F_URL_WEIGHT,
+            bm25f_title_weight: utils::BM25F_TITLE_WEIGHT,
+            bm25f_body_weight: utils::BM25F_BODY_WEIGHT,
+            bm25f_url_b: utils::BM25F_URL_B,
+            bm25f_title_b: utils::BM25F_TITLE_B,
+            bm25f_heading_weight: utils::BM25F_HEADING_WEIGHT,
+            bm25f_heading_b: utils::BM25F_HEADING_B,
+            analyzer: AnalyzerConfig::default(),
+        }
+    }
+}
+
+impl IndexConfig {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+}
+
+// Locations of the files making up an index directory
+#[derive(Clone, Debug)]
+pub struct IndexPaths {
+    dir: PathBuf,
+}
+
+impl IndexPaths {
+    pub fn new(dir: &Path) -> Self {
+        Self { dir: dir.to_path_buf() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // A segment added to the index, stored in a subdirectory of the index directory
+    pub fn segment(&self, number: usize) -> IndexPaths {
+        IndexPaths::new(&self.dir.join(format!("segment_{:04}", number)))
+    }
+
+    // The segments to search: the index directory itself, which holds the documents of the initial build,
+    // followed by the added segments in the order they were added. A segment built by a compaction replaces
+    // all the ones before it, the index directory included.
+    pub fn segments(&self) -> io::Result<Vec<IndexPaths>> {
+        let mut segments = vec![self.clone()];
+        for (_, segment) in self.added_segments()? {
+            if !segment.is_complete() {
+                continue;
+            }
+            if segment.is_compacted() {
+                segments.clear();
+            }
+            segments.push(segment);
+        }
+        Ok(segments)
+    }
+
+    // Numbers and paths of the segment subdirectories, including the ones whose build did not finish
+    pub fn added_segments(&self) -> io::Result<Vec<(usize, IndexPaths)>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut numbers = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            if let Some(number) = name.to_str()
+                .and_then(|name| name.strip_prefix("segment_"))
+                .and_then(|number| number.parse::<usize>().ok()) {
+                numbers.push(number);
+            }
+        }
+        numbers.sort_unstable();
+
+        Ok(numbers.into_iter().map(|number| (number, self.segment(number))).collect())
+    }
+
+    // The index config is saved last, once all the other files are written
+    pub fn is_complete(&self) -> bool {
+        Path::new(&self.config()).exists()
+    }
+
+    // Whether the segment was built by a compaction, see segments
+    pub fn is_compacted(&self) -> bool {
+        Path::new(&self.compacted()).exists()
+    }
+
+    fn file(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    // Sorted batches of postings, merged into merged_postings
+    pub fn postings_dir(&self) -> String {
+        self.file("postings_data")
+    }
+
+    pub fn merged_postings(&self) -> String {
+        self.file("merged_postings.data")
+    }
+
+    pub fn index(&self) -> String {
+        self.file("bin_index.data")
+    }
+
+    pub fn lexicon(&self) -> String {
+        self.file("bin_lexicon.data")
+    }
+
+    pub fn directory(&self) -> String {
+        self.file("bin_directory.data")
+    }
+
+    // Metadata of the batches, written into the doc table
+    pub fn doc_metadata_batches_dir(&self) -> String {
+        self.file("doc_metadata_batches")
+    }
+
+    // Columns of the doc table, see doc_table.rs
+    pub fn doc_lengths(&self) -> String {
+        self.file("doc_lengths.data")
+    }
+
+    pub fn doc_string_offsets(&self) -> String {
+        self.file("doc_string_offsets.data")
+    }
+
+    pub fn doc_strings(&self) -> String {
+        self.file("doc_strings.data")
+    }
+
+    pub fn docno_index(&self) -> String {
+        self.file("docno_index.data")
+    }
+
+    pub fn doc_field_lengths(&self) -> String {
+        self.file("doc_field_lengths.data")
+    }
+
+    // Compressed document text, see doc_store.rs
+    pub fn doc_store(&self) -> String {
+        self.file("doc_store.data")
+    }
+
+    pub fn doc_store_index(&self) -> String {
+        self.file("doc_store_index.data")
+    }
+
+    // Document stores of the batches, concatenated into doc_store
+    pub fn doc_store_batches_dir(&self) -> String {
+        self.file("doc_store_batches")
+    }
+
+    // Collection statistics record of the segment, see scoring.rs
+    pub fn statistics(&self) -> String {
+        self.file("collection_stats.toml")
+    }
+
+    // Mapping of terms to the ids used while indexing
+    pub fn term_ids(&self) -> String {
+        self.file("lexicon.data")
+    }
+
+    // Bitset of the deleted docids of all segments, see tombstones.rs
+    pub fn deleted_docs(&self) -> String {
+        self.file("deleted_docs.data")
+    }
+
+    // Where a compaction builds the new index before it replaces the current one
+    pub fn compaction(&self) -> IndexPaths {
+        IndexPaths::new(&self.dir.join("compaction"))
+    }
+
+    // Empty file marking a segment built by a compaction
+    pub fn compacted(&self) -> String {
+        self.file("compacted")
+    }
+
+    pub fn config(&self) -> String {
+        self.file("index_config.toml")
+    }
+
+    pub fn log(&self) -> String {
+        self.file("indexer.log")
+    }
+}
@@ -1,81 +1,181 @@
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Result, BufRead, Read};
-use flate2::read::GzDecoder;
+use std::io::{BufReader, Result};
 use bimap::BiMap;
 use std::io::Write;
-use std::path::Path;
-use chrono::Utc;
+use std::path::{Path, PathBuf};
 use simplelog::*;
 use log::{info, LevelFilter};
 use crate::external_sorter::merge_sorted_files;
 use std::fs::read_dir;
 use std::io;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::mpsc::SyncSender;
+use std::thread;
 
 #[cfg(feature = "debug_unicode")]
 
 #[cfg(not(feature = "debug_unicode"))]
 use bincode;
-use crate::{indexer, utils};
-use crate::utils::BATCH_SIZE; // number of documents to process before dumping to disk
-
-pub fn decompress_gzip_file(file_path: &str) -> Result<Box<dyn BufRead>> {
-    let file = File::open(file_path)?;
-    let decoder = GzDecoder::new(file);
-    Ok(Box::new(BufReader::new(decoder)))
-}
+use crate::analyzer::Analyzer;
+use crate::indexer;
+use crate::indexer::DocMetadata;
+use crate::config::{IndexConfig, IndexPaths};
+use crate::doc_store::{batch_doc_store, merge_batch_doc_stores, DocStoreWriter};
+use crate::doc_table::{batch_doc_metadata, merge_batch_doc_metadata};
+use crate::parser::stored_text;
+use crate::sources::{DocumentSource, SourceDocument};
+
+// Index the documents of the collection, numbering them from first_doc_id on
+pub fn process_collection(source: Box<dyn DocumentSource>, paths: &IndexPaths, config: &IndexConfig,
+                          first_doc_id: u32) -> std::io::Result<()> {
+    // Initialize the logger, a build earlier in the same process keeps the one it installed
+    let log_file = File::create(paths.log())?;
+    let _ = WriteLogger::init(LevelFilter::Info, Config::default(), log_file);
+
+    let postings_dir = paths.postings_dir();
+    let doc_metadata_dir = paths.doc_metadata_batches_dir();
+    let doc_store_dir = paths.doc_store_batches_dir();
+    // Left over by an interrupted build
+    for dir in [&doc_metadata_dir, &doc_store_dir] {
+        if Path::new(dir).exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+    }
+    let threads = config.threads
+        .unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1))
+        .max(1);
+
+    // The reader thread hands batches of documents to the workers, a few batches ahead at most.
+    // Each worker owns its receiver handle, so the reader stops instead of blocking if all of them died.
+    let (sender, receiver) = mpsc::sync_channel::<DocumentBatch>(2 * threads);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let analyzer = Analyzer::new(&config.analyzer);
+
+    let (read_result, indexers) = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let postings_dir = &postings_dir;
+                let doc_metadata_dir = &doc_metadata_dir;
+                let doc_store_dir = &doc_store_dir;
+                let analyzer = &analyzer;
+                scope.spawn(move || -> io::Result<indexer::Indexer> {
+                    let mut indexer = indexer::Indexer::new();
+                    loop {
+                        let batch = receiver.lock().unwrap().recv();
+                        let Ok(batch) = batch else { break };
+
+                        let mut doc_metadata = HashMap::with_capacity(batch.documents.len());
+                        for (offset, document) in batch.documents.iter().enumerate() {
+                            let doc_id = batch.first_doc_id + offset as u32;
+                            doc_metadata.insert(doc_id, indexer.process_document(doc_id, document, analyzer));
+                        }
+                        indexer.dump_postings_to_disk(postings_dir, batch.batch_number);
+                        write_doc_metadata_to_disk(&doc_metadata, &batch_doc_metadata(doc_metadata_dir, batch.batch_number))?;
+                        if config.store_documents {
+                            write_batch_doc_store(&batch, doc_store_dir)?;
+                        }
+                    }
+                    Ok(indexer)
+                })
+            })
+            .collect();
+        drop(receiver);
+
+        let read_result = read_documents(source, config, first_doc_id, sender);
+        let indexers: Vec<_> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+        (read_result, indexers)
+    });
+    let doc_count = read_result?;
+    let indexers = indexers.into_iter().collect::<io::Result<Vec<_>>>()?;
+
+    let mut indexers = indexers.into_iter();
+    let mut indexer = indexers.next().unwrap_or_else(indexer::Indexer::new);
+    for other in indexers {
+        indexer.merge(other);
+    }
 
-pub fn process_gzip_file(file_path: &str) -> std::io::Result<()> {
-    // Initialize the logger
-    let log_file = File::create("indexer.log").unwrap();
-    WriteLogger::init(LevelFilter::Info, Config::default(), log_file).unwrap();
+    indexer.dump_lexicon_to_disk(&paths.term_ids());
+    merge_batch_doc_metadata(&doc_metadata_dir, paths)?;
 
-    let reader = decompress_gzip_file(file_path)?;
-    let mut indexer = indexer::Indexer::new();
+    if config.store_documents {
+        merge_batch_doc_stores(&doc_store_dir, &paths.doc_store(), &paths.doc_store_index())?;
+    } else {
+        // The store of a previous build of the directory would not match the new documents
+        for path in [paths.doc_store(), paths.doc_store_index()] {
+            if Path::new(&path).exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
 
-    let mut current_doc = Vec::new();
-    let mut doc_count = 0;
+    info!("The number of documents processed: {}", doc_count);
+    info!("The number of all terms: {}", indexer.current_term_id);
 
-    for line in reader.lines() {
-        let line = line?;
-        current_doc.push(line.clone());
+    Ok(())
+}
 
-        if line.contains("</DOC>") {
-            let full_doc = current_doc.join("\n");
-            indexer.process_document(&full_doc);
+// Consecutive documents of the collection, indexed and dumped to disk together
+struct DocumentBatch {
+    batch_number: usize,
+    first_doc_id: u32,
+    documents: Vec<SourceDocument>,
+}
 
-            // If we've reached our batch size, dump to disk and clear the current postings.
-            doc_count += 1;
-            if doc_count % BATCH_SIZE == 0 {
-                indexer.dump_postings_to_disk();
-            }
+// Store the text of the documents of the batch in a document store of its own
+fn write_batch_doc_store(batch: &DocumentBatch, doc_store_dir: &str) -> io::Result<()> {
+    let (data_path, index_path) = batch_doc_store(doc_store_dir, batch.batch_number);
+    let mut writer = DocStoreWriter::create(&data_path, &index_path)?;
+    for (offset, document) in batch.documents.iter().enumerate() {
+        writer.add(batch.first_doc_id + offset as u32, &stored_text(document))?;
+    }
+    writer.finish()
+}
 
-            // Clear th e current doc for the next one.
-            current_doc.clear();
-        }
+// Read the documents of the collection and send them in batches of batch_size documents.
+// Docids are assigned in collection order here, so they do not depend on the number of workers.
+fn read_documents(mut source: Box<dyn DocumentSource>, config: &IndexConfig, first_doc_id: u32,
+                  sender: SyncSender<DocumentBatch>) -> std::io::Result<usize> {
+    let mut batch = Vec::with_capacity(config.batch_size);
+    let mut batch_number = 0;
+    let mut doc_count = 0;
 
-        if utils::DEBUG_MODE && doc_count > utils::DEBUG_DOC_LIMIT {
-            break;
+    let mut send = |batch: Vec<SourceDocument>, doc_count: usize| {
+        let batch = DocumentBatch {
+            batch_number,
+            first_doc_id: first_doc_id + (doc_count - batch.len()) as u32,
+            documents: batch,
+        };
+        batch_number += 1;
+        sender.send(batch).map_err(|_| io::Error::other("All indexing workers stopped"))
+    };
+
+    while config.doc_limit.is_none_or(|limit| doc_count < limit) {
+        let Some(document) = source.next_document()? else { break };
+        batch.push(document);
+        doc_count += 1;
+
+        // If we've reached our batch size, hand it over to a worker
+        if batch.len() == config.batch_size {
+            send(std::mem::take(&mut batch), doc_count)?;
         }
     }
 
-    // After processing all documents, dump any remaining postings that didn't reach the next batch size.
-    if !current_doc.is_empty() {
-        let full_doc = current_doc.join("\n");
-        indexer.process_document(&full_doc);
-        indexer.dump_postings_to_disk();
+    // Send the remaining documents that didn't reach the next batch size.
+    if !batch.is_empty() {
+        send(batch, doc_count)?;
     }
 
-    indexer.dump_lexicon_to_disk();
-    indexer.dump_doc_metadata_to_disk();
-
-    info!("The number of documents processed: {}", doc_count);
-    info!("The number of all terms: {}", indexer.current_term_id);
-
-    Ok(())
+    Ok(doc_count)
 }
 
-pub fn write_posting_to_disk(postings: &HashMap<u32, HashMap<u32, u32>>, term_id_map: &BiMap<String, u32>) {
+pub fn write_posting_to_disk(postings: &HashMap<u32, HashMap<u32, Vec<u32>>>, term_id_map: &BiMap<String, u32>,
+                             postings_dir: &str, batch_number: usize) {
+    if postings.is_empty() {
+        return;
+    }
+
     // Create a vector of term_string, postings_list pairs
     let mut postings_with_terms: Vec<_> = postings.iter()
         .filter_map(|(&token_id, postings_list)| {
@@ -86,12 +186,11 @@ pub fn write_posting_to_disk(postings: &HashMap<u32, HashMap<u32, u32>>, term_id
     // Sort the vector based on term_string
     postings_with_terms.sort_by_key(|(term_string, _)| term_string.clone());
 
-    // Get the current timestamp
-    let current_time = Utc::now();
-    let filename = format!("postings_{}.data", current_time.format("%Y%m%d%H%M%S%f"));
+    // Batches are dumped concurrently, their number keeps the file names unique
+    let filename = format!("postings_{:06}.data", batch_number);
 
     // Path to store the postings
-    let path = Path::new("postings_data").join(filename);
+    let path = Path::new(postings_dir).join(filename);
 
     // Create the directory if it doesn't exist
     std::fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
@@ -123,7 +222,7 @@ pub fn write_posting_to_disk(postings: &HashMap<u32, HashMap<u32, u32>>, term_id
 
 
 
-pub fn write_lexicon_to_disk(lexicon: &BiMap<String, u32>) {
+pub fn write_lexicon_to_disk(lexicon: &BiMap<String, u32>, lexicon_path: &str) {
     // Sort the lexicon based on the terms (left values)
     let mut sorted_terms: Vec<_> = lexicon.left_values().cloned().collect();
     sorted_terms.sort();
@@ -134,34 +233,34 @@ pub fn write_lexicon_to_disk(lexicon: &BiMap<String, u32>) {
         .collect();
 
     // Path to store the lexicon
-    let path = Path::new("data").join("lexicon.data");
+    let path = Path::new(lexicon_path);
     std::fs::create_dir_all(path.parent().unwrap()).expect("Failed to create directory");
 
     #[cfg(feature = "debug_unicode")]
     {
         let serialized_data = serde_json::to_string(&terms_with_ids).expect("Failed to serialize lexicon as JSON");
-        let mut file = File::create(&path).expect("Failed to create file");
+        let mut file = File::create(path).expect("Failed to create file");
         file.write_all(serialized_data.as_bytes()).expect("Failed to write to file");
     }
 
     #[cfg(not(feature = "debug_unicode"))]
     {
         let serialized_data = bincode::serialize(&terms_with_ids).expect("Failed to serialize lexicon");
-        let mut file = File::create(&path).expect("Failed to create file");
+        let mut file = File::create(path).expect("Failed to create file");
         file.write_all(&serialized_data).expect("Failed to write to file");
     }
 }
 
-pub fn write_doc_metadata_to_disk(metadata: &HashMap<u32, (String, u32)>) -> io::Result<()> {
-    let path = Path::new("data").join("doc_metadata.data");
+pub fn write_doc_metadata_to_disk(metadata: &HashMap<u32, DocMetadata>, doc_metadata_path: &str) -> io::Result<()> {
+    let path = Path::new(doc_metadata_path);
     std::fs::create_dir_all(path.parent().unwrap())?;
 
-    let mut file = File::create(&path)?;
+    let mut file = File::create(path)?;
 
     #[cfg(feature = "debug_unicode")]
     {
-        for (&doc_id, (doc_name, doc_length)) in metadata {
-            let serialized_data = serde_json::to_string(&(doc_id, doc_name, doc_length))
+        for (&doc_id, doc) in metadata {
+            let serialized_data = serde_json::to_string(&(doc_id, doc))
                 .expect("Failed to serialize doc_metadata as JSON");
             writeln!(file, "{}", serialized_data)?;
         }
@@ -179,10 +278,25 @@ pub fn write_doc_metadata_to_disk(metadata: &HashMap<u32, (String, u32)>) -> io:
     Ok(())
 }
 
+// The batch files of the directory with the extension, in batch order. No batch directory if there were no
+// documents.
+pub fn batch_files(batch_dir: &str, extension: &str) -> io::Result<Vec<PathBuf>> {
+    let mut batches = Vec::new();
+    if Path::new(batch_dir).exists() {
+        for entry in read_dir(batch_dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|batch_extension| batch_extension == extension) {
+                batches.push(path);
+            }
+        }
+    }
+    // The batch numbers are zero-padded, name order is batch order
+    batches.sort();
+    Ok(batches)
+}
 
-pub fn merge_sorted_postings() -> std::io::Result<()> {
-    let dir = Path::new("postings_data");
-    let output_dir = Path::new("data");
+pub fn merge_sorted_postings(postings_dir: &str, merged_postings_path: &str) -> std::io::Result<()> {
+    let dir = Path::new(postings_dir);
 
     // Get all batches (files) in the postings_data directory
     let files: Vec<_> = read_dir(dir)?
@@ -190,32 +304,32 @@ pub fn merge_sorted_postings() -> std::io::Result<()> {
         .map(|entry| entry.path())
         .collect();
 
-    // Merge these batches into the desired output directory
-    let merged_output_path = output_dir.join("merged_postings.data");
-    merge_sorted_files(&merged_output_path.to_string_lossy(), files)
+    // Merge these batches into the desired output file
+    merge_sorted_files(merged_postings_path, files)
 }
 
 
-pub fn load_doc_metadata(doc_metadata_path: &str) -> Result<HashMap<u32, (String, u32)>> {
+pub fn load_doc_metadata(doc_metadata_path: &str) -> Result<HashMap<u32, DocMetadata>> {
     let path = Path::new(doc_metadata_path);
     let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
+    let reader = BufReader::new(file);
 
     #[cfg(feature = "debug_unicode")]
     {
+        use std::io::BufRead;
         let mut metadata = HashMap::new();
         for line in reader.lines() {
             let line = line?;
-            let tuple: (u32, String, u32) = serde_json::from_str(&line)
+            let (doc_id, doc): (u32, DocMetadata) = serde_json::from_str(&line)
                 .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-            metadata.insert(tuple.0, (tuple.1, tuple.2));
+            metadata.insert(doc_id, doc);
         }
         Ok(metadata)
     }
 
     #[cfg(not(feature = "debug_unicode"))]
     {
-        let metadata: HashMap<u32, (String, u32)> = bincode::deserialize_from(reader)
+        let metadata: HashMap<u32, DocMetadata> = bincode::deserialize_from(reader)
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         Ok(metadata)
     }
@@ -0,0 +1,183 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use memmap2::Mmap;
+use crate::config::IndexPaths;
+use crate::disk_io::batch_files;
+
+// Number of consecutive documents compressed together. Larger blocks compress better but every lookup
+// decompresses a whole block.
+const DOC_STORE_BLOCK_DOCS: usize = 16;
+// Size of an entry of the block index: the first docid of the block and its offset in the data file
+const BLOCK_INDEX_ENTRY: usize = 12;
+
+// Text of the indexed documents, for snippets. The data file is a sequence of deflate-compressed blocks of
+// (docid, length, UTF-8 text) entries in increasing docid order, the index file locates the block of a docid.
+// Docids may be missing from a block, a compaction drops the deleted documents.
+pub struct DocStore {
+    data: Mmap,
+    // (first docid, offset) of every block, in docid order
+    blocks: Vec<(u32, u64)>,
+}
+
+impl DocStore {
+    // None for a segment built without a document store
+    pub fn open(paths: &IndexPaths) -> io::Result<Option<Self>> {
+        let index = match fs::read(paths.doc_store_index()) {
+            Ok(index) => index,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let blocks = index.chunks_exact(BLOCK_INDEX_ENTRY)
+            .map(|entry| (LittleEndian::read_u32(&entry[0..]), LittleEndian::read_u64(&entry[4..])))
+            .collect();
+
+        // SAFETY: the store is written once with the rest of the segment and never modified while being served
+        let data = unsafe { Mmap::map(&File::open(paths.doc_store())?)? };
+        Ok(Some(Self { data, blocks }))
+    }
+
+    // Smallest docid of the store, None if it is empty
+    pub fn first_doc_id(&self) -> Option<u32> {
+        self.blocks.first().map(|&(doc_id, _)| doc_id)
+    }
+
+    // Text of the document, None if it is not in the store
+    pub fn document(&self, doc_id: u32) -> io::Result<Option<String>> {
+        let block = self.blocks.partition_point(|&(first_doc_id, _)| first_doc_id <= doc_id);
+        if block == 0 {
+            return Ok(None);
+        }
+
+        Ok(self.block(block - 1)?.into_iter()
+            .find(|(block_doc_id, _)| *block_doc_id == doc_id)
+            .map(|(_, text)| text))
+    }
+
+    // All the documents of the store in docid order, block by block
+    pub fn documents(&self) -> impl Iterator<Item = io::Result<Vec<(u32, String)>>> + '_ {
+        (0..self.blocks.len()).map(|block| self.block(block))
+    }
+
+    fn block(&self, block: usize) -> io::Result<Vec<(u32, String)>> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let start = self.blocks[block].1 as usize;
+        let end = self.blocks.get(block + 1).map_or(self.data.len(), |&(_, offset)| offset as usize);
+        let compressed = self.data.get(start..end).ok_or_else(|| invalid("Document store block out of bounds"))?;
+
+        let mut bytes = Vec::new();
+        DeflateDecoder::new(compressed).read_to_end(&mut bytes)?;
+
+        let mut documents = Vec::new();
+        let mut entry = bytes.as_slice();
+        while !entry.is_empty() {
+            let header = entry.get(..8).ok_or_else(|| invalid("Truncated document store entry"))?;
+            let doc_id = LittleEndian::read_u32(&header[0..]);
+            let length = LittleEndian::read_u32(&header[4..]) as usize;
+            let text = entry.get(8..8 + length).ok_or_else(|| invalid("Truncated document store entry"))?;
+            let text = String::from_utf8(text.to_vec()).map_err(|_| invalid("Document text is not UTF-8"))?;
+            documents.push((doc_id, text));
+            entry = &entry[8 + length..];
+        }
+        Ok(documents)
+    }
+}
+
+// Appends documents, in increasing docid order, to a new document store
+pub struct DocStoreWriter {
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    offset: u64,
+    // Uncompressed entries of the block being filled
+    block: Vec<u8>,
+    block_first_doc_id: u32,
+    block_docs: usize,
+}
+
+impl DocStoreWriter {
+    pub fn create(data_path: &str, index_path: &str) -> io::Result<Self> {
+        if let Some(parent) = Path::new(data_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Self {
+            data: BufWriter::new(File::create(data_path)?),
+            index: BufWriter::new(File::create(index_path)?),
+            offset: 0,
+            block: Vec::new(),
+            block_first_doc_id: 0,
+            block_docs: 0,
+        })
+    }
+
+    pub fn add(&mut self, doc_id: u32, text: &str) -> io::Result<()> {
+        if self.block_docs == 0 {
+            self.block_first_doc_id = doc_id;
+        }
+        self.block.extend_from_slice(&doc_id.to_le_bytes());
+        self.block.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        self.block.extend_from_slice(text.as_bytes());
+        self.block_docs += 1;
+
+        if self.block_docs == DOC_STORE_BLOCK_DOCS {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.block_docs > 0 {
+            self.write_block()?;
+        }
+        self.data.flush()?;
+        self.index.flush()
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.block)?;
+        let compressed = encoder.finish()?;
+
+        self.data.write_all(&compressed)?;
+        self.index.write_all(&self.block_first_doc_id.to_le_bytes())?;
+        self.index.write_all(&self.offset.to_le_bytes())?;
+        self.offset += compressed.len() as u64;
+
+        self.block.clear();
+        self.block_docs = 0;
+        Ok(())
+    }
+}
+
+// Data and index paths of the document store of a batch of documents, written by an indexing worker
+pub fn batch_doc_store(doc_store_dir: &str, batch_number: usize) -> (String, String) {
+    let path = Path::new(doc_store_dir).join(format!("docs_{:06}", batch_number));
+    (path.with_extension("data").to_string_lossy().into_owned(),
+     path.with_extension("index").to_string_lossy().into_owned())
+}
+
+// Concatenate the document stores of the batches, in batch order hence docid order, into the store of the
+// segment and remove them
+pub fn merge_batch_doc_stores(doc_store_dir: &str, data_path: &str, index_path: &str) -> io::Result<()> {
+    let batches = batch_files(doc_store_dir, "data")?;
+    let mut data = BufWriter::new(File::create(data_path)?);
+    let mut index = BufWriter::new(File::create(index_path)?);
+    let mut offset = 0;
+    for batch_data in batches {
+        for entry in fs::read(batch_data.with_extension("index"))?.chunks_exact(BLOCK_INDEX_ENTRY) {
+            index.write_all(&entry[..4])?;
+            index.write_all(&(offset + LittleEndian::read_u64(&entry[4..])).to_le_bytes())?;
+        }
+        offset += io::copy(&mut File::open(batch_data)?, &mut data)?;
+    }
+    data.flush()?;
+    index.flush()?;
+
+    if Path::new(doc_store_dir).exists() {
+        fs::remove_dir_all(doc_store_dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,303 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
+use crate::config::IndexPaths;
+use crate::disk_io::{batch_files, load_doc_metadata};
+use crate::fields::{Field, FIELDS};
+use crate::indexer::DocMetadata;
+
+// Length of the docids of the range of a segment that have no document, dropped by a compaction
+const MISSING_DOC: u32 = u32::MAX;
+// The lengths file header is (first docid, number of documents)
+const LENGTHS_HEADER: usize = 8;
+// Size of the entry of a docid in the string offsets file: offset of its DOCNO in the strings and DOCNO length
+const STRING_OFFSET_ENTRY: usize = 12;
+// Size of the entry of a docid in the field lengths file: number of terms of its URL line, its title and its
+// headings.
+const FIELD_LENGTHS_ENTRY: usize = 12;
+
+// Metadata of the documents of a segment, in columns memory-mapped from disk and indexed by docid, so that
+// opening it reads nothing and only the pages of the documents looked up are loaded:
+// - doc_lengths: the number of terms of every docid of the segment range, a dense array
+// - doc_strings: the DOCNO followed by the URL of every document, a string heap
+// - doc_string_offsets: where the strings of every docid start in the heap
+// - docno_index: the docids sorted by DOCNO, for the reverse lookup
+// - doc_field_lengths: the number of URL, title and heading terms of every docid
+pub struct DocTable {
+    first_doc_id: u32,
+    doc_count: u32,
+    lengths: Mmap,
+    string_offsets: Mmap,
+    strings: Mmap,
+    docno_index: Mmap,
+    field_lengths: Mmap,
+}
+
+impl DocTable {
+    pub fn open(paths: &IndexPaths) -> io::Result<Self> {
+        let lengths = map(&paths.doc_lengths())?;
+        if lengths.len() < LENGTHS_HEADER {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Truncated document lengths file"));
+        }
+        Ok(Self {
+            first_doc_id: LittleEndian::read_u32(&lengths[0..]),
+            doc_count: LittleEndian::read_u32(&lengths[4..]),
+            lengths,
+            string_offsets: map(&paths.doc_string_offsets())?,
+            strings: map(&paths.doc_strings())?,
+            docno_index: map(&paths.docno_index())?,
+            field_lengths: map(&paths.doc_field_lengths())?,
+        })
+    }
+
+    // Number of documents, not counting the missing docids
+    pub fn doc_count(&self) -> u32 {
+        self.doc_count
+    }
+
+    // The docids of the segment are in first_doc_id..end_doc_id
+    pub fn end_doc_id(&self) -> u32 {
+        self.first_doc_id + self.slots() as u32
+    }
+
+    pub fn contains(&self, doc_id: u32) -> bool {
+        self.length(doc_id).is_some()
+    }
+
+    // Number of terms of the document, None if it is not in the segment
+    pub fn length(&self, doc_id: u32) -> Option<u32> {
+        let slot = doc_id.checked_sub(self.first_doc_id)? as usize;
+        let position = LENGTHS_HEADER + 4 * slot;
+        let length = LittleEndian::read_u32(self.lengths.get(position..position + 4)?);
+        Some(length).filter(|&length| length != MISSING_DOC)
+    }
+
+    // Number of terms of every field of the document, indexed by Field. None if it is not in the segment.
+    pub fn field_lengths(&self, doc_id: u32) -> Option<[u32; FIELDS]> {
+        let length = self.length(doc_id)?;
+        let position = FIELD_LENGTHS_ENTRY * (doc_id - self.first_doc_id) as usize;
+        let entry = self.field_lengths.get(position..position + FIELD_LENGTHS_ENTRY)?;
+        let mut lengths = [0; FIELDS];
+        for (field, length) in lengths.iter_mut().zip(entry.chunks_exact(4)) {
+            *field = LittleEndian::read_u32(length);
+        }
+        lengths[Field::Body as usize] = length.saturating_sub(lengths[..Field::Body as usize].iter().sum());
+        Some(lengths)
+    }
+
+    // Total number of terms of every field of the documents
+    pub fn field_totals(&self) -> [u64; FIELDS] {
+        let mut totals = [0; FIELDS];
+        for lengths in (self.first_doc_id..self.end_doc_id()).filter_map(|doc_id| self.field_lengths(doc_id)) {
+            for (total, length) in totals.iter_mut().zip(lengths) {
+                *total += length as u64;
+            }
+        }
+        totals
+    }
+
+    pub fn docno(&self, doc_id: u32) -> Option<&str> {
+        self.strings(doc_id).map(|(docno, _)| docno)
+    }
+
+    pub fn url(&self, doc_id: u32) -> Option<&str> {
+        self.strings(doc_id).map(|(_, url)| url)
+    }
+
+    // The documents with the DOCNO, in docid order
+    pub fn doc_ids(&self, docno: &str) -> Vec<u32> {
+        let doc_id_at = |index: usize| LittleEndian::read_u32(&self.docno_index[4 * index..]);
+        let docno_at = |index: usize| self.docno(doc_id_at(index)).unwrap_or_default();
+        let len = self.docno_index.len() / 4;
+        let start = partition_point(len, |index| docno_at(index) < docno);
+        let end = partition_point(len, |index| docno_at(index) <= docno);
+        (start..end).map(doc_id_at).collect()
+    }
+
+    // Docid and metadata of every document, in docid order
+    pub fn documents(&self) -> impl Iterator<Item = (u32, DocMetadata)> + '_ {
+        (self.first_doc_id..self.end_doc_id()).filter_map(|doc_id| {
+            let length = self.length(doc_id)?;
+            let (docno, url) = self.strings(doc_id)?;
+            let field_lengths = self.field_lengths(doc_id)?;
+            Some((doc_id, DocMetadata { docno: docno.to_string(), url: url.to_string(), length, field_lengths }))
+        })
+    }
+
+    // Lengths of the documents, in docid order
+    pub fn lengths(&self) -> impl Iterator<Item = u32> + '_ {
+        self.lengths[LENGTHS_HEADER..].chunks_exact(4)
+            .map(LittleEndian::read_u32)
+            .filter(|&length| length != MISSING_DOC)
+    }
+
+    fn slots(&self) -> usize {
+        (self.lengths.len() - LENGTHS_HEADER) / 4
+    }
+
+    fn strings(&self, doc_id: u32) -> Option<(&str, &str)> {
+        self.length(doc_id)?;
+        document_strings(&self.string_offsets, &self.strings, (doc_id - self.first_doc_id) as usize)
+    }
+}
+
+// DOCNO and URL of the document in the slot
+fn document_strings<'a>(string_offsets: &[u8], strings: &'a [u8], slot: usize) -> Option<(&'a str, &'a str)> {
+    let entry = string_offsets.get(slot * STRING_OFFSET_ENTRY..(slot + 1) * STRING_OFFSET_ENTRY)?;
+    let start = LittleEndian::read_u64(&entry[0..]) as usize;
+    let docno_end = start + LittleEndian::read_u32(&entry[8..]) as usize;
+    // The strings of a document end where the ones of the next slot start
+    let end = string_offsets.get((slot + 1) * STRING_OFFSET_ENTRY..(slot + 1) * STRING_OFFSET_ENTRY + 8)
+        .map_or(strings.len(), |next| LittleEndian::read_u64(next) as usize);
+
+    let docno = std::str::from_utf8(strings.get(start..docno_end)?).ok()?;
+    let url = std::str::from_utf8(strings.get(docno_end..end)?).ok()?;
+    Some((docno, url))
+}
+
+// First index of 0..len for which the predicate, true then false over the range, is false
+fn partition_point(len: usize, predicate: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if predicate(middle) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+fn map(path: &str) -> io::Result<Mmap> {
+    // SAFETY: the doc table is written once with the rest of the segment and never modified while being served
+    unsafe { Mmap::map(&File::open(path)?) }
+}
+
+// Writes the doc table of a segment, the documents are added in increasing docid order
+pub struct DocTableWriter {
+    paths: IndexPaths,
+    lengths: BufWriter<File>,
+    string_offsets: BufWriter<File>,
+    strings: BufWriter<File>,
+    field_lengths: BufWriter<File>,
+    first_doc_id: Option<u32>,
+    next_doc_id: u32,
+    doc_count: u32,
+    strings_length: u64,
+}
+
+impl DocTableWriter {
+    pub fn create(paths: &IndexPaths) -> io::Result<Self> {
+        fs::create_dir_all(paths.dir())?;
+        let mut lengths = BufWriter::new(File::create(paths.doc_lengths())?);
+        // Header, rewritten by finish
+        lengths.write_all(&[0; LENGTHS_HEADER])?;
+
+        Ok(Self {
+            paths: paths.clone(),
+            lengths,
+            string_offsets: BufWriter::new(File::create(paths.doc_string_offsets())?),
+            strings: BufWriter::new(File::create(paths.doc_strings())?),
+            field_lengths: BufWriter::new(File::create(paths.doc_field_lengths())?),
+            first_doc_id: None,
+            next_doc_id: 0,
+            doc_count: 0,
+            strings_length: 0,
+        })
+    }
+
+    pub fn add(&mut self, doc_id: u32, doc: &DocMetadata) -> io::Result<()> {
+        if self.first_doc_id.is_none() {
+            self.first_doc_id = Some(doc_id);
+            self.next_doc_id = doc_id;
+        }
+        if doc_id < self.next_doc_id {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Documents added out of docid order"));
+        }
+
+        // The docids skipped in between are missing, with empty strings
+        while self.next_doc_id < doc_id {
+            self.write_slot(MISSING_DOC, "", "", [0; FIELDS])?;
+        }
+        self.write_slot(doc.length, &doc.docno, &doc.url, doc.field_lengths)?;
+        self.doc_count += 1;
+        Ok(())
+    }
+
+    fn write_slot(&mut self, length: u32, docno: &str, url: &str, field_lengths: [u32; FIELDS]) -> io::Result<()> {
+        self.lengths.write_all(&length.to_le_bytes())?;
+        for field in [Field::Url, Field::Title, Field::Heading] {
+            self.field_lengths.write_all(&field_lengths[field as usize].to_le_bytes())?;
+        }
+        self.string_offsets.write_all(&self.strings_length.to_le_bytes())?;
+        self.string_offsets.write_all(&(docno.len() as u32).to_le_bytes())?;
+        self.strings.write_all(docno.as_bytes())?;
+        self.strings.write_all(url.as_bytes())?;
+        self.strings_length += (docno.len() + url.len()) as u64;
+        self.next_doc_id += 1;
+        Ok(())
+    }
+
+    // Number of documents added
+    pub fn doc_count(&self) -> u32 {
+        self.doc_count
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let first_doc_id = self.first_doc_id.unwrap_or(0);
+        self.lengths.seek(SeekFrom::Start(0))?;
+        self.lengths.write_all(&first_doc_id.to_le_bytes())?;
+        self.lengths.write_all(&self.doc_count.to_le_bytes())?;
+        self.lengths.flush()?;
+        self.string_offsets.flush()?;
+        self.strings.flush()?;
+        self.field_lengths.flush()?;
+
+        // The docids sorted by DOCNO, read back from the written strings rather than kept in memory
+        let lengths = map(&self.paths.doc_lengths())?;
+        let string_offsets = map(&self.paths.doc_string_offsets())?;
+        let strings = map(&self.paths.doc_strings())?;
+        let docno = |slot: usize| document_strings(&string_offsets, &strings, slot).map_or("", |(docno, _)| docno);
+
+        let mut slots: Vec<usize> = lengths[LENGTHS_HEADER..].chunks_exact(4).enumerate()
+            .filter(|(slot, length)| LittleEndian::read_u32(length) != MISSING_DOC && !docno(*slot).is_empty())
+            .map(|(slot, _)| slot)
+            .collect();
+        slots.sort_by(|&a, &b| docno(a).cmp(docno(b)).then(a.cmp(&b)));
+
+        let mut docno_index = BufWriter::new(File::create(self.paths.docno_index())?);
+        for slot in slots {
+            docno_index.write_all(&(first_doc_id + slot as u32).to_le_bytes())?;
+        }
+        docno_index.flush()
+    }
+}
+
+// Path of the metadata of a batch of documents, written by an indexing worker
+pub fn batch_doc_metadata(doc_metadata_dir: &str, batch_number: usize) -> String {
+    Path::new(doc_metadata_dir).join(format!("docs_{:06}.data", batch_number)).to_string_lossy().into_owned()
+}
+
+// Write the metadata of the batches, in batch order hence docid order, into the doc table of the segment
+// and remove them
+pub fn merge_batch_doc_metadata(doc_metadata_dir: &str, paths: &IndexPaths) -> io::Result<()> {
+    let batches = batch_files(doc_metadata_dir, "data")?;
+    let mut writer = DocTableWriter::create(paths)?;
+    for batch in batches {
+        let batch_metadata = load_doc_metadata(&batch.to_string_lossy())?;
+        let mut doc_ids: Vec<u32> = batch_metadata.keys().copied().collect();
+        doc_ids.sort_unstable();
+        for doc_id in doc_ids {
+            writer.add(doc_id, &batch_metadata[&doc_id])?;
+        }
+    }
+    writer.finish()?;
+
+    if Path::new(doc_metadata_dir).exists() {
+        fs::remove_dir_all(doc_metadata_dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+use log::warn;
+
+// Cutoff of MRR and nDCG, the official MS MARCO metrics
+const TOP_RANKS: usize = 10;
+// Number of random sign flips of the randomization test, and its seed so that p-values are reproducible
+const RANDOMIZATION_TRIALS: usize = 10_000;
+const RANDOMIZATION_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+// Significance level marked in the comparison
+const SIGNIFICANCE_LEVEL: f64 = 0.05;
+
+// Relevance grade of the judged documents of each query, by qid then docno
+pub type Qrels = HashMap<String, HashMap<String, u32>>;
+// Retrieved docnos of each query, best first
+pub type Run = HashMap<String, Vec<String>>;
+
+// Metric values of every evaluated query, in the order of the metric names
+pub struct Evaluation {
+    metrics: Vec<String>,
+    queries: BTreeMap<String, Vec<f64>>,
+}
+
+impl Evaluation {
+    pub fn mean(&self, metric: usize) -> f64 {
+        if self.queries.is_empty() {
+            return 0.0;
+        }
+        self.queries.values().map(|values| values[metric]).sum::<f64>() / self.queries.len() as f64
+    }
+
+    fn values(&self, metric: usize) -> Vec<f64> {
+        self.queries.values().map(|values| values[metric]).collect()
+    }
+}
+
+// TREC qrels file, `qid iteration docno relevance` lines
+pub fn load_qrels(path: &Path) -> io::Result<Qrels> {
+    let mut qrels: Qrels = HashMap::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            [] => {},
+            [qid, _, docno, relevance] => {
+                let relevance = relevance.parse::<i32>().map_err(|_| invalid_line("qrels", number))?;
+                // Negative grades mark judged non-relevant documents
+                qrels.entry(qid.to_string()).or_default().insert(docno.to_string(), relevance.max(0) as u32);
+            },
+            _ => return Err(invalid_line("qrels", number)),
+        }
+    }
+    Ok(qrels)
+}
+
+// TREC run file, `qid Q0 docno rank score tag` lines. Documents are ordered by decreasing score and
+// increasing rank between equal scores, whatever the order of the lines.
+pub fn load_run(path: &Path) -> io::Result<Run> {
+    let mut entries: HashMap<String, Vec<(f64, u64, String)>> = HashMap::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        match fields[..] {
+            [] => {},
+            [qid, _, docno, rank, score, _] => {
+                let rank = rank.parse().map_err(|_| invalid_line("run", number))?;
+                let score = score.parse().map_err(|_| invalid_line("run", number))?;
+                entries.entry(qid.to_string()).or_default().push((score, rank, docno.to_string()));
+            },
+            _ => return Err(invalid_line("run", number)),
+        }
+    }
+
+    Ok(entries.into_iter()
+        .map(|(qid, mut ranking)| {
+            ranking.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+            (qid, ranking.into_iter().map(|(_, _, docno)| docno).collect())
+        })
+        .collect())
+}
+
+fn invalid_line(file: &str, number: usize) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Malformed line {} of the {} file", number + 1, file))
+}
+
+// Metrics of the run on every query with at least one relevant document. Queries missing from the run
+// score 0, so that runs retrieving nothing for hard queries are not rewarded for it.
+pub fn evaluate(qrels: &Qrels, run: &Run, cutoffs: &[usize]) -> Evaluation {
+    let mut metrics = vec![format!("MRR@{}", TOP_RANKS), format!("nDCG@{}", TOP_RANKS), "MAP".to_string()];
+    metrics.extend(cutoffs.iter().map(|k| format!("P@{}", k)));
+    metrics.extend(cutoffs.iter().map(|k| format!("Recall@{}", k)));
+
+    let unjudged = run.keys().filter(|qid| !qrels.contains_key(*qid)).count();
+    if unjudged > 0 {
+        warn!("{} queries of the run have no relevance judgments and are not evaluated", unjudged);
+    }
+
+    let queries = qrels.iter()
+        .filter(|(_, judgments)| judgments.values().any(|&relevance| relevance > 0))
+        .map(|(qid, judgments)| {
+            let ranking = run.get(qid).map(Vec::as_slice).unwrap_or_default();
+            (qid.clone(), query_metrics(judgments, ranking, cutoffs))
+        })
+        .collect();
+
+    Evaluation { metrics, queries }
+}
+
+// Values of the metrics of one query, in the order of their names in evaluate
+fn query_metrics(judgments: &HashMap<String, u32>, ranking: &[String], cutoffs: &[usize]) -> Vec<f64> {
+    let grades: Vec<u32> = ranking.iter()
+        .map(|docno| judgments.get(docno).copied().unwrap_or(0))
+        .collect();
+    let total_relevant = judgments.values().filter(|&&relevance| relevance > 0).count();
+    // Number of relevant documents among the first i + 1
+    let relevant_at: Vec<usize> = grades.iter()
+        .scan(0, |relevant, &grade| {
+            *relevant += (grade > 0) as usize;
+            Some(*relevant)
+        })
+        .collect();
+    let relevant_in_top = |k: usize| relevant_at[..k.min(relevant_at.len())].last().copied().unwrap_or(0);
+
+    let reciprocal_rank = grades.iter().take(TOP_RANKS)
+        .position(|&grade| grade > 0)
+        .map_or(0.0, |rank| 1.0 / (rank + 1) as f64);
+
+    // Linear gains, as trec_eval
+    let dcg = |grades: &[u32]| -> f64 {
+        grades.iter().take(TOP_RANKS).enumerate()
+            .fold(0.0, |dcg, (rank, &grade)| dcg + grade as f64 / (rank as f64 + 2.0).log2())
+    };
+    let mut ideal: Vec<u32> = judgments.values().copied().collect();
+    ideal.sort_unstable_by(|a, b| b.cmp(a));
+    let ndcg = dcg(&grades) / dcg(&ideal);
+
+    let average_precision = grades.iter().enumerate()
+        .filter(|(_, &grade)| grade > 0)
+        .fold(0.0, |sum, (rank, _)| sum + relevant_at[rank] as f64 / (rank + 1) as f64) / total_relevant as f64;
+
+    let mut values = vec![reciprocal_rank, ndcg, average_precision];
+    values.extend(cutoffs.iter().map(|&k| relevant_in_top(k) as f64 / k.max(1) as f64));
+    values.extend(cutoffs.iter().map(|&k| relevant_in_top(k) as f64 / total_relevant as f64));
+    values
+}
+
+// Mean of every metric, preceded by the value of every query if per_query is set, in trec_eval's format
+pub fn print_evaluation(evaluation: &Evaluation, per_query: bool) {
+    if per_query {
+        for (qid, values) in &evaluation.queries {
+            for (metric, value) in evaluation.metrics.iter().zip(values) {
+                println!("{:<12}\t{}\t{:.4}", metric, qid, value);
+            }
+        }
+    }
+
+    println!("{:<12}\tall\t{}", "num_q", evaluation.queries.len());
+    for (metric, name) in evaluation.metrics.iter().enumerate() {
+        println!("{:<12}\tall\t{:.4}", name, evaluation.mean(metric));
+    }
+}
+
+// Side-by-side means of the run and the baseline, evaluated on the same qrels and cutoffs, with the p-value
+// of a two-sided paired randomization test on each metric
+pub fn print_comparison(baseline: &Evaluation, run: &Evaluation) {
+    println!("{:<12}\t{:>8}\t{:>8}\t{:>8}\t{:>8}", "metric", "baseline", "run", "delta", "p-value");
+    for (metric, name) in run.metrics.iter().enumerate() {
+        let p_value = randomization_test(&baseline.values(metric), &run.values(metric));
+        let (baseline_mean, run_mean) = (baseline.mean(metric), run.mean(metric));
+        let marker = if p_value < SIGNIFICANCE_LEVEL { " *" } else { "" };
+        println!("{:<12}\t{:>8.4}\t{:>8.4}\t{:>+8.4}\t{:>8.4}{}", name, baseline_mean, run_mean,
+                 run_mean - baseline_mean, p_value, marker);
+    }
+    println!("* significant at p < {}, {} queries", SIGNIFICANCE_LEVEL, run.queries.len());
+}
+
+// Fraction of random swaps of the paired per-query values whose mean difference is at least the observed one.
+// Swapping a pair flips the sign of its difference.
+fn randomization_test(baseline: &[f64], run: &[f64]) -> f64 {
+    let differences: Vec<f64> = run.iter().zip(baseline).map(|(a, b)| a - b).collect();
+    // Tolerance for the floating point error of summing in another order
+    let observed = differences.iter().sum::<f64>().abs() - 1e-9;
+
+    // xorshift64, good enough to draw the signs and independent of any crate
+    let mut state = RANDOMIZATION_SEED;
+    let mut extreme = 0;
+    for _ in 0..RANDOMIZATION_TRIALS {
+        let mut sum = 0.0;
+        for difference in &differences {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            sum += if state >> 63 == 0 { *difference } else { -*difference };
+        }
+        if sum.abs() >= observed {
+            extreme += 1;
+        }
+    }
+
+    // The observed assignment counts as one of the trials, a p-value is never 0
+    (extreme + 1) as f64 / (RANDOMIZATION_TRIALS + 1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn judgments(grades: &[(&str, u32)]) -> HashMap<String, u32> {
+        grades.iter().map(|&(docno, grade)| (docno.to_string(), grade)).collect()
+    }
+
+    fn ranking(docnos: &[&str]) -> Vec<String> {
+        docnos.iter().map(|docno| docno.to_string()).collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn metrics_of_a_ranking() {
+        let judgments = judgments(&[("d1", 2), ("d2", 1), ("d3", 0), ("d4", 1)]);
+        let values = query_metrics(&judgments, &ranking(&["d3", "d1", "x", "d2"]), &[1, 2, 5]);
+
+        let expected_ndcg = (2.0 / 3f64.log2() + 1.0 / 5f64.log2()) / (2.0 + 1.0 / 3f64.log2() + 0.5);
+        let expected = [0.5, expected_ndcg, (1.0 / 2.0 + 2.0 / 4.0) / 3.0, 0.0, 0.5, 0.4, 0.0, 1.0 / 3.0, 2.0 / 3.0];
+        assert_eq!(values.len(), expected.len());
+        for (&actual, &expected) in values.iter().zip(&expected) {
+            assert_close(actual, expected);
+        }
+    }
+
+    #[test]
+    fn reciprocal_rank_and_ndcg_stop_at_the_top_ranks() {
+        let judgments = judgments(&[("relevant", 1)]);
+        let mut docnos = vec!["x"; TOP_RANKS];
+        docnos.push("relevant");
+        let values = query_metrics(&judgments, &ranking(&docnos), &[]);
+        assert_close(values[0], 0.0);
+        assert_close(values[1], 0.0);
+        assert_close(values[2], 1.0 / (TOP_RANKS + 1) as f64);
+    }
+
+    #[test]
+    fn queries_without_relevant_documents_are_not_evaluated() {
+        let mut qrels = Qrels::new();
+        qrels.insert("1".to_string(), judgments(&[("a", 1)]));
+        qrels.insert("2".to_string(), judgments(&[("b", 1)]));
+        qrels.insert("3".to_string(), judgments(&[("c", 0)]));
+        let mut run = Run::new();
+        run.insert("1".to_string(), ranking(&["a"]));
+        run.insert("4".to_string(), ranking(&["a"]));
+
+        // The query missing from the run scores 0
+        let evaluation = evaluate(&qrels, &run, &[10]);
+        assert_eq!(evaluation.queries.keys().collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(evaluation.metrics, ["MRR@10", "nDCG@10", "MAP", "P@10", "Recall@10"]);
+        assert_close(evaluation.mean(0), 0.5);
+        assert_close(evaluation.mean(4), 0.5);
+    }
+
+    #[test]
+    fn run_files_are_ordered_by_score_then_rank() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run");
+        fs::write(&path, "1 Q0 c 3 1.5 tag\n1 Q0 a 2 2.0 tag\n\n1 Q0 b 1 2.0 tag\n2 Q0 d 1 0.1 tag\n").unwrap();
+        let run = load_run(&path).unwrap();
+        assert_eq!(run["1"], ["b", "a", "c"]);
+        assert_eq!(run["2"], ["d"]);
+
+        fs::write(&path, "1 Q0 a 1 high tag\n").unwrap();
+        assert_eq!(load_run(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn negative_grades_are_not_relevant() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qrels");
+        fs::write(&path, "1 0 a 2\n1 0 b -1\n").unwrap();
+        assert_eq!(load_qrels(&path).unwrap()["1"], judgments(&[("a", 2), ("b", 0)]));
+
+        fs::write(&path, "1 0 a\n").unwrap();
+        assert_eq!(load_qrels(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn randomization_test_p_values() {
+        let baseline = vec![0.5; 20];
+        assert_close(randomization_test(&baseline, &baseline), 1.0);
+        // Only the observed signs and their opposite are as extreme, about 2 trials in a million
+        let better = vec![0.6; 20];
+        assert!(randomization_test(&baseline, &better) < 0.001);
+    }
+}
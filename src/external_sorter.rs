@@ -1,7 +1,12 @@
 use std::collections::{BinaryHeap, HashMap};
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::path::PathBuf;
+#[cfg(not(feature = "debug_unicode"))]
+use serde::de::DeserializeOwned;
+
+// (term, {doc_ID: [positions]}) record of a sorted batch
+type TermPostings = (String, HashMap<u32, Vec<u32>>);
 
 struct MergingIterator {
     reader: BufReader<File>,
@@ -12,46 +17,45 @@ impl MergingIterator {
         Ok(MergingIterator { reader: BufReader::new(file) })
     }
 
-    fn next(&mut self) -> Option<(String, HashMap<u32, u32>)> {
+    fn next(&mut self) -> io::Result<Option<TermPostings>> {
         #[cfg(feature = "debug_unicode")]
         {
-            let line = self.reader.by_ref().lines().next()?.ok()?;
-            match serde_json::from_str::<(String, HashMap<u32, u32>)>(&line) {
-                Ok(data) => Some(data),
-                Err(e) => {
-                    eprintln!("Failed to deserialize data from line: {}", e);
-                    None
-                }
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
             }
+            Ok(Some(serde_json::from_str(&line)?))
         }
 
         #[cfg(not(feature = "debug_unicode"))]
         {
-            // Read the length of the serialized tuple
-            let mut length_buffer = [0u8; 8];
-            if self.reader.read_exact(&mut length_buffer).is_err() {
-                return None;
-            }
-            let length = u64::from_le_bytes(length_buffer);
-
-            let mut buffer = vec![0u8; length as usize];
-            if self.reader.read_exact(&mut buffer).is_err() {
-                return None;
-            }
-
-            match bincode::deserialize::<(String, HashMap<u32, u32>)>(&buffer) {
-                Ok(data) => Some(data),
-                Err(e) => {
-                    eprintln!("(external sorter) Failed to deserialize binary data: {}", e);
-                    None
-                }
-            }
+            read_record(&mut self.reader)
         }
     }
 }
 
+// The next length prefixed record of a postings file, None at its end. A record cut off or that does not
+// deserialize is an error, the index would otherwise silently miss its term.
+#[cfg(not(feature = "debug_unicode"))]
+pub fn read_record<T: DeserializeOwned>(reader: &mut impl BufRead) -> io::Result<Option<T>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let truncated = |e: io::Error| io::Error::new(io::ErrorKind::InvalidData, format!("Truncated postings record: {}", e));
+
+    // Read the length of the serialized tuple
+    let mut length_buffer = [0u8; 8];
+    reader.read_exact(&mut length_buffer).map_err(truncated)?;
+    let mut buffer = vec![0u8; u64::from_le_bytes(length_buffer) as usize];
+    reader.read_exact(&mut buffer).map_err(truncated)?;
+
+    bincode::deserialize(&buffer)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt postings record: {}", e)))
+}
+
 struct ReverseOrdered {
-    value: (String, HashMap<u32, u32>),  // Changed usize to u32 here
+    value: TermPostings,
     idx: usize,
 }
 
@@ -77,15 +81,12 @@ impl PartialOrd for ReverseOrdered {
 
 pub fn merge_sorted_files(output_file_path: &str, input_files: Vec<PathBuf>) -> std::io::Result<()> {
     let mut merging_iters: Vec<MergingIterator> = input_files.into_iter()
-        .map(File::open)
-        .filter_map(|f| f.ok())
-        .map(MergingIterator::new)
-        .filter_map(Result::ok)
-        .collect();
+        .map(|path| File::open(path).and_then(MergingIterator::new))
+        .collect::<io::Result<_>>()?;
 
     let mut heap = BinaryHeap::new();
     for (idx, iter) in merging_iters.iter_mut().enumerate() {
-        if let Some(val) = iter.next() {
+        if let Some(val) = iter.next()? {
             heap.push(ReverseOrdered {
                 value: val,
                 idx,
@@ -97,7 +98,7 @@ pub fn merge_sorted_files(output_file_path: &str, input_files: Vec<PathBuf>) ->
     let mut writer = BufWriter::new(output_file);
 
     let mut current_term: Option<String> = None;
-    let mut current_buffer: HashMap<u32, u32> = HashMap::new();
+    let mut current_buffer: HashMap<u32, Vec<u32>> = HashMap::new();
 
     while let Some(ReverseOrdered { value, idx }) = heap.pop() {
         let (term, postings) = value;
@@ -105,18 +106,17 @@ pub fn merge_sorted_files(output_file_path: &str, input_files: Vec<PathBuf>) ->
         if let Some(ref current_t) = current_term {
             if &term != current_t { // Term changed
                 // Write current buffer to disk and reset
-                write_posting(&mut writer, (current_t.clone(), current_buffer.clone()))?;
-                current_buffer.clear();
+                write_posting(&mut writer, (current_t.clone(), std::mem::take(&mut current_buffer)))?;
             }
         }
 
         current_term = Some(term.clone());
 
-        for (doc_id, freq) in postings {
-            *current_buffer.entry(doc_id).or_insert(0) += freq;
+        for (doc_id, positions) in postings {
+            current_buffer.entry(doc_id).or_default().extend(positions);
         }
 
-        if let Some(val) = merging_iters[idx].next() {
+        if let Some(val) = merging_iters[idx].next()? {
             heap.push(ReverseOrdered {
                 value: val,
                 idx,
@@ -133,10 +133,13 @@ pub fn merge_sorted_files(output_file_path: &str, input_files: Vec<PathBuf>) ->
 }
 
 // Helper function to write postings to file
-fn write_posting(writer: &mut BufWriter<File>, posting: (String, HashMap<u32, u32>)) -> std::io::Result<()> {
+fn write_posting(writer: &mut BufWriter<File>, posting: (String, HashMap<u32, Vec<u32>>)) -> std::io::Result<()> {
     // Sort by doc_ID (although HashMap doesn't guarantee order, it's helpful to do it explicitly)
-    let mut sorted_posting: Vec<(u32, u32)> = posting.1.into_iter().collect();
-    sorted_posting.sort_by_key(|&(doc_id, _)| doc_id);
+    let mut sorted_posting: Vec<(u32, Vec<u32>)> = posting.1.into_iter().collect();
+    sorted_posting.sort_by_key(|(doc_id, _)| *doc_id);
+    for (_, positions) in &mut sorted_posting {
+        positions.sort_unstable();
+    }
 
     #[cfg(feature = "debug_unicode")]
     {
@@ -0,0 +1,63 @@
+// Parts of a document: the URL line, the title line, the headings of an HTML page and the body. Their terms are
+// indexed in this order, the plain terms cover all of them and the ones of the fields but the body are indexed a
+// second time under a field term of their own, for the queries restricted to them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Url = 0,
+    Title = 1,
+    Heading = 2,
+    Body = 3,
+}
+
+pub const FIELDS: usize = 4;
+
+// Between the field name and the term in a field term, no analyzed term contains it
+const FIELD_SEPARATOR: char = '\u{1F}';
+
+impl Field {
+    // In the order of their terms in the document
+    pub const ALL: [Field; FIELDS] = [Field::Url, Field::Title, Field::Heading, Field::Body];
+
+    // Name of the field in the query syntax, e.g. `title:foo`
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Url => "url",
+            Field::Title => "title",
+            Field::Heading => "heading",
+            Field::Body => "body",
+        }
+    }
+
+    // The fields with postings of their own, the body ones are the plain terms minus the other fields
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "url" => Some(Field::Url),
+            "title" => Some(Field::Title),
+            "heading" => Some(Field::Heading),
+            _ => None,
+        }
+    }
+
+    // The lexicon term of the occurrences of the term in the field
+    pub fn term(self, term: &str) -> String {
+        format!("{}{}{}", self.name(), FIELD_SEPARATOR, term)
+    }
+
+    // Field and term of a field term, None for a plain term
+    pub fn split_term(term: &str) -> Option<(Field, &str)> {
+        let (name, term) = term.split_once(FIELD_SEPARATOR)?;
+        Some((Field::from_name(name)?, term))
+    }
+
+    // Field of the position in the plain postings of a document with the field lengths
+    pub fn of_position(position: u32, lengths: &[u32; FIELDS]) -> Self {
+        let mut end = 0;
+        for field in Field::ALL {
+            end += lengths[field as usize];
+            if position < end {
+                return field;
+            }
+        }
+        Field::Body
+    }
+}
@@ -0,0 +1,310 @@
+// Elements whose content is not text shown to the reader
+const HIDDEN_ELEMENTS: [&str; 5] = ["script", "style", "noscript", "template", "svg"];
+
+// Elements that start a new line of text. Inline ones such as <b> may split a word, they add no space.
+const BLOCK_ELEMENTS: [&str; 32] = [
+    "address", "article", "aside", "blockquote", "br", "dd", "div", "dl", "dt", "fieldset", "figcaption", "figure",
+    "footer", "form", "h1", "h2", "h3", "h4", "h5", "h6", "header", "hr", "li", "main", "nav", "ol", "p", "pre",
+    "section", "table", "td", "tr",
+];
+
+// Text of an HTML page without its markup
+#[derive(Debug, Default)]
+pub struct HtmlText {
+    // Content of the <title> element
+    pub title: String,
+    // Content of the h1 to h6 elements, one per line
+    pub headings: String,
+    // The text outside the headings
+    pub body: String,
+    // All the text as shown, the headings where they are
+    pub text: String,
+}
+
+// Whether the text has markup to strip: a tag, a comment or a character reference. Not only pages do, the
+// bodies of TREC and WARC documents and of JSONL records often are HTML fragments.
+pub fn has_markup(text: &str) -> bool {
+    let tag = text.match_indices('<').any(|(index, _)| {
+        let rest = &text[index..];
+        // A '<' without its '>' is more likely a comparison than a tag
+        rest.starts_with("<!--") || parse_tag(rest).is_some_and(|tag| rest[..tag.length].ends_with('>'))
+    });
+    tag || text.match_indices('&').any(|(index, _)| entity_at(&text[index..]).is_some())
+}
+
+// Strip the markup of the page: comments and tags are dropped, and so is the content of scripts, styles and
+// the other hidden elements; entities are decoded. The title is the one of the <title> element, taken apart
+// like the headings.
+pub fn extract_html(html: &str) -> HtmlText {
+    let mut extracted = HtmlText::default();
+    let mut rest = html;
+    let mut heading_depth: u32 = 0;
+
+    while let Some(tag_start) = rest.find('<') {
+        let text = decode_entities(&rest[..tag_start]);
+        extracted.text.push_str(&text);
+        if heading_depth > 0 {
+            extracted.headings.push_str(&text);
+        } else {
+            extracted.body.push_str(&text);
+        }
+        rest = &rest[tag_start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = comment.find("-->").map_or("", |end| &comment[end + 3..]);
+            continue;
+        }
+        let Some(tag) = parse_tag(rest) else {
+            // A lone '<' is text
+            extracted.text.push('<');
+            if heading_depth > 0 {
+                extracted.headings.push('<');
+            } else {
+                extracted.body.push('<');
+            }
+            rest = &rest[1..];
+            continue;
+        };
+        rest = &rest[tag.length..];
+        let name = tag.name.as_str();
+
+        if name == "title" && !tag.closing {
+            let (content, after) = element_content(rest, name);
+            if extracted.title.is_empty() {
+                extracted.title = single_line(&decode_entities(content));
+            }
+            rest = after;
+        } else if HIDDEN_ELEMENTS.contains(&name) && !tag.closing && !tag.self_closing {
+            rest = element_content(rest, name).1;
+        } else if BLOCK_ELEMENTS.contains(&name) {
+            if is_heading(name) {
+                heading_depth = if tag.closing { heading_depth.saturating_sub(1) } else { heading_depth + 1 };
+                extracted.headings.push('\n');
+            }
+            extracted.text.push('\n');
+            extracted.body.push('\n');
+        }
+    }
+    let text = decode_entities(rest);
+    extracted.text.push_str(&text);
+    extracted.body.push_str(&text);
+
+    extracted.headings = normalize_lines(&extracted.headings);
+    extracted.body = normalize_lines(&extracted.body);
+    extracted.text = normalize_lines(&extracted.text);
+    extracted
+}
+
+struct Tag {
+    // Lowercase element name
+    name: String,
+    closing: bool,
+    self_closing: bool,
+    // Length of the tag in the page, up to its closing '>'
+    length: usize,
+}
+
+// The tag at the start of the text, None if the '<' does not open one. Declarations such as <!DOCTYPE> and
+// processing instructions are tags without a name.
+fn parse_tag(text: &str) -> Option<Tag> {
+    let inner = &text[1..];
+    let closing = inner.starts_with('/');
+    let name_start = if closing { &inner[1..] } else { inner };
+    let first = name_start.chars().next()?;
+    if !(first.is_ascii_alphabetic() || (!closing && (first == '!' || first == '?'))) {
+        return None;
+    }
+    let name: String = name_start.chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    // The tag ends at the first '>' outside of a quoted attribute value
+    let mut quote = None;
+    for (index, c) in text.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(open), c) if c == open => quote = None,
+            (Some(_), _) => {},
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => {
+                let self_closing = text[..index].ends_with('/');
+                return Some(Tag { name, closing, self_closing, length: index + 1 });
+            },
+            _ => {},
+        }
+    }
+    // Unterminated, the rest of the page is inside it
+    Some(Tag { name, closing, self_closing: false, length: text.len() })
+}
+
+// Content of the element up to its closing tag, and the text after that tag
+fn element_content<'a>(text: &'a str, name: &str) -> (&'a str, &'a str) {
+    let closing = format!("</{}", name);
+    let end = text.match_indices("</")
+        .map(|(index, _)| index)
+        .find(|&index| text.get(index..index + closing.len()).is_some_and(|tag| tag.eq_ignore_ascii_case(&closing)));
+    match end {
+        Some(end) => {
+            let after = &text[end..];
+            let after = after.find('>').map_or("", |close| &after[close + 1..]);
+            (&text[..end], after)
+        },
+        None => (text, ""),
+    }
+}
+
+fn is_heading(name: &str) -> bool {
+    matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+}
+
+// Replace the character references with their characters, unknown ones are kept as they are
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        match entity_at(rest) {
+            Some((c, length)) => {
+                decoded.push(c);
+                rest = &rest[length..];
+            },
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            },
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+// Character of the reference at the start of the text and the length of the reference, None if the '&' does not
+// start a known one
+fn entity_at(text: &str) -> Option<(char, usize)> {
+    let end = text.char_indices().take(12).find(|&(_, c)| c == ';').map(|(index, _)| index)?;
+    entity(&text[1..end]).map(|c| (c, end + 1))
+}
+
+// Character of a reference such as "amp", "#233" or "#xE9"
+fn entity(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        // The non-breaking space separates words like any space
+        "nbsp" => ' ',
+        "copy" => '©',
+        "reg" => '®',
+        "trade" => '™',
+        "hellip" => '…',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "laquo" => '«',
+        "raquo" => '»',
+        "middot" => '·',
+        "bull" => '•',
+        "deg" => '°',
+        "euro" => '€',
+        "pound" => '£',
+        "sect" => '§',
+        "agrave" => 'à',
+        "aacute" => 'á',
+        "acirc" => 'â',
+        "auml" => 'ä',
+        "ccedil" => 'ç',
+        "egrave" => 'è',
+        "eacute" => 'é',
+        "ecirc" => 'ê',
+        "euml" => 'ë',
+        "iacute" => 'í',
+        "iuml" => 'ï',
+        "ntilde" => 'ñ',
+        "oacute" => 'ó',
+        "ocirc" => 'ô',
+        "ouml" => 'ö',
+        "uacute" => 'ú',
+        "ugrave" => 'ù',
+        "uuml" => 'ü',
+        "szlig" => 'ß',
+        "Auml" => 'Ä',
+        "Eacute" => 'É',
+        "Ouml" => 'Ö',
+        "Uuml" => 'Ü',
+        _ => return None,
+    })
+}
+
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// The non-empty lines of the text, with their spaces collapsed
+fn normalize_lines(text: &str) -> String {
+    text.lines()
+        .map(single_line)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_and_entities_are_markup() {
+        assert!(has_markup("<div>Hello</div> world"));
+        assert!(has_markup("Hello <br/> world"));
+        assert!(has_markup("AT&amp;T"));
+        assert!(has_markup("Caf&#233; and cr&#xE8;me"));
+        assert!(has_markup("before <!-- note --> after"));
+        assert!(!has_markup("plain text with no markup"));
+        assert!(!has_markup("a < b and c > d, x<3, fish & chips, &unknown;"));
+        assert!(!has_markup("if a<b then stop"));
+    }
+
+    #[test]
+    fn fragment_tags_are_stripped_and_entities_decoded() {
+        let extracted = extract_html("<div>Hello&nbsp;world</div> AT&amp;T <b>bo</b>ld caf&eacute; &#8364;5 &bogus;");
+        assert_eq!(extracted.text, "Hello world\nAT&T bold café €5 &bogus;");
+        assert_eq!(extracted.body, extracted.text);
+        assert!(extracted.title.is_empty());
+        assert!(extracted.headings.is_empty());
+    }
+
+    #[test]
+    fn script_style_and_comment_content_is_dropped() {
+        let extracted = extract_html(concat!(
+            "<p>before</p><script type=\"text/javascript\">var x = '</p>'; if (a < b) {}</script>",
+            "<STYLE>p { color: red }</STYLE><!-- hidden note -->after<noscript>enable scripts</noscript>",
+        ));
+        assert_eq!(extracted.text, "before\nafter");
+    }
+
+    #[test]
+    fn pages_give_their_title_and_headings() {
+        let extracted = extract_html(concat!(
+            "<!DOCTYPE html><html><head><title>The &quot;Title&quot;</title></head>",
+            "<body><h1>Main <i>heading</i></h1><p>First paragraph.</p><h2 class='x>y'>Second</h2>Text</body></html>",
+        ));
+        assert_eq!(extracted.title, "The \"Title\"");
+        assert_eq!(extracted.headings, "Main heading\nSecond");
+        assert_eq!(extracted.body, "First paragraph.\nText");
+        assert_eq!(extracted.text, "Main heading\nFirst paragraph.\nSecond\nText");
+    }
+}
@@ -1,13 +1,25 @@
 use std::collections::HashMap;
+use crate::analyzer::Analyzer;
 use crate::disk_io;
+use crate::fields::Field;
+use crate::sources::SourceDocument;
 use bimap::BiMap;
+use serde::{Serialize, Deserialize};
 
-pub struct Indexer {
-    // Temporary postings: (token_ID, {document_ID: frequency})
-    postings: HashMap<u32, HashMap<u32, u32>>,
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocMetadata {
+    // Identifier of the document in the collection, e.g. from its <DOCNO> tag
+    pub docno: String,
+    pub url: String,
+    // Number of terms of the document
+    pub length: u32,
+    // Number of terms of every field, indexed by Field
+    pub field_lengths: [u32; crate::fields::FIELDS],
+}
 
-    // Metadata about the documents: (docID, (URL, number_of_terms))
-    doc_metadata: HashMap<u32, (String, u32)>,
+pub struct Indexer {
+    // Temporary postings: (token_ID, {document_ID: [positions]}), the frequency is the number of positions
+    postings: HashMap<u32, HashMap<u32, Vec<u32>>>,
 
     // Bidirectional mapping from terms to IDs
     term_id_map: BiMap<String, u32>,
@@ -18,61 +30,80 @@ impl Indexer {
     pub fn new() -> Self {
         Self {
             postings: HashMap::new(),
-            doc_metadata: HashMap::new(),
             term_id_map: BiMap::new(),
             current_term_id: 0,
         }
     }
 
-    pub fn process_document(&mut self, document: &str) {
-        // Parsing the document to get docID, URL, and tokens
-        let (doc_id, url, tokens) = crate::parser::parse_document(document);
+    // Collect the postings of the document and return its metadata, written to the doc table by the caller
+    pub fn process_document(&mut self, doc_id: u32, document: &SourceDocument, analyzer: &Analyzer) -> DocMetadata {
+        // Parsing the document to get DOCNO, URL, and the terms of every field
+        let document = crate::parser::parse_document(document, analyzer);
+        let field_lengths = Field::ALL.map(|field| document.field_terms[field as usize].len() as u32);
+        let metadata = DocMetadata {
+            docno: document.docno,
+            url: document.url,
+            length: field_lengths.iter().sum(),
+            field_lengths,
+        };
 
-        // Update doc_metadata
-        self.doc_metadata.insert(doc_id.clone() as u32, (url, tokens.len() as u32));
-
-        let mut token_freq = HashMap::new();
-        for token in &tokens {
-            *token_freq.entry(token).or_insert(0) += 1;
+        // Collect the positions of every token within the document, the terms of the fields but the body also
+        // within their field under their field term
+        let mut token_positions: HashMap<String, Vec<u32>> = HashMap::new();
+        let tokens = Field::ALL.iter().flat_map(|&field| &document.field_terms[field as usize]);
+        for (position, token) in tokens.enumerate() {
+            token_positions.entry(token.clone()).or_default().push(position as u32);
+        }
+        for field in [Field::Url, Field::Title, Field::Heading] {
+            for (position, token) in document.field_terms[field as usize].iter().enumerate() {
+                token_positions.entry(field.term(token)).or_default().push(position as u32);
+            }
         }
 
-        for (token, freq) in token_freq {
-            let term_id = match self.term_id_map.get_by_left(&token.to_string()) {
+        for (token, positions) in token_positions {
+            let term_id = match self.term_id_map.get_by_left(&token) {
                 Some(id) => *id,
                 None => {
                     let id = self.current_term_id;
-                    self.term_id_map.insert(token.to_string(), id);
+                    self.term_id_map.insert(token, id);
                     self.current_term_id += 1;
                     id
                 }
             };
 
-            let doc_freq = self.postings.entry(term_id)
-                .or_insert_with(HashMap::new)
-                .entry(doc_id.clone() as u32)
-                .or_insert(0);
-            *doc_freq += freq;
+            self.postings.entry(term_id)
+                .or_default()
+                .entry(doc_id)
+                .or_default()
+                .extend(positions);
         }
+
+        metadata
     }
 
     // We dump postings multiple times to disk and clear them in memory everytime
-    pub fn dump_postings_to_disk(&mut self) {
+    pub fn dump_postings_to_disk(&mut self, postings_dir: &str, batch_number: usize) {
         // Send the whole postings HashMap for dumping to disk
-        disk_io::write_posting_to_disk(&self.postings, &self.term_id_map);
+        disk_io::write_posting_to_disk(&self.postings, &self.term_id_map, postings_dir, batch_number);
 
         // Clear the in-memory postings
         self.postings.clear();
     }
 
+    // Fold the terms of another indexer, whose postings were already dumped, into this one
+    pub fn merge(&mut self, other: Indexer) {
+        for (term, _) in other.term_id_map {
+            if !self.term_id_map.contains_left(&term) {
+                self.term_id_map.insert(term, self.current_term_id);
+                self.current_term_id += 1;
+            }
+        }
+    }
+
     // We dump lexicon only once to disk
-    pub fn dump_lexicon_to_disk(&self) {
+    pub fn dump_lexicon_to_disk(&self, lexicon_path: &str) {
         // Convert BiMap to a standard HashMap for disk storage
-        disk_io::write_lexicon_to_disk(&self.term_id_map);
-
-    }
+        disk_io::write_lexicon_to_disk(&self.term_id_map, lexicon_path);
 
-    // We dump doc metadata only once to disk
-    pub fn dump_doc_metadata_to_disk(&self) {
-        disk_io::write_doc_metadata_to_disk(&self.doc_metadata);
     }
 }
@@ -5,82 +5,209 @@ mod disk_io;
 mod external_sorter;
 mod bin_indexer;
 mod term_query_processor;
+mod query_parser;
+mod scoring;
+mod posting_cursor;
+mod segment;
+mod tombstones;
+mod compaction;
+mod config;
+mod cli;
+mod batch;
+mod evaluation;
+mod doc_store;
+mod doc_table;
+mod snippets;
+mod analyzer;
+mod fields;
+mod html;
+mod sources;
+mod verify;
 
 use std::fs;
 use std::path::Path;
-use disk_io::{process_gzip_file, merge_sorted_postings};
+use clap::Parser;
+use disk_io::{process_collection, merge_sorted_postings};
 use bin_indexer::build_bin_index;
-use crate::term_query_processor::TermQueryProcessor;
-use actix_files;
+use crate::analyzer::Language;
+use crate::batch::{retrieve, run_queries};
+use crate::cli::{Cli, Command};
+use crate::config::{IndexConfig, IndexPaths};
+use crate::evaluation::{evaluate, load_qrels, load_run, print_comparison, print_evaluation};
+use crate::compaction::compact_index;
+use crate::doc_table::DocTable;
+use crate::scoring::ScoringFunction;
+use crate::sources::DocumentSource;
+use crate::term_query_processor::{QueryMode, TermQueryProcessor, DEFAULT_PAGE_SIZE};
+use crate::tombstones::Tombstones;
+use crate::verify::verify_index;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use serde::Deserialize;
 
 
 // Function to clean up the postings_data folder
-fn cleanup_postings_data_folder() -> std::io::Result<()> {
-    let dir = Path::new("postings_data");
+fn cleanup_postings_data_folder(postings_dir: &str) -> std::io::Result<()> {
+    let dir = Path::new(postings_dir);
     if dir.exists() {
         fs::remove_dir_all(dir)?;
     }
     Ok(())
 }
 
-fn build_index() {
-    // Assignment 2: Build the Inverted Index
+fn build_index(source: Box<dyn DocumentSource>, paths: &IndexPaths, config: &IndexConfig) -> std::io::Result<()> {
+    fs::create_dir_all(paths.dir())?;
 
-    if let Err(e) = cleanup_postings_data_folder() {
-        eprintln!("Error cleaning up postings_data folder: {}", e);
+    // A rebuild replaces the segments added to the previous index
+    for (_, segment) in paths.added_segments()? {
+        fs::remove_dir_all(segment.dir())?;
     }
 
-    let file_path = "data/msmarco-docs.trec.gz";
-    if let Err(e) = process_gzip_file(file_path) {
-        eprintln!("Error processing file: {}", e);
+    build_segment(source, paths, config, 0)
+}
+
+// Index the documents of the input into a new segment next to the existing ones, with the
+// parameters of the existing index. The new documents are numbered after all the indexed ones.
+fn add_segment(source: Box<dyn DocumentSource>, paths: &IndexPaths, config: &IndexConfig) -> std::io::Result<()> {
+    let mut next_number = 1;
+    for (number, segment) in paths.added_segments()? {
+        if segment.is_complete() {
+            next_number = number + 1;
+        } else {
+            // Left over by an interrupted add
+            fs::remove_dir_all(segment.dir())?;
+        }
     }
 
-    // After processing the file, apply the external merge sort on the batches
-    if let Err(e) = merge_sorted_postings() {
-        eprintln!("Error merging sorted postings: {}", e);
+    let mut first_doc_id = 0;
+    for segment in paths.segments()? {
+        first_doc_id = first_doc_id.max(DocTable::open(&segment)?.end_doc_id());
     }
 
-    // Build binary inverted index and store in 'data/' directory
-    if let Err(e) = build_bin_index("data/merged_postings.data", "data/bin_index.data",
-                                    "data/bin_lexicon.data", "data/bin_directory.data") {
-        eprintln!("Error building binary inverted index: {}", e);
+    let segment = paths.segment(next_number);
+    fs::create_dir_all(segment.dir())?;
+    build_segment(source, &segment, config, first_doc_id)
+}
+
+// Record tombstones for the documents, given by docid or DOCNO. They disappear from the results
+// of the next loaded query processor.
+fn delete_documents(paths: &IndexPaths, doc_ids: &[u32], docnos: &[String]) -> std::io::Result<()> {
+    let doc_tables = paths.segments()?.iter().map(DocTable::open).collect::<std::io::Result<Vec<_>>>()?;
+
+    let mut tombstones = Tombstones::load(&paths.deleted_docs())?;
+    let mut to_delete = doc_ids.to_vec();
+    for docno in docnos {
+        // Every copy of the document, in case it was indexed more than once
+        let matching: Vec<u32> = doc_tables.iter().flat_map(|doc_table| doc_table.doc_ids(docno)).collect();
+        if matching.is_empty() {
+            eprintln!("No document with DOCNO {} in the index", docno);
+        }
+        to_delete.extend(matching);
+    }
+
+    for doc_id in to_delete {
+        if !doc_tables.iter().any(|doc_table| doc_table.contains(doc_id)) {
+            eprintln!("Document {} is not in the index", doc_id);
+        } else if !tombstones.insert(doc_id) {
+            eprintln!("Document {} was already deleted", doc_id);
+        }
+    }
+    tombstones.save(&paths.deleted_docs())?;
+
+    println!("{} deleted documents, run compact to remove them from the index files", tombstones.len());
+    Ok(())
+}
+
+fn build_segment(source: Box<dyn DocumentSource>, paths: &IndexPaths, config: &IndexConfig,
+                 first_doc_id: u32) -> std::io::Result<()> {
+    cleanup_postings_data_folder(&paths.postings_dir())?;
+
+    process_collection(source, paths, config, first_doc_id)?;
+
+    // After processing the file, apply the external merge sort on the batches
+    merge_sorted_postings(&paths.postings_dir(), &paths.merged_postings())?;
+
+    // Build binary inverted index and store it in the segment directory
+    build_bin_index(&paths.merged_postings(), &paths.index(), &paths.lexicon(), &paths.directory(),
+                    &DocTable::open(paths)?, &paths.statistics(), config)?;
+
+    // The query processor needs the block size and BM25 parameters the index was built with.
+    // Saved last, it also marks the segment as complete.
+    config.save(Path::new(&paths.config()))
+}
+
+// Parameters saved by the build
+fn load_index_config(paths: &IndexPaths) -> std::io::Result<IndexConfig> {
+    if !paths.is_complete() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No index in the directory, run build first"));
     }
+    IndexConfig::load(Path::new(&paths.config()))
 }
 
 struct AppState {
-    query_processor: Arc<Mutex<TermQueryProcessor>>,
+    query_processor: Arc<TermQueryProcessor>,
 }
 
 #[derive(Deserialize)]
 struct QueryParams {
+    // `q` on the /search endpoint
+    #[serde(alias = "q")]
     query: String,
+    // Scoring function, the configured one if absent
+    scorer: Option<ScoringFunction>,
+    // Number of results to skip, for the next pages
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_size")]
+    k: usize,
+    // ISO 639-1 code of the language of the query, detected if absent
+    lang: Option<String>,
+}
+
+fn default_page_size() -> usize {
+    DEFAULT_PAGE_SIZE
+}
+
+// A language without stop words and stemmer is detected like a missing one
+fn query_language(lang: &Option<String>) -> Option<Language> {
+    lang.as_deref().and_then(Language::from_code)
+}
+
+#[derive(Deserialize)]
+struct LookupParams {
+    docno: String,
 }
 
-async fn handle_conjunctive_query(
+// The query endpoints differ only by the mode of their queries, given as resource data
+async fn handle_query(
     data: web::Data<AppState>,
+    mode: web::Data<QueryMode>,
     query: web::Query<QueryParams>,
 ) -> impl Responder {
-    let mut processor = data.query_processor.lock().unwrap();
+    let processor = Arc::clone(&data.query_processor);
+    let mode = *mode.into_inner();
+    let query = query.into_inner();
 
-    match processor.conjunctive_query(&query.query) {
-        Ok(json) => HttpResponse::Ok()
+    // Query evaluation is CPU bound, it runs on the blocking thread pool so that the workers keep serving requests
+    let response = web::block(move || {
+        processor.query(mode, &query.query, query.scorer, query_language(&query.lang), query.offset, query.k)
+    }).await;
+    match response {
+        Ok(Ok(json)) => HttpResponse::Ok()
             .insert_header(("X-Response-Type", "json"))
             .content_type("application/json")
             .body(json),
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        _ => HttpResponse::InternalServerError().finish(),
     }
 }
 
-async fn handle_disjunctive_query(
+async fn handle_lookup(
     data: web::Data<AppState>,
-    query: web::Query<QueryParams>,
+    query: web::Query<LookupParams>,
 ) -> impl Responder {
-    let mut processor = data.query_processor.lock().unwrap();
+    let processor = &data.query_processor;
 
-    match processor.disjunctive_query(&query.query) {
+    match processor.lookup_docno(&query.docno) {
         Ok(json) => HttpResponse::Ok()
             .insert_header(("X-Response-Type", "json"))
             .content_type("application/json")
@@ -89,27 +216,164 @@ async fn handle_disjunctive_query(
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
+async fn handle_document(
+    data: web::Data<AppState>,
+    doc_id: web::Path<u32>,
+) -> impl Responder {
+    let processor = &data.query_processor;
+
+    match processor.get_document(doc_id.into_inner()) {
+        Ok(Some(document)) => HttpResponse::Ok()
+            .insert_header(("X-Response-Type", "json"))
+            .json(document),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+fn main() -> std::io::Result<()> {
+    match Cli::parse().command {
+        Command::Build { input, source, out_dir, config, overrides } => {
+            let mut index_config = match config {
+                Some(config_path) => IndexConfig::load(&config_path)?,
+                None => IndexConfig::default(),
+            };
+            overrides.apply(&mut index_config);
+
+            // Opened first, a missing input leaves the previous index as it was
+            let source = source.open(&input)?;
+            // process_collection installs its own file logger
+            build_index(source, &IndexPaths::new(&out_dir), &index_config)
+        },
+        Command::Add { input, source, index_dir, overrides } => {
+            let paths = IndexPaths::new(&index_dir);
+            if !paths.is_complete() {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "No index to add to, run build first"));
+            }
+
+            let mut config = load_index_config(&paths)?;
+            overrides.apply(&mut config);
+            add_segment(source.open(&input)?, &paths, &config)
+        },
+        Command::Delete { index_dir, doc_ids, docnos } => delete_documents(&IndexPaths::new(&index_dir), &doc_ids, &docnos),
+        Command::Compact { index_dir } => {
+            let paths = IndexPaths::new(&index_dir);
+            let config = load_index_config(&paths)?;
+            compact_index(&paths, &config)
+        },
+        Command::Verify { index_dir } => {
+            let paths = IndexPaths::new(&index_dir);
+            let verification = verify_index(&paths, &load_index_config(&paths)?)?;
+            println!("Verified {} segments, {} terms, {} postings: {} inconsistencies",
+                     verification.segments, verification.terms, verification.postings, verification.problems);
+            if verification.problems > 0 {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The index is inconsistent"));
+            }
+            Ok(())
+        },
+        Command::Serve { index_dir, bind, static_dir, workers, overrides } => {
+            env_logger::init();
+            let paths = IndexPaths::new(&index_dir);
+            let mut config = load_index_config(&paths)?;
+            overrides.apply(&mut config);
+
+            actix_web::rt::System::new().block_on(serve(&paths, &config, &bind, &static_dir, workers))
+        },
+        Command::Lookup { index_dir, docno } => {
+            let paths = IndexPaths::new(&index_dir);
+            let processor = TermQueryProcessor::new(&paths, &load_index_config(&paths)?);
+            println!("{}", processor.lookup_docno(&docno)?);
+            Ok(())
+        },
+        Command::Document { index_dir, doc_id } => {
+            let paths = IndexPaths::new(&index_dir);
+            let processor = TermQueryProcessor::new(&paths, &load_index_config(&paths)?);
+            match processor.get_document(doc_id)? {
+                Some(document) => println!("{}", serde_json::to_string(&document)?),
+                None => eprintln!("Document {} is not in the index, deleted or its text is not stored", doc_id),
+            }
+            Ok(())
+        },
+        Command::Query { index_dir, mode, offset, k, lang, overrides, query } => {
+            env_logger::init();
+            let paths = IndexPaths::new(&index_dir);
+            let mut config = load_index_config(&paths)?;
+            overrides.apply(&mut config);
+
+            let processor = TermQueryProcessor::new(&paths, &config);
+            println!("{}", processor.query(mode, &query, None, query_language(&lang), offset, k)?);
+            Ok(())
+        },
+        Command::Run { index_dir, queries, output, mode, depth, tag, threads, overrides } => {
+            env_logger::init();
+            let paths = IndexPaths::new(&index_dir);
+            let mut config = load_index_config(&paths)?;
+            overrides.apply(&mut config);
+
+            let processor = TermQueryProcessor::new(&paths, &config);
+            run_queries(&processor, &queries, &output, mode, depth, &tag, threads)
+        },
+        Command::Evaluate { qrels, run, queries, index_dir, mode, depth, threads, overrides, baseline, cutoffs, per_query } => {
+            env_logger::init();
+            let qrels = load_qrels(&qrels)?;
+            let run = match (run, queries) {
+                (Some(run), _) => load_run(&run)?,
+                (None, Some(queries)) => {
+                    let paths = IndexPaths::new(&index_dir);
+                    let mut config = load_index_config(&paths)?;
+                    overrides.apply(&mut config);
+
+                    let processor = TermQueryProcessor::new(&paths, &config);
+                    retrieve(&processor, &queries, mode, depth, threads)?.into_iter()
+                        .map(|(qid, ranking)| (qid, ranking.into_iter().map(|(docno, _)| docno).collect()))
+                        .collect()
+                },
+                (None, None) => unreachable!("clap requires --run or --queries"),
+            };
+
+            let evaluation = evaluate(&qrels, &run, &cutoffs);
+            match baseline {
+                Some(baseline) => print_comparison(&evaluate(&qrels, &load_run(&baseline)?, &cutoffs), &evaluation),
+                None => print_evaluation(&evaluation, per_query),
+            }
+            Ok(())
+        },
+    }
+}
+
+fn query_resource(path: &str, mode: QueryMode) -> actix_web::Resource {
+    web::resource(path)
+        .app_data(web::Data::new(mode))
+        .route(web::get().to(handle_query))
+}
 
-    // Create your TermQueryProcessor instance here
-    let tqp = Arc::new(Mutex::new(TermQueryProcessor::new("data/bin_index.data", "data/bin_lexicon.data", "data/bin_directory.data",
-                                                          "data/doc_metadata.data")));
+async fn serve(paths: &IndexPaths, config: &IndexConfig, bind: &str, static_dir: &Path,
+               workers: Option<usize>) -> std::io::Result<()> {
+    // Shared by all workers without locking, queries run in parallel
+    let tqp = Arc::new(TermQueryProcessor::new(paths, config));
+    let static_dir = static_dir.to_path_buf();
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         let app_data = web::Data::new(AppState {
             query_processor: tqp.clone(),
         });
 
         App::new()
             .app_data(app_data)
-            .service(web::resource("/conjunctive_query").route(web::get().to(handle_conjunctive_query)))
-            .service(web::resource("/disjunctive_query").route(web::get().to(handle_disjunctive_query)))
+            .service(query_resource("/conjunctive_query", QueryMode::And))
+            .service(query_resource("/disjunctive_query", QueryMode::Or))
+            .service(query_resource("/phrase_query", QueryMode::Phrase))
+            .service(query_resource("/search", QueryMode::Search))
+            .service(web::resource("/lookup").route(web::get().to(handle_lookup)))
+            .service(web::resource("/doc/{id}").route(web::get().to(handle_document)))
             // Serve static files
-            .service(actix_files::Files::new("/", "./static").index_file("index.html"))
-    })
-        .bind("127.0.0.1:8080")?
+            .service(actix_files::Files::new("/", &static_dir).index_file("index.html"))
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+
+    server.bind(bind)?
         .run()
         .await
-}
\ No newline at end of file
+}
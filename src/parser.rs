@@ -1,81 +1,85 @@
-use regex::Regex;
-use unicode_normalization::UnicodeNormalization;
-use unicode_segmentation::UnicodeSegmentation;
-use stop_words::{get, LANGUAGE::English}; // Import required components from stop-words crate
-use std::collections::HashSet;
-use lazy_static::lazy_static;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::analyzer::{Analyzer, Language};
+use crate::fields::{Field, FIELDS};
+use crate::html::{extract_html, has_markup};
+use crate::sources::SourceDocument;
 
-// Cache the stop words for the English language
-lazy_static! {
-    static ref STOP_WORDS: HashSet<String> = {
-        let words = get(English);
-        words.into_iter().collect()
-    };
+pub struct ParsedDocument {
+    pub docno: String,
+    pub url: String,
+    // Terms of every field, indexed by Field; the document terms are all of them in order
+    pub field_terms: [Vec<String>; FIELDS],
 }
 
-static DOCID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// Docids are assigned by the caller from the position of the document in the collection,
+// the DOCNO is kept as the external identifier of the document
+pub fn parse_document(document: &SourceDocument, analyzer: &Analyzer) -> ParsedDocument {
+    let content = DocumentContent::new(document);
+    // The language is the one of the whole text, the fields are too short to detect theirs. One declared without
+    // stop words and stemmer is detected too.
+    let declared_language = document.lang.as_deref().and_then(Language::from_code);
+    let language = analyzer.language(&content.text, declared_language);
+    // No word spans the line breaks between the fields, their terms are the ones of the whole text
+    let field_terms = Field::ALL.map(|field| analyzer.analyze(content.field(field), language));
 
-pub fn parse_document(document: &str) -> (usize, String, Vec<String>) {
-    let doc_id = get_doc_id(); // extract_doc_id(document);
-    let url = extract_url(document);
-    let text = extract_text_content(document);
-    let tokens = parse_line(&text);
-
-    (doc_id, url, tokens)
-}
-
-// fn extract_doc_id(document: &str) -> usize { // Change return type to usize
-//     let re = Regex::new(r"(?s)<DOCNO>D(\d+)</DOCNO>").unwrap(); // Updated regex to directly match the number after 'D'
-//     re.captures(document)
-//         .and_then(|cap| cap.get(1))
-//         .and_then(|m| m.as_str().parse::<usize>().ok()) // Parse the number to usize
-//         .unwrap_or_default()
-// }
-
-fn get_doc_id() -> usize { // Change return type to usize
-    DOCID_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ParsedDocument { docno: document.docno.clone(), url: document.url.clone(), field_terms }
 }
 
-
-fn extract_url(document: &str) -> String {
-    let text_content = extract_text_content(document);
-    let first_line = text_content.lines().next().unwrap_or("");
-    first_line.trim().to_string()
+// The text of the document kept in the document store, the URL line, the title line and the body without
+// the markup of an HTML page, see split_text_content
+pub fn stored_text(document: &SourceDocument) -> String {
+    DocumentContent::new(document).text
 }
 
-fn extract_text_content(document: &str) -> String {
-    let re = Regex::new(r"(?s)<TEXT>(.*?)</TEXT>").unwrap(); // Note the (?s) flag
-    re.captures(document)
-        .and_then(|cap| cap.get(1))
-        .map(|m| m.as_str().trim().to_string())
-        .unwrap_or_default()
+// The fields of a document. The markup of an HTML body is stripped, and its title and headings are taken apart;
+// a plain text body has no headings.
+struct DocumentContent {
+    url: String,
+    title: String,
+    headings: String,
+    body: String,
+    // The URL line, the title line and the text as shown, headings included
+    text: String,
 }
 
-pub fn parse_line(line: &str) -> Vec<String> {
-    // Normalize the text to NFKC (Normalization Form KC: Compatibility Composition)
-    let normalized = line.nfkc().collect::<String>();
+impl DocumentContent {
+    fn new(document: &SourceDocument) -> Self {
+        let (url, title, body) = (&document.url, &document.title, document.body.trim());
+        if !has_markup(body) {
+            return Self {
+                url: url.clone(),
+                title: title.clone(),
+                headings: String::new(),
+                body: body.to_string(),
+                text: format!("{}\n{}\n{}", url, title, body),
+            };
+        }
 
-    // Replace "." and "_" and "-" and quotation marks with whitespace to treat them as delimiters
-    let replaced = normalized.replace(".", " ")
-        .replace("_", " ")
-        .replace("-", " ")
-        .replace("\"", " ")
-        .replace("\'", " ");
+        let html = extract_html(body);
+        // A title given with the document wins over the one of the page
+        let title = if title.is_empty() { html.title } else { title.clone() };
+        Self {
+            text: format!("{}\n{}\n{}", url, title, html.text),
+            url: url.clone(),
+            title,
+            headings: html.headings,
+            body: html.body,
+        }
+    }
 
-    // Tokenize into words using unicode segmentation
-    let words = replaced.unicode_words();
+    fn field(&self, field: Field) -> &str {
+        match field {
+            Field::Url => &self.url,
+            Field::Title => &self.title,
+            Field::Heading => &self.headings,
+            Field::Body => &self.body,
+        }
+    }
+}
 
-    // Convert to lowercase, filter out stop words and collect
-    words.map(|word| word.to_lowercase())
-        .filter(|word| !STOP_WORDS.contains(word))
-        .filter(|word| {
-            // Check if it's a number and if it's less than 10
-            if let Ok(num) = word.parse::<f64>() {
-                num >= 10.0
-            } else {
-                true // Keep non-numeric words
-            }
-        })
-        .collect()
+// URL, title and body of the TEXT content of a TREC document or of a stored text. Like in the MS MARCO documents, the title is the line after the URL
+// when more lines follow it; a document with a single line of text has no title.
+pub fn split_text_content(text: &str) -> (&str, &str, &str) {
+    let (url, rest) = text.split_once('\n').unwrap_or((text, ""));
+    let (title, body) = rest.split_once('\n').unwrap_or(("", rest));
+    (url.trim(), title.trim(), body.trim())
 }
@@ -0,0 +1,251 @@
+extern crate stream_vbyte;
+extern crate byteorder;
+
+use byteorder::{ByteOrder, LittleEndian};
+use stream_vbyte::decode::decode;
+use stream_vbyte::scalar::Scalar;
+use crate::bin_indexer::TermMetadata;
+use crate::fields::{Field, FIELDS};
+
+// Docid of a cursor that has moved past the last posting
+pub const END_OF_POSTINGS: u32 = u32::MAX;
+
+// Iterates over the postings of one term, decoding a single block at a time.
+// The cursor starts on the first posting; block_maxima allow next_geq to jump over whole blocks
+// without reading them, and positions are only decompressed when asked for.
+// Blocks are read straight from the memory-mapped index, so any number of cursors, on any number
+// of threads, can share one index without seeking a common file handle.
+pub struct PostingCursor<'a> {
+    index: &'a [u8],
+    metadata: TermMetadata,
+    // Number of postings in every block but the last
+    block_size: usize,
+    block_index: usize,
+    // Decoded (docid, frequency) pairs of the current block
+    block: Vec<(u32, u32)>,
+    // Decoded positions of the current block, filled on demand
+    block_positions: Option<Vec<Vec<u32>>>,
+    position: usize,
+    // Field of the postings of a field term, None for a plain term
+    field: Option<Field>,
+    // Cursors of the field terms of a plain term, for the frequencies of the term in every field
+    field_cursors: Vec<PostingCursor<'a>>,
+}
+
+impl<'a> PostingCursor<'a> {
+    pub fn new(index: &'a [u8], metadata: TermMetadata, block_size: usize) -> std::io::Result<Self> {
+        let mut cursor = PostingCursor {
+            index,
+            metadata,
+            block_size,
+            block_index: 0,
+            block: Vec::new(),
+            block_positions: None,
+            position: 0,
+            field: None,
+            field_cursors: Vec::new(),
+        };
+        cursor.load_block(0)?;
+        Ok(cursor)
+    }
+
+    pub fn metadata(&self) -> &TermMetadata {
+        &self.metadata
+    }
+
+    pub fn field(&self) -> Option<Field> {
+        self.field
+    }
+
+    pub fn set_field(&mut self, field: Option<Field>) {
+        self.field = field;
+    }
+
+    pub fn set_field_cursors(&mut self, field_cursors: Vec<PostingCursor<'a>>) {
+        self.field_cursors = field_cursors;
+    }
+
+    // Frequencies of the term in every field of the current document, indexed by Field: all of them in the field
+    // of a field term, the ones of the field cursors for a plain term and the others in the body. The field
+    // cursors only move forward, the documents have to be asked for in increasing docid order.
+    pub fn field_freqs(&mut self) -> std::io::Result<[u32; FIELDS]> {
+        let doc_id = self.doc();
+        let mut freqs = [0; FIELDS];
+        match self.field {
+            Some(field) => freqs[field as usize] = self.freq(),
+            None => {
+                let mut body_freq = self.freq();
+                for cursor in &mut self.field_cursors {
+                    cursor.next_geq(doc_id)?;
+                    if let (Some(field), true) = (cursor.field(), cursor.doc() == doc_id) {
+                        freqs[field as usize] = cursor.freq();
+                        body_freq = body_freq.saturating_sub(cursor.freq());
+                    }
+                }
+                freqs[Field::Body as usize] = body_freq;
+            },
+        }
+        Ok(freqs)
+    }
+
+    // Replace the stored score bounds of the term and of all its blocks with a single one
+    pub fn set_max_score(&mut self, max_score: f32) {
+        self.metadata.max_score = max_score;
+        self.metadata.block_max_scores.fill(max_score);
+    }
+
+    // Current docid, END_OF_POSTINGS once the cursor is exhausted
+    pub fn doc(&self) -> u32 {
+        self.block.get(self.position).map(|&(doc_id, _)| doc_id).unwrap_or(END_OF_POSTINGS)
+    }
+
+    pub fn freq(&self) -> u32 {
+        self.block.get(self.position).map(|&(_, freq)| freq).unwrap_or(0)
+    }
+
+    // Positions of the term in the current document, empty if the index has no positions
+    pub fn positions(&mut self) -> std::io::Result<&[u32]> {
+        if self.doc() == END_OF_POSTINGS || self.metadata.compressed_positions_per_block.is_empty() {
+            return Ok(&[]);
+        }
+
+        if self.block_positions.is_none() {
+            // The positions of a block follow its docids and frequencies
+            let offset = self.metadata.block_offsets[self.block_index]
+                + self.metadata.compressed_docids_per_block[self.block_index]
+                + 4 * self.block.len() as u64;
+            let compressed_positions = self.read_bytes(offset, self.metadata.compressed_positions_per_block[self.block_index])?;
+
+            let frequencies: Vec<u32> = self.block.iter().map(|&(_, freq)| freq).collect();
+            self.block_positions = Some(decode_positions(compressed_positions, &frequencies));
+        }
+
+        Ok(&self.block_positions.as_ref().unwrap()[self.position])
+    }
+
+    pub fn next(&mut self) -> std::io::Result<()> {
+        if self.doc() == END_OF_POSTINGS {
+            return Ok(());
+        }
+
+        self.position += 1;
+        if self.position >= self.block.len() {
+            self.load_block(self.block_index + 1)?;
+        }
+        Ok(())
+    }
+
+    // Advance to the first posting with a docid greater than or equal to the target
+    pub fn next_geq(&mut self, doc_id: u32) -> std::io::Result<()> {
+        if self.doc() >= doc_id {
+            return Ok(());
+        }
+
+        // Skip the blocks whose maximum docid is smaller than the target without decoding them
+        let block_index = self.block_containing(doc_id);
+        if block_index != self.block_index {
+            self.load_block(block_index)?;
+        }
+        self.position += self.block[self.position..].partition_point(|&(d, _)| d < doc_id);
+        Ok(())
+    }
+
+    // Maximum score and last docid of the block that would contain the target, without decoding it
+    pub fn block_bound(&self, doc_id: u32) -> (f32, u32) {
+        let block_index = self.block_containing(doc_id);
+        match self.metadata.block_max_scores.get(block_index) {
+            Some(&max_score) => (max_score, self.metadata.block_maxima[block_index]),
+            None => (0.0, END_OF_POSTINGS),
+        }
+    }
+
+    // First block, from the current one on, whose maximum docid is not smaller than the target
+    fn block_containing(&self, doc_id: u32) -> usize {
+        let start = self.block_index.min(self.metadata.block_maxima.len());
+        start + self.metadata.block_maxima[start..].partition_point(|&max_doc_id| max_doc_id < doc_id)
+    }
+
+    fn load_block(&mut self, block_index: usize) -> std::io::Result<()> {
+        self.block_index = block_index;
+        self.block_positions = None;
+        self.position = 0;
+        self.block.clear();
+
+        if block_index >= self.metadata.block_offsets.len() {
+            return Ok(());
+        }
+
+        // Determine the number of docids in this block
+        let block_size = if block_index == self.metadata.block_offsets.len() - 1 {
+            self.metadata.num_posting_in_last_block as usize
+        } else {
+            self.block_size
+        };
+
+        // Read and decompress docids for this block
+        let block_offset = self.metadata.block_offsets[block_index];
+        let compressed_docids_size = self.metadata.compressed_docids_per_block[block_index];
+        let compressed_docids = self.read_bytes(block_offset, compressed_docids_size)?;
+
+        let mut docids = vec![0u32; block_size];
+        decode::<Scalar>(compressed_docids, block_size, &mut docids);
+
+        // The first docid is a delta from the last docid of the previous block
+        if block_index > 0 {
+            docids[0] += self.metadata.block_maxima[block_index - 1];
+        }
+        let decoded_docids = delta_decoding(&docids);
+
+        // Read frequencies for this block
+        let frequencies = self.read_bytes(block_offset + compressed_docids_size, 4 * block_size as u64)?;
+
+        self.block.extend(decoded_docids.into_iter().zip(frequencies.chunks_exact(4).map(LittleEndian::read_u32)));
+        Ok(())
+    }
+
+    fn read_bytes(&self, offset: u64, length: u64) -> std::io::Result<&'a [u8]> {
+        let index: &'a [u8] = self.index;
+        index.get(offset as usize..(offset + length) as usize)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Posting block out of bounds"))
+    }
+}
+
+fn delta_decoding(encoded_docids: &[u32]) -> Vec<u32> {
+    let mut decoded_docids = Vec::with_capacity(encoded_docids.len());
+    let mut last_doc_id = 0;
+
+    for &encoded_docid in encoded_docids {
+        let docid = if last_doc_id == 0 { // The first docid is not a delta
+            encoded_docid
+        } else {
+            last_doc_id + encoded_docid
+        };
+        decoded_docids.push(docid);
+        last_doc_id = docid;
+    }
+
+    decoded_docids
+}
+
+// Split a block's position stream by the frequencies and undo the gap encoding within each document
+fn decode_positions(compressed_positions: &[u8], frequencies: &[u32]) -> Vec<Vec<u32>> {
+    let total_positions: usize = frequencies.iter().map(|&freq| freq as usize).sum();
+    let mut gaps = vec![0u32; total_positions];
+    decode::<Scalar>(compressed_positions, total_positions, &mut gaps);
+
+    let mut positions = Vec::with_capacity(frequencies.len());
+    let mut start = 0;
+    for &freq in frequencies {
+        let mut last_position = 0;
+        let doc_positions: Vec<u32> = gaps[start..start + freq as usize].iter()
+            .map(|&gap| {
+                last_position += gap;
+                last_position
+            })
+            .collect();
+        positions.push(doc_positions);
+        start += freq as usize;
+    }
+
+    positions
+}
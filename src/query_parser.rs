@@ -0,0 +1,349 @@
+use crate::analyzer::{Analyzer, Language};
+use crate::fields::Field;
+
+// Abstract syntax tree of a boolean query
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryNode {
+    Term(String),
+    Phrase(Vec<String>),
+    And(Vec<QueryNode>),
+    // Adjacent clauses and explicit ORs; `Required` and `Not` children turn it into a must/should/must-not group
+    Or(Vec<QueryNode>),
+    Not(Box<QueryNode>),
+    Required(Box<QueryNode>),
+}
+
+impl QueryNode {
+    // The same query over the occurrences in the field, through the field terms
+    fn in_field(self, field: Field) -> QueryNode {
+        match self {
+            QueryNode::Term(term) => QueryNode::Term(field.term(&term)),
+            QueryNode::Phrase(terms) => QueryNode::Phrase(terms.iter().map(|term| field.term(term)).collect()),
+            QueryNode::And(children) => QueryNode::And(children.into_iter().map(|child| child.in_field(field)).collect()),
+            QueryNode::Or(children) => QueryNode::Or(children.into_iter().map(|child| child.in_field(field)).collect()),
+            QueryNode::Not(child) => QueryNode::Not(Box::new(child.in_field(field))),
+            QueryNode::Required(child) => QueryNode::Required(Box::new(child.in_field(field))),
+        }
+    }
+
+    // All the terms of the query, without their field
+    pub fn terms(&self) -> Vec<String> {
+        match self {
+            QueryNode::Term(term) => vec![plain_term(term)],
+            QueryNode::Phrase(terms) => terms.iter().map(|term| plain_term(term)).collect(),
+            QueryNode::And(children) | QueryNode::Or(children) => children.iter().flat_map(QueryNode::terms).collect(),
+            QueryNode::Not(child) | QueryNode::Required(child) => child.terms(),
+        }
+    }
+}
+
+fn plain_term(term: &str) -> String {
+    Field::split_term(term).map_or(term, |(_, term)| term).to_string()
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    // A `title:` or `url:` prefix, restricting the clause that follows it to the field
+    Field(Field),
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Plus,
+    Minus,
+}
+
+// Parse a query such as `(apple OR banana) AND "fruit salad" -recipe +fresh title:fresh` into an AST.
+// The parser is lenient: unbalanced parentheses and dangling operators are ignored.
+// Returns None if nothing searchable is left, e.g. when the query consists only of stop words.
+// All its words are analyzed in the language of the whole query, see Analyzer::language.
+pub fn parse_query(query: &str, analyzer: &Analyzer, language: Option<Language>) -> Option<QueryNode> {
+    let tokens = lex(query);
+    let mut parser = Parser { tokens, pos: 0, analyzer, language };
+    let mut clauses = Vec::new();
+
+    while parser.pos < parser.tokens.len() {
+        match parser.parse_or() {
+            Some(node) => clauses.push(node),
+            // Skip a token that cannot start a clause, such as a stray ')'
+            None => parser.pos += 1,
+        }
+    }
+
+    match clauses.len() {
+        0 => None,
+        1 => clauses.pop(),
+        _ => Some(QueryNode::Or(clauses)),
+    }
+}
+
+fn lex(query: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            },
+            '(' => {
+                chars.next();
+                tokens.push(Token::LeftParen);
+            },
+            ')' => {
+                chars.next();
+                tokens.push(Token::RightParen);
+            },
+            '"' => {
+                chars.next();
+                let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                tokens.push(Token::Quoted(phrase));
+            },
+            '+' | '-' => {
+                chars.next();
+                // A sign only counts as a prefix when it is directly attached to the next clause
+                match chars.peek() {
+                    Some(&next) if !next.is_whitespace() => {
+                        tokens.push(if c == '+' { Token::Plus } else { Token::Minus });
+                    },
+                    _ => continue,
+                }
+            },
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                // The field prefix may be followed by a word, or by a phrase or group lexed on their own
+                let field = word.split_once(':')
+                    .and_then(|(name, rest)| Some((Field::from_name(&name.to_ascii_lowercase())?, rest.to_string())));
+                if let Some((field, rest)) = field {
+                    tokens.push(Token::Field(field));
+                    if !rest.is_empty() {
+                        tokens.push(Token::Word(rest));
+                    }
+                    continue;
+                }
+                tokens.push(match word.as_str() {
+                    "AND" | "&&" => Token::And,
+                    "OR" | "||" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                });
+            },
+        }
+    }
+
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Turns the words and phrases into terms like the documents were
+    analyzer: &'a Analyzer,
+    language: Option<Language>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn starts_clause(&self) -> bool {
+        matches!(self.peek(), Some(Token::Word(_) | Token::Quoted(_) | Token::Field(_) | Token::LeftParen
+            | Token::Not | Token::Plus | Token::Minus))
+    }
+
+    // or_expr := and_expr (("OR" | adjacency) and_expr)*
+    fn parse_or(&mut self) -> Option<QueryNode> {
+        let mut children = Vec::new();
+
+        loop {
+            if let Some(node) = self.parse_and() {
+                children.push(node);
+            }
+            if self.peek() == Some(&Token::Or) {
+                self.pos += 1;
+            } else if !self.starts_clause() {
+                break;
+            }
+        }
+
+        match children.len() {
+            0 => None,
+            1 => children.pop(),
+            _ => Some(QueryNode::Or(children)),
+        }
+    }
+
+    // and_expr := unary ("AND" unary)*
+    fn parse_and(&mut self) -> Option<QueryNode> {
+        let mut children: Vec<QueryNode> = self.parse_unary().into_iter().collect();
+
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            if let Some(node) = self.parse_unary() {
+                children.push(node);
+            }
+        }
+
+        match children.len() {
+            0 => None,
+            1 => children.pop(),
+            _ => Some(QueryNode::And(children)),
+        }
+    }
+
+    // unary := ("NOT" | "-" | "+") unary | primary
+    fn parse_unary(&mut self) -> Option<QueryNode> {
+        match self.peek() {
+            Some(Token::Not) | Some(Token::Minus) => {
+                self.pos += 1;
+                self.parse_unary().map(|node| QueryNode::Not(Box::new(node)))
+            },
+            Some(Token::Plus) => {
+                self.pos += 1;
+                self.parse_unary().map(|node| QueryNode::Required(Box::new(node)))
+            },
+            _ => self.parse_primary(),
+        }
+    }
+
+    // primary := field? (word | "quoted phrase" | "(" or_expr ")")
+    fn parse_primary(&mut self) -> Option<QueryNode> {
+        let token = self.peek()?.clone();
+
+        match token {
+            Token::Field(field) => {
+                self.pos += 1;
+                self.parse_primary().map(|node| node.in_field(field))
+            },
+            Token::Word(word) => {
+                self.pos += 1;
+                terms_to_node(self.analyzer.analyze(&word, self.language))
+            },
+            Token::Quoted(phrase) => {
+                self.pos += 1;
+                terms_to_node(self.analyzer.analyze(&phrase, self.language))
+            },
+            Token::LeftParen => {
+                self.pos += 1;
+                let node = self.parse_or();
+                if self.peek() == Some(&Token::RightParen) {
+                    self.pos += 1;
+                }
+                node
+            },
+            _ => None,
+        }
+    }
+}
+
+// A word may be split into several tokens by the analyzer (e.g. "e-mail"), which then have to be adjacent
+fn terms_to_node(mut terms: Vec<String>) -> Option<QueryNode> {
+    match terms.len() {
+        0 => None,
+        1 => terms.pop().map(QueryNode::Term),
+        _ => Some(QueryNode::Phrase(terms)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::AnalyzerConfig;
+
+    fn parse(query: &str) -> Option<QueryNode> {
+        parse_query(query, &Analyzer::new(&AnalyzerConfig::default()), Some(Language::English))
+    }
+
+    // The node of a word analyzed like the parser does
+    fn term(word: &str) -> QueryNode {
+        let mut terms = Analyzer::new(&AnalyzerConfig::default()).analyze(word, Some(Language::English));
+        assert_eq!(terms.len(), 1, "{} is not a single term", word);
+        QueryNode::Term(terms.pop().unwrap())
+    }
+
+    fn field_term(field: Field, word: &str) -> QueryNode {
+        match term(word) {
+            QueryNode::Term(term) => QueryNode::Term(field.term(&term)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn phrase(words: &[&str]) -> QueryNode {
+        QueryNode::Phrase(words.iter().map(|word| match term(word) {
+            QueryNode::Term(term) => term,
+            _ => unreachable!(),
+        }).collect())
+    }
+
+    #[test]
+    fn and_binds_tighter_than_adjacency_and_or() {
+        assert_eq!(parse("apple banana AND cherry"),
+                   Some(QueryNode::Or(vec![term("apple"), QueryNode::And(vec![term("banana"), term("cherry")])])));
+        assert_eq!(parse("apple OR banana && cherry || grape"),
+                   Some(QueryNode::Or(vec![
+                       term("apple"),
+                       QueryNode::And(vec![term("banana"), term("cherry")]),
+                       term("grape"),
+                   ])));
+    }
+
+    #[test]
+    fn groups_phrases_and_prefixes() {
+        assert_eq!(parse("(apple OR banana) AND \"fruit salad\" -recipe +fresh NOT frozen"),
+                   Some(QueryNode::Or(vec![
+                       QueryNode::And(vec![QueryNode::Or(vec![term("apple"), term("banana")]), phrase(&["fruit", "salad"])]),
+                       QueryNode::Not(Box::new(term("recipe"))),
+                       QueryNode::Required(Box::new(term("fresh"))),
+                       QueryNode::Not(Box::new(term("frozen"))),
+                   ])));
+    }
+
+    #[test]
+    fn field_prefixes_restrict_the_clause_that_follows() {
+        let node = parse("title:fresh URL:\"fruit salad\" heading:(apple OR banana)").unwrap();
+        assert_eq!(node, QueryNode::Or(vec![
+            field_term(Field::Title, "fresh"),
+            QueryNode::Phrase(vec![Field::Url.term("fruit"), Field::Url.term("salad")]),
+            QueryNode::Or(vec![field_term(Field::Heading, "apple"), field_term(Field::Heading, "banana")]),
+        ]));
+
+        // Highlighted wherever they occur
+        let terms = parse("title:fresh url:apple").unwrap().terms();
+        assert_eq!(terms, [term("fresh"), term("apple")].map(|node| match node {
+            QueryNode::Term(term) => term,
+            _ => unreachable!(),
+        }));
+    }
+
+    #[test]
+    fn words_split_by_the_analyzer_are_a_phrase() {
+        assert_eq!(parse("blue-green"), Some(phrase(&["blue", "green"])));
+    }
+
+    #[test]
+    fn malformed_queries_are_parsed_leniently() {
+        assert_eq!(parse(") apple AND"), Some(term("apple")));
+        assert_eq!(parse("apple (banana"), Some(QueryNode::Or(vec![term("apple"), term("banana")])));
+        assert_eq!(parse("- apple +"), Some(term("apple")));
+        assert_eq!(parse("\"fruit salad"), Some(phrase(&["fruit", "salad"])));
+        assert_eq!(parse("AND OR NOT ()"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn stop_words_alone_leave_nothing_to_search() {
+        assert_eq!(parse("the of and"), None);
+        assert_eq!(parse("the apple"), Some(term("apple")));
+    }
+}
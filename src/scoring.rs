@@ -0,0 +1,432 @@
+use std::fs;
+use std::io;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::config::IndexConfig;
+use crate::doc_table::DocTable;
+use crate::fields::{Field, FIELDS};
+
+// Shared by the query processor and the index builder, so that the score upper bounds stored
+// in the lexicon are exactly the maximum of the scores computed at query time.
+// Lucene computes the same scores without the constant k1 + 1 factor, and with lossy document lengths.
+pub fn bm25(tf: u32, df: u32, doc_len: u32, total_docs: u32, avg_doc_len: f32, k1: f32, b: f32) -> f32 {
+    let idf = idf(df, total_docs);
+    let term_freq_component = (tf as f32) * (k1 + 1.0);
+    let denominator = tf as f32 + k1 * (1.0 - b + b * (doc_len as f32 / avg_doc_len));
+
+    idf * (term_freq_component / denominator)
+}
+
+// Lucene's BM25 idf, which stays positive even for terms in more than half of the documents
+pub fn idf(df: u32, total_docs: u32) -> f32 {
+    (1.0 + (total_docs as f32 - df as f32 + 0.5) / (df as f32 + 0.5)).ln()
+}
+
+// Turn a BM25 or BM25F upper bound computed with the statistics of one segment into an upper bound of the scores
+// computed with the statistics of the whole collection. For any document, the idf factor of its score
+// changes by idf / segment_idf, and the term frequency factor grows at most by the length ratio, see
+// CollectionStatistics::length_ratio.
+pub fn rescale_bound(bound: f32, segment_idf: f32, idf: f32, length_ratio: f32, k1: f32) -> f32 {
+    if idf <= 0.0 {
+        // No score can be positive
+        return 0.0;
+    }
+    if segment_idf <= 0.0 {
+        // The bound says nothing about the term frequency factor, which never exceeds k1 + 1
+        return idf * (k1 + 1.0);
+    }
+    with_rounding_margin(bound * (idf / segment_idf) * length_ratio)
+}
+
+// The computed upper bound with some room for the rounding errors, the bound must not fall below the actual
+// maximum. Some scorers give negative scores, the margin is relative to the magnitude of the bound.
+pub fn with_rounding_margin(bound: f32) -> f32 {
+    bound + bound.abs() * 1e-5
+}
+
+// Scoring function of a query, selectable per request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum ScoringFunction {
+    #[default]
+    #[serde(rename = "bm25")]
+    #[value(name = "bm25")]
+    Bm25,
+    #[serde(rename = "bm25plus")]
+    #[value(name = "bm25plus")]
+    Bm25Plus,
+    #[serde(rename = "bm25l")]
+    #[value(name = "bm25l")]
+    Bm25L,
+    #[serde(rename = "tfidf")]
+    #[value(name = "tfidf")]
+    TfIdf,
+    // Query likelihood with Dirichlet prior smoothing
+    #[serde(rename = "dirichlet")]
+    #[value(name = "dirichlet")]
+    Dirichlet,
+    // Query likelihood with Jelinek-Mercer smoothing
+    #[serde(rename = "jelinek-mercer")]
+    #[value(name = "jelinek-mercer")]
+    JelinekMercer,
+    // Divergence from randomness, Poisson model with Laplace after-effect and normalisation 2
+    #[serde(rename = "pl2")]
+    #[value(name = "pl2")]
+    Pl2,
+    // BM25 over the weighted URL, title and body frequencies of the term
+    #[serde(rename = "bm25f")]
+    #[value(name = "bm25f")]
+    Bm25F,
+}
+
+impl ScoringFunction {
+    // The scorer with the parameters of the configuration
+    pub fn scorer(self, config: &IndexConfig) -> Box<dyn Scorer> {
+        match self {
+            ScoringFunction::Bm25 => Box::new(Bm25 { k1: config.bm25_k1, b: config.bm25_b }),
+            ScoringFunction::Bm25Plus => Box::new(Bm25Plus { k1: config.bm25_k1, b: config.bm25_b, delta: config.bm25_plus_delta }),
+            ScoringFunction::Bm25L => Box::new(Bm25L { k1: config.bm25_k1, b: config.bm25_b, delta: config.bm25l_delta }),
+            ScoringFunction::TfIdf => Box::new(TfIdf),
+            ScoringFunction::Dirichlet => Box::new(Dirichlet { mu: config.dirichlet_mu }),
+            ScoringFunction::JelinekMercer => Box::new(JelinekMercer { lambda: config.jelinek_mercer_lambda }),
+            ScoringFunction::Pl2 => Box::new(Pl2 { c: config.pl2_c }),
+            ScoringFunction::Bm25F => {
+                let parameters = BoundParameters::new(self, config);
+                Box::new(Bm25F { k1: parameters.k1, b: parameters.b, weights: parameters.weights, field_b: parameters.field_b })
+            },
+        }
+    }
+}
+
+// Scoring function and parameters the score bounds of a lexicon were computed with. The bounds only hold for
+// the queries scored the same way, the others prune disjunctive queries with the looser max_score of their scorer.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct BoundParameters {
+    pub scorer: ScoringFunction,
+    pub k1: f32,
+    pub b: f32,
+    // BM25F weights and length normalisation of every field, indexed by Field, 0 for BM25
+    pub weights: [f32; crate::fields::FIELDS],
+    pub field_b: [f32; crate::fields::FIELDS],
+}
+
+impl BoundParameters {
+    // Parameters the scoring function scores with in the configuration
+    pub fn new(scorer: ScoringFunction, config: &IndexConfig) -> Self {
+        let (weights, field_b) = match scorer {
+            ScoringFunction::Bm25F => (
+                [config.bm25f_url_weight, config.bm25f_title_weight, config.bm25f_heading_weight, config.bm25f_body_weight],
+                [config.bm25f_url_b, config.bm25f_title_b, config.bm25f_heading_b, config.bm25_b],
+            ),
+            _ => ([0.0; FIELDS], [0.0; FIELDS]),
+        };
+        Self { scorer, k1: config.bm25_k1, b: config.bm25_b, weights, field_b }
+    }
+
+    // The bounds an index is built with: BM25F ones if it ranks with BM25F, BM25 ones otherwise
+    pub fn of_index(config: &IndexConfig) -> Self {
+        match config.scorer {
+            ScoringFunction::Bm25F => Self::new(ScoringFunction::Bm25F, config),
+            _ => Self::new(ScoringFunction::Bm25, config),
+        }
+    }
+}
+
+// Statistics of a query term over the whole collection
+pub struct TermStatistics {
+    // Number of documents containing the term
+    pub doc_freq: u32,
+    // Number of occurrences of the term, the sum of its total_term_freq over all segments
+    pub collection_freq: u64,
+}
+
+// Statistics of the documents of a segment, or of the whole collection, the scorers depend on.
+// The index build saves the record of every segment next to its lexicon.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct CollectionStatistics {
+    // Number of documents, the deleted ones included until a compaction drops them
+    pub total_docs: u32,
+    // Number of tokens of all documents
+    pub total_terms: u64,
+    pub avg_doc_len: f32,
+    pub max_doc_len: u32,
+    // Number of distinct terms of the lexicon
+    pub unique_terms: u32,
+    // Number of tokens of every field of all documents. The derive declares a FIELDS constant of its own
+    pub field_terms: [u64; crate::fields::FIELDS],
+    // How the score bounds of the lexicon were computed
+    pub bounds: BoundParameters,
+}
+
+impl CollectionStatistics {
+    // Statistics of the documents, unique_terms is only known once the lexicon is built
+    pub fn new(doc_table: &DocTable, config: &IndexConfig) -> Self {
+        let total_docs = doc_table.doc_count();
+        let total_terms = doc_table.lengths().map(|length| length as u64).sum();
+        let field_terms = doc_table.field_totals();
+        Self {
+            total_docs,
+            total_terms,
+            avg_doc_len: average(total_terms, total_docs),
+            max_doc_len: doc_table.lengths().max().unwrap_or(0),
+            unique_terms: 0,
+            field_terms,
+            bounds: BoundParameters::of_index(config),
+        }
+    }
+
+    // Statistics of the segments together. Terms shared by several segments cannot be told apart without
+    // their lexicons, so unique_terms is the size of the largest one.
+    pub fn combine(segments: &[CollectionStatistics]) -> Self {
+        let total_docs = segments.iter().map(|segment| segment.total_docs).sum();
+        let total_terms = segments.iter().map(|segment| segment.total_terms).sum();
+        Self {
+            total_docs,
+            total_terms,
+            avg_doc_len: average(total_terms, total_docs),
+            max_doc_len: segments.iter().map(|segment| segment.max_doc_len).max().unwrap_or(0),
+            unique_terms: segments.iter().map(|segment| segment.unique_terms).max().unwrap_or(0),
+            field_terms: Field::ALL.map(|field| segments.iter().map(|segment| segment.field_terms[field as usize]).sum()),
+            ..segments.first().copied().unwrap_or_default()
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let contents = toml::to_string(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+
+    // Largest factor by which the term frequency factor of a bound computed with the statistics of the segment grows
+    // with these ones. It only depends on the document or field lengths through their averages, and a larger average
+    // can only shrink the length normalisation of a document by the ratio of the averages.
+    pub fn length_ratio(&self, segment: &CollectionStatistics) -> f32 {
+        let ratio = |average: f32, segment_average: f32| if segment_average > 0.0 { average / segment_average } else { 1.0 };
+        match self.bounds.scorer {
+            // The fields missing from every document of the segment have no frequency there
+            ScoringFunction::Bm25F => Field::ALL.iter()
+                .map(|&field| ratio(self.avg_field_len(field), segment.avg_field_len(field)))
+                .fold(1.0, f32::max),
+            _ => ratio(self.avg_doc_len, segment.avg_doc_len).max(1.0),
+        }
+    }
+
+    // Average number of tokens of the field in a document
+    fn avg_field_len(&self, field: Field) -> f32 {
+        average(self.field_terms[field as usize], self.total_docs)
+    }
+
+    // Probability of drawing the term from the collection, as used by the language models
+    fn term_probability(&self, term: &TermStatistics) -> f32 {
+        term.collection_freq.max(1) as f32 / self.total_terms.max(1) as f32
+    }
+}
+
+// The contribution of one query term to the score of a document. The score of a document is the sum
+// of the contributions of the query terms it contains.
+pub trait Scorer: Send + Sync {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32;
+
+    // Upper bound of the score of the term in any document of the collection, for dynamic pruning
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32;
+
+    // Whether the score depends on the fields the term occurs in, then computed by field_score
+    fn weighs_fields(&self) -> bool {
+        false
+    }
+
+    // Score from the frequencies of the term in the URL, the title and the body of the document, and their lengths
+    fn field_score(&self, tf: &[u32; FIELDS], lengths: &[u32; FIELDS], term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        self.score(tf.iter().sum(), lengths.iter().sum(), term, collection)
+    }
+}
+
+// Robertson and Walker's BM25, the scoring function the index bounds are computed with unless it ranks with BM25F
+pub struct Bm25 {
+    pub k1: f32,
+    pub b: f32,
+}
+
+impl Scorer for Bm25 {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        bm25(tf, term.doc_freq, doc_len, collection.total_docs, collection.avg_doc_len, self.k1, self.b)
+    }
+
+    // The term frequency factor tends to k1 + 1 for large frequencies
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        idf(term.doc_freq, collection.total_docs) * (self.k1 + 1.0)
+    }
+}
+
+// BM25 with a lower bound delta on the term frequency factor, so that long documents containing the term
+// always score above documents without it (Lv and Zhai, 2011)
+pub struct Bm25Plus {
+    pub k1: f32,
+    pub b: f32,
+    pub delta: f32,
+}
+
+impl Scorer for Bm25Plus {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let length_norm = 1.0 - self.b + self.b * (doc_len as f32 / collection.avg_doc_len);
+        let term_freq_component = (tf as f32) * (self.k1 + 1.0) / (tf as f32 + self.k1 * length_norm);
+        smoothed_idf(term.doc_freq, collection.total_docs) * (term_freq_component + self.delta)
+    }
+
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        smoothed_idf(term.doc_freq, collection.total_docs) * (self.k1 + 1.0 + self.delta)
+    }
+}
+
+// BM25 with the length normalised term frequency shifted by delta (Lv and Zhai, 2011)
+pub struct Bm25L {
+    pub k1: f32,
+    pub b: f32,
+    pub delta: f32,
+}
+
+impl Scorer for Bm25L {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let length_norm = 1.0 - self.b + self.b * (doc_len as f32 / collection.avg_doc_len);
+        let shifted_tf = tf as f32 / length_norm + self.delta;
+        let idf = ((collection.total_docs as f32 + 1.0) / (term.doc_freq as f32 + 0.5)).ln();
+        idf * (self.k1 + 1.0) * shifted_tf / (self.k1 + shifted_tf)
+    }
+
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let idf = ((collection.total_docs as f32 + 1.0) / (term.doc_freq as f32 + 0.5)).ln();
+        idf * (self.k1 + 1.0)
+    }
+}
+
+// Logarithmic term frequency times inverse document frequency, without length normalisation
+pub struct TfIdf;
+
+impl Scorer for TfIdf {
+    fn score(&self, tf: u32, _doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        (1.0 + (tf.max(1) as f32).ln()) * smoothed_idf(term.doc_freq, collection.total_docs)
+    }
+
+    // A term cannot occur more often than the length of the longest document
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        self.score(collection.max_doc_len, collection.max_doc_len, term, collection)
+    }
+}
+
+// Query likelihood of the document language model with a Dirichlet prior, ranked as in Lucene: the part
+// of the log-likelihood that depends on the document, floored at 0
+pub struct Dirichlet {
+    pub mu: f32,
+}
+
+impl Scorer for Dirichlet {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let probability = collection.term_probability(term);
+        let score = (1.0 + tf as f32 / (self.mu * probability)).ln() + (self.mu / (doc_len as f32 + self.mu)).ln();
+        score.max(0.0)
+    }
+
+    // With tf <= doc_len, (mu + tf / p) / (doc_len + mu) never exceeds 1 / p
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        -collection.term_probability(term).ln()
+    }
+}
+
+// Query likelihood of the document language model interpolated with the collection model
+pub struct JelinekMercer {
+    pub lambda: f32,
+}
+
+impl Scorer for JelinekMercer {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let probability = collection.term_probability(term);
+        let document_probability = tf as f32 / doc_len.max(1) as f32;
+        (1.0 + (1.0 - self.lambda) * document_probability / (self.lambda * probability)).ln()
+    }
+
+    // The document probability is at most 1
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let probability = collection.term_probability(term);
+        (1.0 + (1.0 - self.lambda) / (self.lambda * probability)).ln()
+    }
+}
+
+// PL2 from the divergence from randomness framework, as in Terrier
+pub struct Pl2 {
+    pub c: f32,
+}
+
+impl Scorer for Pl2 {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let tfn = tf as f32 * (1.0 + self.c * collection.avg_doc_len / doc_len.max(1) as f32).log2();
+        let mean = term.collection_freq as f32 / collection.total_docs.max(1) as f32;
+        let information = tfn * (tfn / mean).log2()
+            + (mean - tfn) * std::f32::consts::LOG2_E
+            + 0.5 * (2.0 * std::f32::consts::PI * tfn).log2();
+        information / (tfn + 1.0)
+    }
+
+    // The normalised frequency never exceeds c * avg_doc_len * log2(e) as tf <= doc_len, and each part
+    // of the information divided by tfn + 1 is bounded on its own
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let max_tfn = self.c * collection.avg_doc_len * std::f32::consts::LOG2_E;
+        let mean = term.collection_freq as f32 / collection.total_docs.max(1) as f32;
+        (max_tfn / mean).log2().max(0.0)
+            + mean * std::f32::consts::LOG2_E
+            + 0.5 * (2.0 * std::f32::consts::PI * max_tfn).log2().max(0.0)
+    }
+}
+
+// BM25F (Robertson, Zaragoza and Taylor, 2004): the frequencies of the term in the fields, each length normalised
+// with the parameter b of its field, are weighted and summed before the BM25 saturation.
+pub struct Bm25F {
+    pub k1: f32,
+    pub b: f32,
+    // Indexed by Field
+    pub weights: [f32; FIELDS],
+    pub field_b: [f32; FIELDS],
+}
+
+impl Scorer for Bm25F {
+    fn score(&self, tf: u32, doc_len: u32, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        bm25(tf, term.doc_freq, doc_len, collection.total_docs, collection.avg_doc_len, self.k1, self.b)
+    }
+
+    // Both term frequency factors tend to k1 + 1 for large frequencies
+    fn max_score(&self, term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        idf(term.doc_freq, collection.total_docs) * (self.k1 + 1.0)
+    }
+
+    fn weighs_fields(&self) -> bool {
+        true
+    }
+
+    fn field_score(&self, tf: &[u32; FIELDS], lengths: &[u32; FIELDS], term: &TermStatistics, collection: &CollectionStatistics) -> f32 {
+        let mut weighted_tf = 0.0;
+        for field in Field::ALL {
+            let (tf, length, b) = (tf[field as usize], lengths[field as usize], self.field_b[field as usize]);
+            // A field containing the term has a positive length, hence a positive average
+            if tf > 0 {
+                let length_norm = 1.0 - b + b * (length as f32 / collection.avg_field_len(field));
+                weighted_tf += self.weights[field as usize] * tf as f32 / length_norm;
+            }
+        }
+        idf(term.doc_freq, collection.total_docs) * weighted_tf * (self.k1 + 1.0) / (self.k1 + weighted_tf)
+    }
+}
+
+// Exact average document length, 0 for an empty collection
+fn average(total_terms: u64, total_docs: u32) -> f32 {
+    if total_docs == 0 {
+        return 0.0;
+    }
+    (total_terms as f64 / total_docs as f64) as f32
+}
+
+// Inverse document frequency that stays positive for terms in every document
+fn smoothed_idf(df: u32, total_docs: u32) -> f32 {
+    ((total_docs as f32 + 1.0) / df.max(1) as f32).ln()
+}
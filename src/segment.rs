@@ -0,0 +1,144 @@
+use std::fs::File;
+use std::ops::Range;
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
+use crate::bin_indexer::{TermMetadata, load_directory, read_term_metadata, LEXICON_HAS_POSITIONS, LEXICON_OFFSET_TABLE_POSITION};
+use crate::config::IndexPaths;
+use crate::doc_store::DocStore;
+use crate::doc_table::DocTable;
+use crate::scoring::CollectionStatistics;
+
+// One self-contained part of the index, with its own binary index, lexicon and directory.
+// Segments are built one after the other over consecutive docid ranges, so their postings never overlap.
+pub struct Segment {
+    // Every directory_stride-th lexicon term, loaded once and binary searched in memory
+    directory: Vec<(String, u64)>,
+    directory_stride: u32,
+    // Memory-mapped lexicon, terms are located through the offset table at its end
+    lexicon: Mmap,
+    total_terms: u32,
+    offset_table_position: usize,
+    index: Mmap,
+    has_positions: bool,
+    // Statistics of this segment alone, the score upper bounds stored in its lexicon were computed with them
+    statistics: CollectionStatistics,
+    // Lengths, DOCNOs and URLs of the documents
+    doc_table: DocTable,
+    // None if the segment was built without storing the documents
+    doc_store: Option<DocStore>,
+}
+
+impl Segment {
+    pub fn open(paths: &IndexPaths) -> std::io::Result<Self> {
+        let (directory_stride, directory) = load_directory(&paths.directory())?;
+
+        // SAFETY: the index files are written once by build_bin_index and never modified while being served
+        let lexicon = unsafe { Mmap::map(&File::open(paths.lexicon())?)? };
+        let index = unsafe { Mmap::map(&File::open(paths.index())?)? };
+
+        // The lexicon header is (total_terms, has_positions, offset table position)
+        let total_terms = LittleEndian::read_u32(&lexicon[0..]);
+        let has_positions = LittleEndian::read_u32(&lexicon[LEXICON_HAS_POSITIONS as usize..]) != 0;
+        let offset_table_position = LittleEndian::read_u64(&lexicon[LEXICON_OFFSET_TABLE_POSITION as usize..]) as usize;
+
+        let doc_table = DocTable::open(paths)?;
+        let statistics = CollectionStatistics::load(&paths.statistics())?;
+
+        let doc_store = DocStore::open(paths)?;
+
+        Ok(Self {
+            directory,
+            directory_stride,
+            lexicon,
+            total_terms,
+            offset_table_position,
+            index,
+            has_positions,
+            statistics,
+            doc_table,
+            doc_store,
+        })
+    }
+
+    pub fn index(&self) -> &[u8] {
+        &self.index
+    }
+
+    pub fn has_positions(&self) -> bool {
+        self.has_positions
+    }
+
+    pub fn statistics(&self) -> &CollectionStatistics {
+        &self.statistics
+    }
+
+    pub fn doc_table(&self) -> &DocTable {
+        &self.doc_table
+    }
+
+    pub fn doc_store(&self) -> Option<&DocStore> {
+        self.doc_store.as_ref()
+    }
+
+    pub fn total_terms(&self) -> u32 {
+        self.total_terms
+    }
+
+    // Term and metadata of the entry at the term index, in lexicon order
+    pub fn term_at(&self, term_index: usize) -> std::io::Result<(String, TermMetadata)> {
+        let mut entry = self.lexicon_entry(term_index)?;
+        read_term_metadata(&mut entry, self.has_positions)
+    }
+
+    // Range of lexicon term indexes that may contain the term, from the directory entry preceding it
+    pub fn query_term_directory(&self, term: &str) -> Option<Range<usize>> {
+        let entry = self.directory.partition_point(|(dir_term, _)| dir_term.as_str() <= term);
+        if entry == 0 {
+            // The term sorts before the first term of the lexicon
+            return None;
+        }
+
+        let start = (entry - 1) * self.directory_stride as usize;
+        let end = (start + self.directory_stride as usize).min(self.total_terms as usize);
+        Some(start..end)
+    }
+
+    // Metadata of the term, None if it does not occur in this segment
+    pub fn term_metadata(&self, term: &str) -> std::io::Result<Option<TermMetadata>> {
+        let Some(range) = self.query_term_directory(term) else { return Ok(None) };
+
+        // Binary search the terms of the directory range, UTF-8 byte order is the order the lexicon was sorted in
+        let mut low = range.start;
+        let mut high = range.end;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            match self.lexicon_term(middle)?.cmp(term.as_bytes()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => {
+                    let mut entry = self.lexicon_entry(middle)?;
+                    let (_, metadata) = read_term_metadata(&mut entry, self.has_positions)?;
+                    return Ok(Some(metadata));
+                },
+            }
+        }
+
+        Ok(None)
+    }
+
+    // The lexicon bytes starting at the entry of the term index
+    fn lexicon_entry(&self, term_index: usize) -> std::io::Result<&[u8]> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Lexicon offset out of bounds");
+        let table_entry = self.offset_table_position + 8 * term_index;
+        let offset = self.lexicon.get(table_entry..table_entry + 8).ok_or_else(invalid)?;
+        self.lexicon.get(LittleEndian::read_u64(offset) as usize..).ok_or_else(invalid)
+    }
+
+    // The term string of the entry at the term index
+    fn lexicon_term(&self, term_index: usize) -> std::io::Result<&[u8]> {
+        let entry = self.lexicon_entry(term_index)?;
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Lexicon entry out of bounds");
+        let term_length = LittleEndian::read_u32(entry.get(..4).ok_or_else(invalid)?) as usize;
+        entry.get(4..4 + term_length).ok_or_else(invalid)
+    }
+}
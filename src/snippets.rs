@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+use unicode_segmentation::UnicodeSegmentation;
+use crate::analyzer::{is_cjk, Analyzer, Language};
+
+// Number of words of a snippet
+const SNIPPET_WORDS: usize = 30;
+// Number of words kept before the first query term of the snippet, when the text allows
+const SNIPPET_LEADING_WORDS: usize = 5;
+
+// The passage of SNIPPET_WORDS consecutive words of the text with the most distinct query terms, then the
+// most occurrences of them, the earliest one between equals. It is returned as HTML, escaped, with the query
+// terms in <mark> tags and an ellipsis where the text goes on.
+pub fn snippet(text: &str, query_terms: &HashSet<String>, analyzer: &Analyzer, language: Option<Language>) -> String {
+    // The delimiters of the analyzer are all one byte long, replacing them keeps the offsets into the text
+    let delimited: String = text.chars()
+        .map(|c| if matches!(c, '.' | '_' | '-' | '"' | '\'') { ' ' } else { c })
+        .collect();
+
+    // (start, end, query term) of every word, a word matches when the analyzer turns it into the term.
+    // CJK words are indexed as pairs of characters, every character is a word of the snippet.
+    let mut words: Vec<(usize, usize, Option<String>)> = Vec::new();
+    for (start, word) in delimited.unicode_word_indices() {
+        if analyzer.cjk_bigrams() && word.chars().all(is_cjk) {
+            words.extend(word.char_indices().map(|(offset, c)| (start + offset, start + offset + c.len_utf8(), None)));
+        } else {
+            let term = analyzer.analyze(word, language).into_iter().find(|term| query_terms.contains(term));
+            words.push((start, start + word.len(), term));
+        }
+    }
+    // Both characters of a query pair match, whichever CJK words they come from
+    for index in 1..words.len() {
+        let (start, previous_end, _) = words[index - 1];
+        let (next_start, end, _) = words[index];
+        if !analyzer.cjk_bigrams() || previous_end != next_start || !text[start..end].chars().all(is_cjk) {
+            continue;
+        }
+        if let Some(pair) = analyzer.analyze(&text[start..end], language).pop().filter(|pair| query_terms.contains(pair)) {
+            words[index - 1].2.get_or_insert_with(|| pair.clone());
+            words[index].2 = Some(pair);
+        }
+    }
+    if words.is_empty() {
+        return String::new();
+    }
+
+    // Slide the window over the words, counting the occurrences of every term inside it
+    let window = SNIPPET_WORDS.min(words.len());
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut occurrences = 0;
+    let mut best = (0, 0, 0);
+    for (end, (_, _, term)) in words.iter().enumerate() {
+        if let Some(term) = term {
+            *counts.entry(term).or_default() += 1;
+            occurrences += 1;
+        }
+        if end >= window {
+            if let Some(term) = &words[end - window].2 {
+                let count = counts.get_mut(term.as_str()).unwrap();
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(term.as_str());
+                }
+                occurrences -= 1;
+            }
+        }
+        if end + 1 >= window && (counts.len(), occurrences) > (best.0, best.1) {
+            best = (counts.len(), occurrences, end + 1 - window);
+        }
+    }
+
+    // The earliest best window ends on a query term, start it a few words before its first one instead.
+    // Moving the window forward only drops words that are not query terms.
+    let first = match words[best.2..best.2 + window].iter().position(|(_, _, term)| term.is_some()) {
+        Some(first_term) => (best.2 + first_term.saturating_sub(SNIPPET_LEADING_WORDS)).min(words.len() - window),
+        None => best.2,
+    };
+    let last = first + window - 1;
+    let mut html = String::new();
+    if first > 0 {
+        html.push_str("… ");
+    }
+    for (index, (start, end, term)) in words[first..=last].iter().enumerate() {
+        if index > 0 {
+            // Whatever separates two words, punctuation included, with its line breaks and runs of spaces
+            // collapsed into one space
+            let separator = &text[words[first + index - 1].1..*start];
+            html.push_str(&escape_html(&collapse_whitespace(separator)));
+        }
+        let word = escape_html(&text[*start..*end]);
+        if term.is_some() {
+            html.push_str(&format!("<mark>{}</mark>", word));
+        } else {
+            html.push_str(&word);
+        }
+    }
+    if last + 1 < words.len() {
+        html.push_str(" …");
+    }
+    html
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    for c in text.chars() {
+        if !c.is_whitespace() {
+            collapsed.push(c);
+        } else if !collapsed.ends_with(' ') {
+            collapsed.push(' ');
+        }
+    }
+    collapsed
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::AnalyzerConfig;
+
+    fn snippet_of(text: &str, query: &str) -> String {
+        let analyzer = Analyzer::new(&AnalyzerConfig::default());
+        let query_terms = analyzer.analyze(query, Some(Language::English)).into_iter().collect();
+        snippet(text, &query_terms, &analyzer, Some(Language::English))
+    }
+
+    // The words w0 to w{count - 1}, with the replacements at their positions
+    fn words(count: usize, replacements: &[(usize, &str)]) -> String {
+        (0..count)
+            .map(|index| replacements.iter().find(|(at, _)| *at == index).map_or(format!("w{}", index), |(_, word)| word.to_string()))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn the_window_starts_a_few_words_before_the_first_query_term() {
+        let text = words(100, &[(60, "jaguar"), (70, "walnuts")]);
+        let expected = format!("… {} <mark>jaguar</mark> {} <mark>walnuts</mark> {} …",
+            words(60, &[]).split(' ').skip(55).collect::<Vec<_>>().join(" "),
+            (61..70).map(|index| format!("w{}", index)).collect::<Vec<_>>().join(" "),
+            (71..85).map(|index| format!("w{}", index)).collect::<Vec<_>>().join(" "));
+        assert_eq!(snippet_of(&text, "jaguar walnut"), expected);
+    }
+
+    #[test]
+    fn distinct_query_terms_win_over_repeated_ones() {
+        // Three occurrences of one term early, both terms once later on
+        let text = words(200, &[(10, "jaguar"), (12, "jaguar"), (14, "jaguar"), (120, "jaguar"), (125, "walnut")]);
+        let snippet = snippet_of(&text, "jaguar walnut");
+        assert!(snippet.starts_with("… w115 w116 w117 w118 w119 <mark>jaguar</mark>"), "{}", snippet);
+        assert!(snippet.contains("<mark>walnut</mark>"));
+        assert!(snippet.ends_with(" …"));
+    }
+
+    #[test]
+    fn short_texts_are_whole_and_the_earliest_window_wins() {
+        // The snippet goes from its first word to its last, the punctuation between them is kept
+        assert_eq!(snippet_of("The Jaguar,\n\n  a walnut tree.", "walnut jaguar"), "The <mark>Jaguar</mark>, a <mark>walnut</mark> tree");
+        assert_eq!(snippet_of("nothing matches here", "jaguar"), "nothing matches here");
+        assert_eq!(snippet_of(&words(40, &[]), "jaguar"), format!("{} …", words(30, &[])));
+        assert_eq!(snippet_of("", "jaguar"), "");
+    }
+
+    #[test]
+    fn text_is_escaped_and_query_terms_marked() {
+        assert_eq!(
+            snippet_of("<i>Jaguars</i> & \"walnut\" isn't <b>zephyr</b>", "jaguar"),
+            "i&gt;<mark>Jaguars</mark>&lt;/i&gt; &amp; &quot;walnut&quot; isn&#39;t &lt;b&gt;zephyr&lt;/b",
+        );
+    }
+
+    #[test]
+    fn both_characters_of_a_cjk_pair_are_marked() {
+        assert_eq!(snippet_of("我在北京大学", "北京"), "我在<mark>北</mark><mark>京</mark>大学");
+    }
+}
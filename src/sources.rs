@@ -0,0 +1,452 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::Value;
+use crate::parser::split_text_content;
+
+// First bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+lazy_static! {
+    static ref DOCNO: Regex = Regex::new(r"(?s)<DOCNO>\s*(.*?)\s*</DOCNO>").unwrap();
+    static ref LANG: Regex = Regex::new(r"(?s)<LANG>\s*(.*?)\s*</LANG>").unwrap();
+}
+
+// Format of a collection to index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    // <DOC> elements with a <DOCNO> and a <TEXT> whose first line is the URL and second line the title
+    #[value(name = "trec")]
+    Trec,
+    // One JSON object per line, with configurable identifier, text, title and URL fields
+    #[value(name = "jsonl")]
+    Jsonl,
+    // MS MARCO documents, docid \t url \t title \t body, or passages, pid \t passage
+    #[value(name = "msmarco")]
+    MsMarco,
+    // Every file under the directory is a document, identified by its path in it
+    #[value(name = "dir")]
+    Directory,
+    // The response records of a WARC file, identified by their WARC-TREC-ID or record id
+    #[value(name = "warc")]
+    Warc,
+}
+
+// Names of the fields of a JSONL document
+#[derive(Clone, Debug)]
+pub struct JsonlFields {
+    pub id: String,
+    pub text: String,
+    pub title: Option<String>,
+    pub url: Option<String>,
+}
+
+// A document of the collection, whatever its format. The URL and the title take a single line.
+pub struct SourceDocument {
+    // The DOCNO as written in the collection, e.g. "D1555982" for MS MARCO; empty if the document has none
+    pub docno: String,
+    pub url: String,
+    pub title: String,
+    // Plain text or an HTML page, see parser.rs
+    pub body: String,
+    // ISO 639-1 code of the declared language, such as "es", None if the document declares none
+    pub lang: Option<String>,
+}
+
+impl SourceDocument {
+    pub fn new(docno: &str, url: &str, title: &str, body: &str) -> Self {
+        Self {
+            docno: docno.to_string(),
+            url: single_line(url),
+            title: single_line(title),
+            body: body.to_string(),
+            lang: None,
+        }
+    }
+}
+
+// The documents of a collection, read one at a time
+pub trait DocumentSource {
+    // The next document, None at the end of the collection
+    fn next_document(&mut self) -> io::Result<Option<SourceDocument>>;
+}
+
+// The source reading the input in the format. The single file formats may be gzipped, whatever their extension.
+pub fn open_source(input: &Path, format: InputFormat, fields: &JsonlFields) -> io::Result<Box<dyn DocumentSource>> {
+    Ok(match format {
+        InputFormat::Trec => Box::new(TrecSource { reader: open_input(input)?, current_doc: Vec::new() }),
+        InputFormat::Jsonl => Box::new(JsonlSource { reader: open_input(input)?, fields: fields.clone(), line_number: 0 }),
+        InputFormat::MsMarco => Box::new(MsMarcoSource { reader: open_input(input)? }),
+        InputFormat::Directory => Box::new(DirectorySource::new(input)?),
+        InputFormat::Warc => Box::new(WarcSource { reader: open_input(input)? }),
+    })
+}
+
+// The file, decompressed if it is gzipped. Concatenated gzip members, as in a .warc.gz, are read one after the other.
+fn open_input(path: &Path) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+fn single_line(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// TREC documents end at the line with the closing </DOC> tag
+struct TrecSource {
+    reader: Box<dyn BufRead>,
+    current_doc: Vec<String>,
+}
+
+impl DocumentSource for TrecSource {
+    fn next_document(&mut self) -> io::Result<Option<SourceDocument>> {
+        let mut line = String::new();
+        while self.reader.read_line(&mut line)? > 0 {
+            let end_of_doc = line.contains("</DOC>");
+            self.current_doc.push(line.trim_end_matches(['\n', '\r']).to_string());
+            line.clear();
+
+            if end_of_doc {
+                let document = parse_trec_document(&self.current_doc.join("\n"));
+                self.current_doc.clear();
+                return Ok(Some(document));
+            }
+        }
+
+        // A truncated last document without its closing tag is still indexed
+        if self.current_doc.is_empty() {
+            return Ok(None);
+        }
+        let document = parse_trec_document(&self.current_doc.join("\n"));
+        self.current_doc.clear();
+        Ok(Some(document))
+    }
+}
+
+// The DOCNO and LANG tags come before the TEXT, whose first line is the URL and second line the title. The text
+// ends at the last closing TEXT tag, the tags it contains are kept as they are.
+fn parse_trec_document(document: &str) -> SourceDocument {
+    let (header, text) = match document.find("<TEXT>") {
+        Some(start) => {
+            let text = &document[start + "<TEXT>".len()..];
+            (&document[..start], text.rfind("</TEXT>").map_or(text, |end| &text[..end]))
+        },
+        None => (document, ""),
+    };
+    let tag = |regex: &Regex| regex.captures(header).and_then(|cap| cap.get(1)).map(|m| m.as_str().to_string());
+
+    // Only the line break after the opening tag is dropped at the start, the URL line may be empty
+    let text = text.trim_start_matches([' ', '\t']);
+    let text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(text);
+    let (url, title, body) = split_text_content(text.trim_end());
+    SourceDocument {
+        lang: tag(&LANG),
+        ..SourceDocument::new(&tag(&DOCNO).unwrap_or_default(), url, title, body)
+    }
+}
+
+struct JsonlSource {
+    reader: Box<dyn BufRead>,
+    fields: JsonlFields,
+    line_number: usize,
+}
+
+impl DocumentSource for JsonlSource {
+    fn next_document(&mut self) -> io::Result<Option<SourceDocument>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        let object: Value = serde_json::from_str(&line)
+            .map_err(|e| invalid(format!("Line {}: {}", self.line_number, e)))?;
+        let field = |name: &str| match object.get(name) {
+            Some(Value::String(text)) => Some(text.clone()),
+            Some(Value::Null) | None => None,
+            // Numeric identifiers are common
+            Some(value) => Some(value.to_string()),
+        };
+
+        let id = field(&self.fields.id)
+            .ok_or_else(|| invalid(format!("Line {}: no \"{}\" field", self.line_number, self.fields.id)))?;
+        let text = field(&self.fields.text).unwrap_or_default();
+        let title = self.fields.title.as_deref().and_then(field).unwrap_or_default();
+        let url = self.fields.url.as_deref().and_then(field).unwrap_or_default();
+        Ok(Some(SourceDocument::new(&id, &url, &title, &text)))
+    }
+}
+
+struct MsMarcoSource {
+    reader: Box<dyn BufRead>,
+}
+
+impl DocumentSource for MsMarcoSource {
+    fn next_document(&mut self) -> io::Result<Option<SourceDocument>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                continue;
+            }
+
+            let columns: Vec<&str> = line.splitn(4, '\t').collect();
+            return Ok(Some(match columns[..] {
+                [docid, url, title, body] => SourceDocument::new(docid, url, title, body),
+                [pid, passage] => SourceDocument::new(pid, "", "", passage),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("Expected 2 or 4 tab separated columns: {}", line))),
+            }));
+        }
+    }
+}
+
+// The files of the directory and its subdirectories, in path order
+struct DirectorySource {
+    root: PathBuf,
+    files: std::vec::IntoIter<PathBuf>,
+}
+
+impl DirectorySource {
+    fn new(root: &Path) -> io::Result<Self> {
+        let mut files = Vec::new();
+        let mut directories = vec![root.to_path_buf()];
+        while let Some(directory) = directories.pop() {
+            for entry in fs::read_dir(&directory)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    directories.push(entry.path());
+                } else {
+                    files.push(entry.path());
+                }
+            }
+        }
+        files.sort();
+        Ok(Self { root: root.to_path_buf(), files: files.into_iter() })
+    }
+}
+
+impl DocumentSource for DirectorySource {
+    fn next_document(&mut self) -> io::Result<Option<SourceDocument>> {
+        let Some(path) = self.files.next() else { return Ok(None) };
+        let docno = path.strip_prefix(&self.root).unwrap_or(&path).to_string_lossy().into_owned();
+        let text = String::from_utf8_lossy(&fs::read(&path)?).into_owned();
+        Ok(Some(SourceDocument::new(&docno, "", "", &text)))
+    }
+}
+
+// WARC records are a version line, header lines, a blank line and a block of Content-Length bytes.
+// The block of a response record is the HTTP response, whose payload is the document.
+struct WarcSource {
+    reader: Box<dyn BufRead>,
+}
+
+impl DocumentSource for WarcSource {
+    fn next_document(&mut self) -> io::Result<Option<SourceDocument>> {
+        loop {
+            let Some(headers) = self.read_headers()? else { return Ok(None) };
+            let header = |name: &str| headers.iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str());
+
+            let length: usize = header("Content-Length").and_then(|length| length.parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "WARC record without Content-Length"))?;
+            let mut block = vec![0; length];
+            self.reader.read_exact(&mut block)?;
+
+            if header("WARC-Type") != Some("response") {
+                continue;
+            }
+            let docno = header("WARC-TREC-ID").or(header("WARC-Record-ID")).unwrap_or_default()
+                .trim_start_matches('<').trim_end_matches('>');
+            let url = header("WARC-Target-URI").unwrap_or_default();
+            let block = String::from_utf8_lossy(&block);
+            // Past the HTTP status line and headers
+            let payload = block.split_once("\r\n\r\n").or_else(|| block.split_once("\n\n"))
+                .map_or(block.as_ref(), |(_, payload)| payload);
+            return Ok(Some(SourceDocument::new(docno, url, "", payload)));
+        }
+    }
+}
+
+impl WarcSource {
+    // Headers of the next record, None at the end of the file
+    fn read_headers(&mut self) -> io::Result<Option<Vec<(String, String)>>> {
+        let mut line = String::new();
+        // Records are separated by blank lines
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        if !line.starts_with("WARC/") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected a WARC record: {}", line.trim())));
+        }
+
+        let mut headers = Vec::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                return Ok(Some(headers));
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                headers.push((key.trim().to_string(), value.trim().to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn reader(text: &str) -> Box<dyn BufRead> {
+        Box::new(Cursor::new(text.as_bytes().to_vec()))
+    }
+
+    fn read_all(mut source: impl DocumentSource) -> io::Result<Vec<SourceDocument>> {
+        let mut documents = Vec::new();
+        while let Some(document) = source.next_document()? {
+            documents.push(document);
+        }
+        Ok(documents)
+    }
+
+    fn fields(document: &SourceDocument) -> (&str, &str, &str, &str) {
+        (&document.docno, &document.url, &document.title, &document.body)
+    }
+
+    #[test]
+    fn trec_documents() {
+        let collection = "<DOC>\n<DOCNO> D1 </DOCNO>\n<LANG>es</LANG>\n<TEXT>\nhttp://example.com/1\n  Title  one \
+                          \nFirst body\nsecond line\n</TEXT>\n</DOC>\n\
+                          <DOC>\n<DOCNO>D2</DOCNO>\n<TEXT>\n\nNo URL\n<b>bold</b> and </TEXT> in the body\n</TEXT>\n</DOC>\n\
+                          <DOC>\n<DOCNO>D3</DOCNO>\n<TEXT>\nhttp://example.com/3\nThird\nTruncated";
+        let documents = read_all(TrecSource { reader: reader(collection), current_doc: Vec::new() }).unwrap();
+
+        assert_eq!(documents.len(), 3);
+        assert_eq!(fields(&documents[0]), ("D1", "http://example.com/1", "Title one", "First body\nsecond line"));
+        assert_eq!(documents[0].lang.as_deref(), Some("es"));
+        // The tags of the text are part of it
+        assert_eq!(fields(&documents[1]), ("D2", "", "No URL", "<b>bold</b> and </TEXT> in the body"));
+        assert_eq!(documents[1].lang, None);
+        assert_eq!(fields(&documents[2]), ("D3", "http://example.com/3", "Third", "Truncated"));
+    }
+
+    #[test]
+    fn tags_in_the_text_are_not_the_header() {
+        let document = parse_trec_document("<DOC>\n<DOCNO>D1</DOCNO>\n<TEXT>\nurl\ntitle\n<DOCNO>D2</DOCNO> <LANG>fr</LANG>\n</TEXT>\n</DOC>");
+        assert_eq!(fields(&document), ("D1", "url", "title", "<DOCNO>D2</DOCNO> <LANG>fr</LANG>"));
+        assert_eq!(document.lang, None);
+    }
+
+    #[test]
+    fn jsonl_documents() {
+        let fields_names = JsonlFields {
+            id: "id".to_string(),
+            text: "contents".to_string(),
+            title: Some("title".to_string()),
+            url: Some("url".to_string()),
+        };
+        let collection = "{\"id\": \"a\", \"contents\": \"Body\\n</TEXT> kept\", \"title\": \"Two\\nlines\", \"url\": \"http://a\"}\n\
+                          \n\
+                          {\"id\": 7, \"contents\": \"Only text\", \"title\": null}\n";
+        let source = JsonlSource { reader: reader(collection), fields: fields_names.clone(), line_number: 0 };
+        let documents = read_all(source).unwrap();
+
+        assert_eq!(documents.len(), 2);
+        assert_eq!(fields(&documents[0]), ("a", "http://a", "Two lines", "Body\n</TEXT> kept"));
+        // Numeric identifiers are kept as written
+        assert_eq!(fields(&documents[1]), ("7", "", "", "Only text"));
+
+        let source = JsonlSource { reader: reader("{\"id\": \"a\"}\n{\"contents\": \"b\"}\n"), fields: fields_names.clone(), line_number: 0 };
+        let error = read_all(source).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().starts_with("Line 2"), "{}", error);
+
+        let source = JsonlSource { reader: reader("not json\n"), fields: fields_names, line_number: 0 };
+        assert_eq!(read_all(source).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ms_marco_documents_and_passages() {
+        let collection = "D1\thttp://a\tTitle\tBody\twith a tab\r\n\n7\tA passage\n";
+        let documents = read_all(MsMarcoSource { reader: reader(collection) }).unwrap();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(fields(&documents[0]), ("D1", "http://a", "Title", "Body\twith a tab"));
+        assert_eq!(fields(&documents[1]), ("7", "", "", "A passage"));
+
+        let error = read_all(MsMarcoSource { reader: reader("D1\thttp://a\tTitle\n") }).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn warc_response_records() {
+        let record = |kind: &str, id: &str, block: &str| format!(
+            "WARC/1.0\r\nWARC-Type: {}\r\nWARC-Record-ID: <{}>\r\nWARC-Target-URI: http://{}\r\nContent-Length: {}\r\n\r\n{}\r\n\r\n",
+            kind, id, id, block.len(), block);
+        let collection = record("warcinfo", "info", "software: test")
+            + &record("response", "r1", "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>Page</p>");
+        let documents = read_all(WarcSource { reader: reader(&collection) }).unwrap();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(fields(&documents[0]), ("r1", "http://r1", "", "<p>Page</p>"));
+
+        let error = read_all(WarcSource { reader: reader("not a record\n") }).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn gzipped_input_is_detected_by_its_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("collection.txt");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b"1\tA passage\n").unwrap();
+        encoder.finish().unwrap();
+
+        let fields_names = JsonlFields { id: "id".to_string(), text: "contents".to_string(), title: None, url: None };
+        let mut source = open_source(&path, InputFormat::MsMarco, &fields_names).unwrap();
+        let document = source.next_document().unwrap().unwrap();
+        assert_eq!(fields(&document), ("1", "", "", "A passage"));
+        assert!(source.next_document().unwrap().is_none());
+    }
+
+    #[test]
+    fn directory_documents_are_identified_by_their_path() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("b.txt"), "Second").unwrap();
+        fs::write(dir.path().join("a.txt"), "First").unwrap();
+        fs::write(dir.path().join("sub").join("c.html"), "<p>Third</p>").unwrap();
+
+        let documents = read_all(DirectorySource::new(dir.path()).unwrap()).unwrap();
+        let docnos: Vec<_> = documents.iter().map(|document| document.docno.as_str()).collect();
+        assert_eq!(docnos, ["a.txt", "b.txt", Path::new("sub").join("c.html").to_str().unwrap()]);
+        assert_eq!(documents[2].body, "<p>Third</p>");
+    }
+}
@@ -1,453 +1,1210 @@
 extern crate stream_vbyte;
 extern crate byteorder;
 
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use byteorder::{LittleEndian, ReadBytesExt};
-use stream_vbyte::decode::decode;
-use stream_vbyte::scalar::Scalar;
-use crate::utils::DIRECTORY_NTH_TERM;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
 use crate::bin_indexer::TermMetadata;
-use crate::disk_io::load_doc_metadata;
-use crate::parser::parse_line as tokenize;
-use crate::utils::{BM25_K1, BM25_B};
-
-const BLOCK_SIZE: usize = 64;
-
+use crate::posting_cursor::{PostingCursor, END_OF_POSTINGS};
+use crate::analyzer::{Analyzer, Language};
+use crate::parser::split_text_content;
+use crate::query_parser::{parse_query, QueryNode};
+use crate::config::{IndexConfig, IndexPaths};
+use crate::scoring::{idf, rescale_bound, with_rounding_margin, BoundParameters, CollectionStatistics, Scorer, ScoringFunction, TermStatistics};
+use crate::segment::Segment;
+use crate::snippets::snippet;
+use crate::tombstones::Tombstones;
+use crate::doc_table::DocTable;
+use crate::fields::Field;
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+
+use clap::ValueEnum;
+use log::{error, warn};
+use lru::LruCache;
 use serde::{Serialize, Deserialize};
 
+// Number of results of a page when the request does not say
+pub const DEFAULT_PAGE_SIZE: usize = 10;
+// Deepest rank a query can page to, it bounds the top-k heap of every request
+const MAX_RESULT_DEPTH: usize = 10_000;
+// Number of terms whose metadata is cached, the least recently queried are evicted first
+const METADATA_CACHE_TERMS: usize = 100_000;
+
 #[derive(Serialize, Deserialize)]
 struct SearchResult {
     doc_id: u32,
+    docno: String,
     doc_url: String,
     score: f32,
+    // Passage of the document around the query terms, HTML with the terms in <mark> tags.
+    // None if the index does not store the documents.
+    snippet: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LookupResponse {
+    docno: String,
+    // None if no live document has the DOCNO
+    doc_id: Option<u32>,
+    doc_url: Option<String>,
+}
+
+// A stored document, as the collection gave it
+#[derive(Serialize, Deserialize)]
+pub struct Document {
+    pub doc_id: u32,
+    pub docno: String,
+    pub url: String,
+    // Empty if the document has none, see split_text_content
+    pub title: String,
+    pub body: String,
+}
+
+// How the terms of a query are combined
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum QueryMode {
+    // All the terms
+    And,
+    // Any of the terms, top-k with (Block-Max) WAND
+    Or,
+    // The terms in sequence
+    Phrase,
+    // Boolean query language, see query_parser.rs
+    Search,
 }
 
 #[derive(Serialize, Deserialize)]
 struct QueryResponse {
     query: String,
+    offset: usize,
+    // See TopDocs
+    total_matches: usize,
     results: Vec<SearchResult>,
 }
 
+// The best documents of a query, by decreasing score then increasing docid, and how many documents it matched
+pub struct TopDocs {
+    pub docs: Vec<(u32, f32)>,
+    // Number of live documents the query matched, whether or not they were scored
+    pub total_matches: usize,
+}
+
+// Immutable once built, so a single instance can be shared by all server workers. The index files and doc
+// tables are memory-mapped and read positionally, only the metadata cache is written to, behind its own lock.
 pub struct TermQueryProcessor {
-    directory_file: BufReader<File>,
-    lexicon_file: BufReader<File>,
-    index_file: BufReader<File>,
-    doc_metadata: HashMap<u32, (String, u32)>,
-    directory_cache: HashMap<String, u64>,
-    metadata_cache: HashMap<String, TermMetadata>,
-    total_docs: u32,
-    avg_doc_len: u32,
+    // In increasing docid order; queries are evaluated segment by segment and the results concatenated
+    segments: Vec<Segment>,
+    config: IndexConfig,
+    // Metadata of a term in every segment, None where it does not occur
+    metadata_cache: Mutex<LruCache<String, Arc<Vec<Option<TermMetadata>>>>>,
+    // Deleted documents, never returned although their postings are still in the index
+    deleted: Tombstones,
+    // Statistics of the whole collection, over all segments
+    statistics: CollectionStatistics,
+    has_positions: bool,
+    // The analyzer the index was built with, see config.rs
+    analyzer: Analyzer,
 }
 impl TermQueryProcessor {
-    pub fn new(index_path: &str, lexicon_path: &str, directory_path: &str, doc_metadata_path: &str) -> Self {
-        let doc_metadata = load_doc_metadata(doc_metadata_path).unwrap();
-        let total_docs = doc_metadata.keys().max().cloned().unwrap_or(0);
-        let total_length: u32 = doc_metadata.values()
-            .map(|(_, length)| length)
-            .sum();
-        let avg_doc_len = if total_docs > 0 {
-            total_length / total_docs
-        } else {
-            0 // Return 0 if there are no documents
-        };
+    pub fn new(paths: &IndexPaths, config: &IndexConfig) -> Self {
+        let segments: Vec<_> = paths.segments().unwrap().iter()
+            .map(|segment_paths| Segment::open(segment_paths).unwrap())
+            .collect();
+
+        let segment_statistics: Vec<_> = segments.iter().map(|segment| *segment.statistics()).collect();
+        let statistics = CollectionStatistics::combine(&segment_statistics);
+        let has_positions = segments.iter().all(Segment::has_positions);
+        let deleted = Tombstones::load(&paths.deleted_docs()).unwrap();
 
         Self {
-            directory_file: BufReader::new(File::open(directory_path).unwrap()),
-            lexicon_file: BufReader::new(File::open(lexicon_path).unwrap()),
-            index_file: BufReader::new(File::open(index_path).unwrap()),
-            doc_metadata,
-            directory_cache: Default::default(),
-            metadata_cache: Default::default(),
-            total_docs,
-            avg_doc_len,
+            segments,
+            config: config.clone(),
+            metadata_cache: Mutex::new(LruCache::new(NonZeroUsize::new(METADATA_CACHE_TERMS).unwrap())),
+            deleted,
+            statistics,
+            has_positions,
+            analyzer: Analyzer::new(&config.analyzer),
         }
     }
 
-    pub fn query_term_directory(&mut self, term: &str) -> Result<u64, std::io::Error> {
+    // Metadata of the term in every segment, None where it does not occur
+    pub fn query_term_metadata(&self, term: &str) -> Result<Arc<Vec<Option<TermMetadata>>>, std::io::Error> {
         // Check the cache first
-        if let Some(&position) = self.directory_cache.get(term) {
-            return Ok(position);
-        }
-
-        self.directory_file.seek(SeekFrom::Start(0))?; // reset the file pointer to the beginning of the file
-        let total_dirs = self.directory_file.read_u32::<LittleEndian>()?;
-
-        let mut lexicon_position = 0;
-        let mut last_lexicon_position = 0;
-
-        for _ in 0..total_dirs {
-            let term_length = self.directory_file.read_u32::<LittleEndian>()? as usize;
-            let mut term_buffer = vec![0u8; term_length];
-            self.directory_file.read_exact(&mut term_buffer)?;
-            let dir_term = String::from_utf8_lossy(&term_buffer);
-
-            last_lexicon_position = lexicon_position;
-            lexicon_position = self.directory_file.read_u64::<LittleEndian>()?;
-
-            match dir_term.as_ref().cmp(term) {
-                std::cmp::Ordering::Equal => {
-                    // Term matches, update cache with the current lexicon position
-                    self.directory_cache.insert(term.to_string(), lexicon_position);
-                    return Ok(lexicon_position)
-                },
-                std::cmp::Ordering::Greater => {
-                    // Term is greater than the searched term, update cache with the last lexicon position
-                    self.directory_cache.insert(term.to_string(), last_lexicon_position);
-                    return Ok(last_lexicon_position)
-                },
-                std::cmp::Ordering::Less => {
-                    // Continue searching if the directory term is less than the searched term
-                    continue;
-                },
-            }
+        if let Some(metadata) = self.metadata_cache.lock().unwrap().get(term) {
+            return Ok(Arc::clone(metadata));
+        }
 
+        let mut metadata = Vec::with_capacity(self.segments.len());
+        for segment in &self.segments {
+            metadata.push(segment.term_metadata(term)?);
         }
+        if metadata.iter().all(Option::is_none) {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Term not found in lexicon"));
+        }
+
+        // Insert the metadata into the cache
+        let metadata = Arc::new(metadata);
+        self.metadata_cache.lock().unwrap().put(term.to_string(), Arc::clone(&metadata));
 
-        Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Term not found in directory"))
+        Ok(metadata)
     }
 
-    pub fn query_term_metadata(&mut self, term: &str) -> Result<TermMetadata, std::io::Error> {
-        // Check the cache first
-        if let Some(metadata) = self.metadata_cache.get(term) {
-            return Ok(metadata.clone()); // Clone the metadata as it's being returned by reference
-        }
+    // Number of documents of the whole collection containing the term
+    pub fn doc_freq(&self, term: &str) -> u32 {
+        self.query_term_metadata(term)
+            .map(|metadata| metadata.iter().flatten().map(|m| m.doc_freq).sum())
+            .unwrap_or(0)
+    }
 
-        let lexicon_directory = self.query_term_directory(term)?;
-        self.lexicon_file.seek(SeekFrom::Start(lexicon_directory))?;
+    // A cursor over the postings of the term in every segment, None where it does not occur. The cursors of a
+    // plain term scored by a scorer weighing the fields also follow the postings of its field terms.
+    pub fn posting_cursors(&self, term: &str, scorer: &dyn Scorer) -> std::io::Result<Vec<Option<PostingCursor<'_>>>> {
+        let segment_metadata = self.query_term_metadata(term)?;
+        let doc_freq = segment_metadata.iter().flatten().map(|m| m.doc_freq).sum();
+        let total_term_freq = segment_metadata.iter().flatten().map(|m| m.total_term_freq).sum();
+        let field = Field::split_term(term).map(|(field, _)| field);
+        let mut field_cursors = match field {
+            None if scorer.weighs_fields() => self.field_cursors(term)?,
+            _ => Vec::new(),
+        }.into_iter();
+
+        segment_metadata.iter().zip(&self.segments)
+            .map(|(metadata, segment)| {
+                let field_cursors = field_cursors.next().unwrap_or_default();
+                match metadata {
+                    Some(metadata) => {
+                        let metadata = self.collection_metadata(segment, metadata.clone(), doc_freq, total_term_freq);
+                        let mut cursor = PostingCursor::new(segment.index(), metadata, self.config.block_size)?;
+                        cursor.set_field(field);
+                        cursor.set_field_cursors(field_cursors);
+                        Ok(Some(cursor))
+                    },
+                    None => Ok(None),
+                }
+            })
+            .collect()
+    }
 
-        loop {
-            // Read the length of the term first
-            let term_length = match self.lexicon_file.read_u32::<LittleEndian>() {
-                Ok(length) => length as usize,
+    // Cursors over the postings of the field terms of a plain term in every segment
+    fn field_cursors(&self, term: &str) -> std::io::Result<Vec<Vec<PostingCursor<'_>>>> {
+        let mut segment_cursors: Vec<Vec<PostingCursor>> = self.segments.iter().map(|_| Vec::new()).collect();
+        for field in [Field::Url, Field::Title, Field::Heading] {
+            let segment_metadata = match self.query_term_metadata(&field.term(term)) {
+                Ok(segment_metadata) => segment_metadata,
+                // The term does not occur in the field
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
                 Err(e) => return Err(e),
             };
+            for ((cursors, metadata), segment) in segment_cursors.iter_mut().zip(segment_metadata.iter()).zip(&self.segments) {
+                if let Some(metadata) = metadata {
+                    let mut cursor = PostingCursor::new(segment.index(), metadata.clone(), self.config.block_size)?;
+                    cursor.set_field(Some(field));
+                    cursors.push(cursor);
+                }
+            }
+        }
+        Ok(segment_cursors)
+    }
 
-            let mut term_buffer = vec![0u8; term_length];
-            self.lexicon_file.read_exact(&mut term_buffer)?;
-
-            let lex_term = String::from_utf8_lossy(&term_buffer);
+    // Whether the score bounds stored in the index hold for the queries scored with the scoring function
+    fn has_stored_bounds(&self, scoring: ScoringFunction) -> bool {
+        BoundParameters::new(scoring, &self.config) == self.statistics.bounds
+    }
 
-            if lex_term.as_ref() > term {
-                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Term not found in lexicon"));
+    // The metadata of a term in one segment as seen by the scoring of the whole collection: the document and
+    // collection frequencies over all segments and score upper bounds that hold with the collection statistics
+    fn collection_metadata(&self, segment: &Segment, mut metadata: TermMetadata, doc_freq: u32,
+                           total_term_freq: u32) -> TermMetadata {
+        if self.segments.len() > 1 {
+            let segment_statistics = segment.statistics();
+            let segment_idf = idf(metadata.doc_freq, segment_statistics.total_docs);
+            let collection_idf = idf(doc_freq, self.statistics.total_docs);
+            let length_ratio = self.statistics.length_ratio(segment_statistics);
+            let rescale = |bound| rescale_bound(bound, segment_idf, collection_idf, length_ratio, self.statistics.bounds.k1);
+
+            metadata.max_score = rescale(metadata.max_score);
+            for block_max_score in &mut metadata.block_max_scores {
+                *block_max_score = rescale(*block_max_score);
             }
+        }
+        metadata.doc_freq = doc_freq;
+        metadata.total_term_freq = total_term_freq;
+        metadata
+    }
 
-            let term_id = self.lexicon_file.read_u32::<LittleEndian>()?;
-            let doc_freq = self.lexicon_file.read_u32::<LittleEndian>()?;
-            let total_term_freq = self.lexicon_file.read_u32::<LittleEndian>()?;
-            let term_start_pointer = self.lexicon_file.read_u64::<LittleEndian>()?;
-            let num_blocks = self.lexicon_file.read_u32::<LittleEndian>()?;
-            let num_posting_in_last_block = self.lexicon_file.read_u32::<LittleEndian>()?;
-            let last_doc_id = self.lexicon_file.read_u32::<LittleEndian>()?;
-
-            let mut compressed_docids_sizes_per_block = vec![0u64; num_blocks as usize];
-            for size in &mut compressed_docids_sizes_per_block {
-                *size = self.lexicon_file.read_u64::<LittleEndian>()?;
+    // Document-at-a-time intersection segment by segment, a term missing from a segment leaves no match in it
+    fn intersect_segments(&self, term_cursors: Vec<Vec<Option<PostingCursor<'_>>>>, phrase: bool,
+                          scorer: &dyn Scorer, on_match: &mut dyn FnMut(u32, f32)) -> std::io::Result<()> {
+        let mut term_cursors: Vec<_> = term_cursors.into_iter().map(Vec::into_iter).collect();
+
+        for _ in &self.segments {
+            let segment_cursors: Vec<Option<PostingCursor>> = term_cursors.iter_mut()
+                .map(|cursors| cursors.next().flatten())
+                .collect();
+            if let Some(mut cursors) = segment_cursors.into_iter().collect::<Option<Vec<_>>>() {
+                self.intersect(&mut cursors, phrase, scorer, on_match)?;
             }
+        }
 
-            let mut block_offsets = vec![0u64; num_blocks as usize];
-            for offset in &mut block_offsets {
-                *offset = self.lexicon_file.read_u64::<LittleEndian>()?;
-            }
+        Ok(())
+    }
 
-            let mut block_maxima = vec![0u32; num_blocks as usize];
-            for max in &mut block_maxima {
-                *max = self.lexicon_file.read_u32::<LittleEndian>()?;
-            }
+    // Top k documents of the query evaluated in the mode, scored with the scoring function,
+    // the configured one by default
+    pub fn top_k(&self, mode: QueryMode, query: &str, k: usize, scoring: Option<ScoringFunction>,
+                 language: Option<Language>) -> std::io::Result<TopDocs> {
+        let scoring = scoring.unwrap_or(self.config.scorer);
+        let language = self.analyzer.language(query, language);
+        let scorer = scoring.scorer(&self.config);
+        // Only the k best matches are kept, there can be many more of them
+        let mut top_k = TopK::new(k);
+        let mut collect = |doc_id, score| top_k.collect(doc_id, score);
+        match mode {
+            QueryMode::And => self.conjunctive_matches(query, language, scorer.as_ref(), &mut collect)?,
+            QueryMode::Or => {
+                // WAND only ever keeps the top k, and skips documents without finding out whether they match
+                let query_terms = self.analyzer.analyze(query, language);
+                let docs = self.wand_top_k(&query_terms, k, self.config.block_max_wand, scoring)?;
+                return Ok(TopDocs { docs, total_matches: self.count_matches(&query_terms)? });
+            },
+            QueryMode::Phrase => self.match_phrase(&self.analyzer.analyze(query, language), scorer.as_ref(), &mut collect)?,
+            QueryMode::Search => if let Some(node) = parse_query(query, &self.analyzer, language) {
+                for (doc_id, score) in self.evaluate(&node, None, scorer.as_ref())? {
+                    collect(doc_id, score);
+                }
+            },
+        }
 
-            if lex_term == term {
-                let metadata = TermMetadata {
-                    term_id,
-                    doc_freq,
-                    total_term_freq,
-                    term_start_pointer,
-                    num_blocks,
-                    num_posting_in_last_block,
-                    last_doc_id,
-                    compressed_docids_per_block: compressed_docids_sizes_per_block,
-                    block_offsets,
-                    block_maxima,
-                };
+        Ok(TopDocs { total_matches: top_k.matches, docs: top_k.into_docs() })
+    }
 
-                // Insert the metadata into the cache
-                self.metadata_cache.insert(term.to_string(), metadata.clone());
+    // The k documents ranked after the offset best ones, as a JSON response. The language of the query is
+    // detected if not given, which short queries rarely are reliably. A query that cannot be evaluated, such
+    // as a phrase query on an index without positions, is logged and returned as an error.
+    pub fn query(&self, mode: QueryMode, query: &str, scoring: Option<ScoringFunction>, language: Option<Language>,
+                 offset: usize, k: usize) -> std::io::Result<String> {
+        // The pages past the maximum depth are empty
+        let depth = offset.saturating_add(k).min(MAX_RESULT_DEPTH);
+        let top_docs = self.top_k(mode, query, depth, scoring, language).inspect_err(|e| {
+            let description = match mode {
+                QueryMode::And => "conjunctive query",
+                QueryMode::Or => "disjunctive query",
+                QueryMode::Phrase => "phrase query",
+                QueryMode::Search => "query",
+            };
+            error!("Error evaluating {} '{}': {}", description, query, e);
+        })?;
+
+        let query_language = self.analyzer.language(query, language);
+        let query_terms: HashSet<String> = match mode {
+            // The terms of a field are highlighted wherever they occur
+            QueryMode::Search => parse_query(query, &self.analyzer, query_language).map(|node| node.terms()).unwrap_or_default(),
+            _ => self.analyzer.analyze(query, query_language),
+        }.into_iter().collect();
+        Ok(serde_json::to_string(&QueryResponse {
+            query: query.to_string(),
+            offset,
+            total_matches: top_docs.total_matches,
+            results: top_docs.docs.into_iter()
+                .skip(offset)
+                .map(|(doc_id, score)| self.search_result(doc_id, score, &query_terms))
+                .collect(),
+        })?)
+    }
 
-                // Return the metadata
-                return Ok(metadata);
+    // Pass the documents containing all the terms of the query, with their scores, to on_match in docid order
+    fn conjunctive_matches(&self, query: &str, language: Option<Language>, scorer: &dyn Scorer,
+                           on_match: &mut dyn FnMut(u32, f32)) -> std::io::Result<()> {
+        // Terms that are not in the lexicon are ignored
+        let mut term_cursors = Vec::new();
+        for term in &self.analyzer.analyze(query, language) {
+            if let Ok(cursors) = self.posting_cursors(term, scorer) {
+                term_cursors.push(cursors);
             }
         }
-    }
 
-    pub fn query_term_all_postings(&mut self, term: &str) -> std::io::Result<Vec<(u32, u32)>> {
-        let term_metadata = self.query_term_metadata(term)?;
+        self.intersect_segments(term_cursors, false, scorer, on_match)
+    }
 
-        self.index_file.seek(SeekFrom::Start(term_metadata.term_start_pointer))?;
+    // Document-at-a-time intersection of the cursors, passing the common docids with their summed scores to
+    // on_match. With phrase set, a document only matches if the i-th cursor's term occurs i positions after the
+    // first one.
+    fn intersect(&self, cursors: &mut [PostingCursor], phrase: bool, scorer: &dyn Scorer,
+                 on_match: &mut dyn FnMut(u32, f32)) -> std::io::Result<()> {
+        if cursors.is_empty() {
+            return Ok(());
+        }
 
-        let mut postings = Vec::with_capacity(term_metadata.doc_freq as usize);
-        let mut last_doc_id = 0; // Initialize last_doc_id
+        // The shortest postings list drives the intersection, the others follow in increasing length
+        let mut order: Vec<usize> = (0..cursors.len()).collect();
+        order.sort_by_key(|&i| cursors[i].metadata().doc_freq);
+        let lead = order[0];
+
+        let mut candidate = cursors[lead].doc();
+        while candidate != END_OF_POSTINGS {
+            let mut all_match = true;
+            for &i in &order[1..] {
+                cursors[i].next_geq(candidate)?;
+                if cursors[i].doc() != candidate {
+                    all_match = false;
+                    candidate = cursors[i].doc();
+                    break;
+                }
+            }
 
-        // For each block
-        for (i, &compressed_size) in term_metadata.compressed_docids_per_block.iter().enumerate() {
-            // Determine the number of docids in this block
-            let block_size = if i == term_metadata.compressed_docids_per_block.len() - 1 {
-                term_metadata.num_posting_in_last_block as usize
+            if all_match {
+                if !self.deleted.contains(candidate) && (!phrase || is_phrase_match(cursors)?) {
+                    let mut score = 0.0;
+                    for cursor in cursors.iter_mut() {
+                        score += self.score(scorer, cursor, candidate)?;
+                    }
+                    on_match(candidate, score);
+                }
+                cursors[lead].next()?;
             } else {
-                BLOCK_SIZE
-            };
+                cursors[lead].next_geq(candidate)?;
+            }
+            candidate = cursors[lead].doc();
+        }
 
-            // Read and decompress docids for this block
-            let mut compressed_docids = vec![0u8; compressed_size as usize];
-            self.index_file.read_exact(&mut compressed_docids)?;
+        Ok(())
+    }
 
-            let mut docids = vec![0u32; block_size];
-            decode::<Scalar>(&compressed_docids, block_size, &mut docids);
+    // Top-k documents of the disjunction of the terms with WAND dynamic pruning: documents whose score
+    // upper bound (the sum of the terms' max_score) cannot beat the current k-th best score are skipped
+    // without being scored. With block_max the bound is refined with the block_max_scores of the blocks
+    // that would contain the candidate (Block-Max WAND), allowing whole blocks to be skipped undecoded.
+    // The bounds stored in the index only hold for the scoring function and parameters it was built with, see
+    // BoundParameters, the other scoring functions use their own bound for every block.
+    pub fn wand_top_k(&self, query_terms: &[String], k: usize, block_max: bool,
+                      scoring: ScoringFunction) -> std::io::Result<Vec<(u32, f32)>> {
+        if k == 0 {
+            return Ok(Vec::new());
+        }
 
-            // Adjust the first docid in the block if necessary
-            if last_doc_id != 0 {
-                docids[0] += last_doc_id;
+        let scorer = scoring.scorer(&self.config);
+        let stored_bounds = self.has_stored_bounds(scoring);
+        let mut segment_cursors: Vec<Vec<PostingCursor>> = self.segments.iter().map(|_| Vec::new()).collect();
+        for term in query_terms {
+            // Terms missing from the lexicon cannot contribute to any score
+            if let Ok(cursors) = self.posting_cursors(term, scorer.as_ref()) {
+                for (segment, mut cursor) in cursors.into_iter().enumerate() {
+                    if let (Some(cursor), false) = (&mut cursor, stored_bounds) {
+                        let max_score = scorer.max_score(&term_statistics(cursor.metadata()), &self.statistics);
+                        cursor.set_max_score(with_rounding_margin(max_score));
+                    }
+                    segment_cursors[segment].extend(cursor);
+                }
             }
+        }
+
+        let mut top_k = TopK::new(k);
+
+        // The segments share the top-k, the threshold reached in one already prunes the next ones
+        for mut cursors in segment_cursors {
+            loop {
+                cursors.retain(|cursor| cursor.doc() != END_OF_POSTINGS);
+                cursors.sort_by_key(|cursor| cursor.doc());
+
+                // Find the pivot, the first cursor at which the accumulated upper bound exceeds the threshold.
+                // Very common terms can have a negative idf, their bound is clamped to 0 to keep the sum an upper bound.
+                let mut upper_bound = 0.0f32;
+                let mut pivot = None;
+                for (i, cursor) in cursors.iter().enumerate() {
+                    upper_bound += cursor.metadata().max_score.max(0.0);
+                    if upper_bound > top_k.threshold() || !top_k.is_full() {
+                        pivot = Some(i);
+                        break;
+                    }
+                }
+                let Some(mut pivot) = pivot else { break };
+                let pivot_doc = cursors[pivot].doc();
+                while pivot + 1 < cursors.len() && cursors[pivot + 1].doc() == pivot_doc {
+                    pivot += 1;
+                }
 
-            // Delta decoding
-            let decoded_docids = delta_decoding(&docids);
-            last_doc_id = *decoded_docids.last().unwrap();
+                if block_max && top_k.is_full() {
+                    // Refine the bound with the maxima of the blocks containing the pivot docid
+                    let mut block_upper_bound = 0.0f32;
+                    let mut next_candidate = cursors.get(pivot + 1).map(|cursor| cursor.doc()).unwrap_or(END_OF_POSTINGS);
+                    for cursor in &cursors[..=pivot] {
+                        let (block_max_score, block_max_doc) = cursor.block_bound(pivot_doc);
+                        block_upper_bound += block_max_score.max(0.0);
+                        next_candidate = next_candidate.min(block_max_doc.saturating_add(1));
+                    }
 
-            // Read frequencies for this block
-            let mut frequencies = vec![0u32; block_size];
-            for freq in &mut frequencies {
-                *freq = self.index_file.read_u32::<LittleEndian>()?;
-            }
+                    if block_upper_bound <= top_k.threshold() {
+                        // No document before the end of the shortest of these blocks can enter the top-k
+                        for cursor in &mut cursors[..=pivot] {
+                            cursor.next_geq(next_candidate)?;
+                        }
+                        continue;
+                    }
+                }
 
-            // Combine docids and frequencies into postings
-            postings.extend(decoded_docids.into_iter().zip(frequencies.into_iter()));
+                if cursors[0].doc() == pivot_doc {
+                    // All cursors up to the pivot are on the pivot docid: score it fully, unless it was deleted
+                    let deleted = self.deleted.contains(pivot_doc);
+                    let mut score = 0.0f32;
+                    for cursor in &mut cursors[..=pivot] {
+                        if !deleted {
+                            score += self.score(scorer.as_ref(), cursor, pivot_doc)?;
+                        }
+                        cursor.next()?;
+                    }
+
+                    if !deleted && (!top_k.is_full() || score > top_k.threshold()) {
+                        top_k.collect(pivot_doc, score);
+                    }
+                } else {
+                    // Move the lagging cursors up to the pivot docid
+                    for cursor in &mut cursors[..pivot] {
+                        cursor.next_geq(pivot_doc)?;
+                    }
+                }
+            }
         }
-        Ok(postings)
-    }
 
-    pub fn query_term_postings_after_doc_k(&mut self, term: &str, k: u32) -> std::io::Result<Vec<(u32, u32)>> {
-        let term_metadata = self.query_term_metadata(term)?;
+        Ok(top_k.into_docs())
+    }
 
-        let mut postings = Vec::new();
-        let mut last_doc_id = 0;
+    // Number of live documents containing any of the terms, from the union of their docids. Unlike WAND it visits
+    // every posting, but only decodes the blocks and scores nothing.
+    fn count_matches(&self, query_terms: &[String]) -> std::io::Result<usize> {
+        let mut matches = 0;
+        for (segment_index, segment) in self.segments.iter().enumerate() {
+            let mut cursors = Vec::new();
+            for term in query_terms {
+                // Terms missing from the lexicon match nothing
+                let Ok(segment_metadata) = self.query_term_metadata(term) else { continue };
+                if let Some(metadata) = &segment_metadata[segment_index] {
+                    cursors.push(PostingCursor::new(segment.index(), metadata.clone(), self.config.block_size)?);
+                }
+            }
 
-        // Iterate through the blocks to find the starting block
-        for (i, &max_docid) in term_metadata.block_maxima.iter().enumerate() {
-            if max_docid < k {
-                if i > 0 {
-                    // Update last_doc_id with the maximum docid of the previous block
-                    last_doc_id = term_metadata.block_maxima[i - 1];
+            loop {
+                let doc_id = cursors.iter().map(PostingCursor::doc).min().unwrap_or(END_OF_POSTINGS);
+                if doc_id == END_OF_POSTINGS {
+                    break;
+                }
+                if !self.deleted.contains(doc_id) {
+                    matches += 1;
+                }
+                for cursor in cursors.iter_mut().filter(|cursor| cursor.doc() == doc_id) {
+                    cursor.next()?;
                 }
-                continue; // Skip blocks where max_docid is less than k
             }
+        }
+        Ok(matches)
+    }
 
-            // Start processing from this block
-            for (block_index, &offset) in term_metadata.block_offsets.iter().enumerate().skip(i) {
-                // Seek to the start of the block
-                self.index_file.seek(SeekFrom::Start(offset))?;
+    // Pass the documents containing the terms at consecutive positions, with their scores, to on_match in docid order
+    fn match_phrase(&self, query_terms: &[String], scorer: &dyn Scorer,
+                    on_match: &mut dyn FnMut(u32, f32)) -> std::io::Result<()> {
+        if !self.has_positions {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Index was built without positions"));
+        }
 
-                // Determine the number of docids in this block
-                let block_size = if block_index == term_metadata.compressed_docids_per_block.len() - 1 {
-                    term_metadata.num_posting_in_last_block as usize
-                } else {
-                    BLOCK_SIZE
-                };
+        // Every term of the phrase must exist, otherwise nothing can match
+        let mut term_cursors = Vec::with_capacity(query_terms.len());
+        for term in query_terms {
+            match self.posting_cursors(term, scorer) {
+                Ok(cursors) => term_cursors.push(cursors),
+                Err(_) => return Ok(()),
+            }
+        }
 
-                // Read and decompress docids for this block
-                let compressed_size = term_metadata.compressed_docids_per_block[block_index];
-                let mut compressed_docids = vec![0u8; compressed_size as usize];
-                self.index_file.read_exact(&mut compressed_docids)?;
+        self.intersect_segments(term_cursors, true, scorer, on_match)
+    }
 
-                let mut docids = vec![0u32; block_size];
-                decode::<Scalar>(&compressed_docids, block_size, &mut docids);
+    // Evaluate a query node into (docid, score) pairs ordered by docid.
+    // When candidates are given, only those documents are considered, so term cursors jump from
+    // candidate to candidate with next_geq instead of decompressing every block.
+    fn evaluate(&self, node: &QueryNode, candidates: Option<&[u32]>, scorer: &dyn Scorer) -> std::io::Result<Vec<(u32, f32)>> {
+        match node {
+            QueryNode::Term(term) => {
+                let cursors = match self.posting_cursors(term, scorer) {
+                    Ok(cursors) => cursors,
+                    Err(_) => return Ok(Vec::new()),
+                };
 
-                // Adjust the first docid in the block if necessary
-                if last_doc_id != 0 {
-                    docids[0] += last_doc_id;
+                // Segments are in docid order, so the matches of each one are appended in order
+                let mut matches = Vec::new();
+                for mut cursor in cursors.into_iter().flatten() {
+                    match candidates {
+                        Some(doc_ids) => {
+                            for &doc_id in doc_ids {
+                                cursor.next_geq(doc_id)?;
+                                if cursor.doc() == END_OF_POSTINGS {
+                                    break;
+                                }
+                                if cursor.doc() == doc_id && !self.deleted.contains(doc_id) {
+                                    matches.push((doc_id, self.score(scorer, &mut cursor, doc_id)?));
+                                }
+                            }
+                        },
+                        None => {
+                            while cursor.doc() != END_OF_POSTINGS {
+                                if !self.deleted.contains(cursor.doc()) {
+                                    let doc_id = cursor.doc();
+                                    matches.push((doc_id, self.score(scorer, &mut cursor, doc_id)?));
+                                }
+                                cursor.next()?;
+                            }
+                        },
+                    }
+                }
+                Ok(matches)
+            },
+            QueryNode::Phrase(terms) => {
+                if !self.has_positions {
+                    // Without positions the best we can do is to require all the terms
+                    let conjunction = QueryNode::And(terms.iter().cloned().map(QueryNode::Term).collect());
+                    return self.evaluate(&conjunction, candidates, scorer);
+                }
+                let mut matches = Vec::new();
+                self.match_phrase(terms, scorer, &mut |doc_id, score| matches.push((doc_id, score)))?;
+                Ok(match candidates {
+                    Some(doc_ids) => matches.into_iter()
+                        .filter(|(doc_id, _)| doc_ids.binary_search(doc_id).is_ok())
+                        .collect(),
+                    None => matches,
+                })
+            },
+            QueryNode::Required(child) => self.evaluate(child, candidates, scorer),
+            // A purely negative query matches nothing
+            QueryNode::Not(_) => Ok(Vec::new()),
+            QueryNode::And(children) => {
+                let (excluded, required): (Vec<&QueryNode>, Vec<&QueryNode>) = children.iter()
+                    .partition(|child| matches!(child, QueryNode::Not(_)));
+                let matches = self.evaluate_conjunction(&required, candidates, scorer)?;
+                self.exclude(matches, &excluded, scorer)
+            },
+            QueryNode::Or(children) => {
+                let mut required = Vec::new();
+                let mut optional = Vec::new();
+                let mut excluded = Vec::new();
+                for child in children {
+                    match child {
+                        QueryNode::Required(_) => required.push(child),
+                        QueryNode::Not(_) => excluded.push(child),
+                        _ => optional.push(child),
+                    }
                 }
 
-                // Perform delta decoding for the rest of the block
-                let decoded_docids = delta_decoding(&docids);
+                let mut matches = if required.is_empty() {
+                    // At least one of the optional clauses has to match
+                    let mut matches = Vec::new();
+                    for child in optional {
+                        let child_matches = self.evaluate(child, candidates, scorer)?;
+                        matches = union_scores(&matches, &child_matches);
+                    }
+                    matches
+                } else {
+                    // Required clauses decide the matches, optional ones only add to the score
+                    let mut matches = self.evaluate_conjunction(&required, candidates, scorer)?;
+                    for child in optional {
+                        if matches.is_empty() {
+                            break;
+                        }
+                        let doc_ids: Vec<u32> = matches.iter().map(|&(doc_id, _)| doc_id).collect();
+                        let child_matches = self.evaluate(child, Some(&doc_ids), scorer)?;
+                        add_scores(&mut matches, &child_matches);
+                    }
+                    matches
+                };
 
-                // Update last_doc_id for the next block, if there are decoded docids
-                if let Some(&last_decoded_docid) = decoded_docids.last() {
-                    last_doc_id = last_decoded_docid;
-                }
+                matches = self.exclude(matches, &excluded, scorer)?;
+                Ok(matches)
+            },
+        }
+    }
 
-                // Read frequencies for this block
-                let mut frequencies = vec![0u32; block_size];
-                for freq in &mut frequencies {
-                    *freq = self.index_file.read_u32::<LittleEndian>()?;
-                }
+    // Intersect the clauses, starting from the cheapest one and narrowing the candidates as we go
+    fn evaluate_conjunction(&self, clauses: &[&QueryNode], candidates: Option<&[u32]>,
+                            scorer: &dyn Scorer) -> std::io::Result<Vec<(u32, f32)>> {
+        if clauses.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut ordered: Vec<(u64, &QueryNode)> = clauses.iter()
+            .map(|&clause| (self.estimate_cost(clause), clause))
+            .collect();
+        ordered.sort_by_key(|&(cost, _)| cost);
 
-                // Combine docids and frequencies into postings
-                postings.extend(decoded_docids.into_iter().zip(frequencies.into_iter()).filter(|&(docid, _)| docid >= k));
+        let mut matches = self.evaluate(ordered[0].1, candidates, scorer)?;
+        for &(_, clause) in &ordered[1..] {
+            if matches.is_empty() {
+                break;
             }
-            break; // Break after processing the required blocks
+            let doc_ids: Vec<u32> = matches.iter().map(|&(doc_id, _)| doc_id).collect();
+            let clause_matches = self.evaluate(clause, Some(&doc_ids), scorer)?;
+            matches = intersect_scores(&matches, &clause_matches);
         }
 
-        Ok(postings)
+        Ok(matches)
     }
 
-    pub fn conjunctive_query(&mut self, query: &str) -> serde_json::Result<String> {
-        let query_terms = tokenize(query);
-        let mut term_postings_lengths = HashMap::new();
-        let mut valid_terms = Vec::new();
-        let mut results = Vec::new();
-
-        // Loop through each term in the query
-        for term in &query_terms {
-            match self.query_term_metadata(term) {
-                Ok(metadata) => {
-                    term_postings_lengths.insert(term, metadata.doc_freq);
-                    valid_terms.push(term);
-                },
-                Err(_) => {
-                    // Handle the case where the term is not found
-                    continue;
-                }
+    // Remove the documents matched by any of the negated clauses
+    fn exclude(&self, mut matches: Vec<(u32, f32)>, negated: &[&QueryNode],
+               scorer: &dyn Scorer) -> std::io::Result<Vec<(u32, f32)>> {
+        for &clause in negated {
+            if matches.is_empty() {
+                break;
             }
+            let inner = match clause {
+                QueryNode::Not(inner) => inner.as_ref(),
+                other => other,
+            };
+            let doc_ids: Vec<u32> = matches.iter().map(|&(doc_id, _)| doc_id).collect();
+            let excluded = self.evaluate(inner, Some(&doc_ids), scorer)?;
+            matches.retain(|(doc_id, _)| excluded.binary_search_by_key(doc_id, |&(d, _)| d).is_err());
         }
+        Ok(matches)
+    }
 
-        if valid_terms.is_empty() {
-            // Return early if no valid terms are found
-            return serde_json::to_string(&QueryResponse {
-                query: query.to_string(),
-                results,
-            });
+    // Upper bound of the number of documents a node can match, used to order intersections
+    fn estimate_cost(&self, node: &QueryNode) -> u64 {
+        match node {
+            QueryNode::Term(term) => self.doc_freq(term) as u64,
+            QueryNode::Phrase(terms) => terms.iter()
+                .map(|term| self.doc_freq(term) as u64)
+                .min()
+                .unwrap_or(0),
+            QueryNode::And(children) => children.iter()
+                .filter(|child| !matches!(child, QueryNode::Not(_)))
+                .map(|child| self.estimate_cost(child))
+                .min()
+                .unwrap_or(0),
+            QueryNode::Or(children) => children.iter()
+                .filter(|child| !matches!(child, QueryNode::Not(_)))
+                .map(|child| self.estimate_cost(child))
+                .sum(),
+            QueryNode::Required(child) => self.estimate_cost(child),
+            QueryNode::Not(_) => u64::MAX,
         }
+    }
 
-        // Find the term with the shortest postings list
-        let shortest_term = term_postings_lengths.iter().min_by_key(|&(_, v)| v).map(|(&k, _)| k).unwrap();
-        let mut shortest_postings = match self.query_term_all_postings(shortest_term) {
-            Ok(postings) => postings.iter()
-                .map(|&(doc_id, freq)| {
-                    let bm25_score = self.bm25(freq, term_postings_lengths[shortest_term], doc_id);
-                    (doc_id, bm25_score)
-                })
-                .collect::<Vec<(u32, f32)>>(),
-            Err(_) => {
-                // Handle error if postings cannot be retrieved
-                return serde_json::to_string(&QueryResponse {
-                    query: query.to_string(),
-                    results,
-                });
-            }
-        };
+    // Score of the document for the term of the cursor. A scorer weighing the fields gets the frequencies of the
+    // term in every field, see PostingCursor::field_freqs.
+    fn score(&self, scorer: &dyn Scorer, cursor: &mut PostingCursor, doc_id: u32) -> std::io::Result<f32> {
+        let doc_table = self.doc_table(doc_id);
+        let term = term_statistics(cursor.metadata());
+        let field_lengths = doc_table.and_then(|doc_table| doc_table.field_lengths(doc_id))
+            .filter(|_| scorer.weighs_fields());
+        if let Some(lengths) = field_lengths {
+            let tf = cursor.field_freqs()?;
+            return Ok(scorer.field_score(&tf, &lengths, &term, &self.statistics));
+        }
 
-        for term in valid_terms.iter().filter(|&t| t != &shortest_term) {
-            let mut intersected_postings = Vec::new();
-
-            for &(doc_id, mut score) in &shortest_postings {
-                match self.query_term_postings_after_doc_k(term, doc_id) {
-                    Ok(postings) => {
-                        if postings.iter().any(|&(post_doc_id, _)| post_doc_id == doc_id) {
-                            score += postings.iter()
-                                .find(|&&(post_doc_id, _)| post_doc_id == doc_id)
-                                .map(|&(_, post_freq)| self.bm25(post_freq, term_postings_lengths[term], doc_id))
-                                .unwrap_or(0.0);
-                            intersected_postings.push((doc_id, score));
-                        }
-                    },
-                    Err(_) => {
-                        println!("Error retrieving postings after doc {} for term '{}', skipping...", doc_id, term);
-                    }
-                }
-            }
+        let doc_len = doc_table.and_then(|doc_table| doc_table.length(doc_id)).unwrap_or(0);
+        Ok(scorer.score(cursor.freq(), doc_len, &term, &self.statistics))
+    }
 
-            shortest_postings = intersected_postings;
+    fn search_result(&self, doc_id: u32, score: f32, query_terms: &HashSet<String>) -> SearchResult {
+        let docno = self.doc_table(doc_id).and_then(|doc_table| doc_table.docno(doc_id)).unwrap_or_default();
+        let doc_url = self.url(doc_id).unwrap_or_default();
+
+        // The stored text starts with the URL line, the snippet is taken from what follows it
+        let text = self.document_text(doc_id).unwrap_or_else(|e| {
+            warn!("Error reading the text of document {}: {}", doc_id, e);
+            None
+        });
+        let snippet = text.map(|text| {
+            let body = text.split_once('\n').map_or("", |(_, body)| body);
+            // Detected on the whole text like when it was indexed, the store does not keep a declared language
+            let language = self.analyzer.language(&text, None);
+            snippet(body, query_terms, &self.analyzer, language)
+        });
+
+        SearchResult { doc_id, docno: docno.to_string(), doc_url: doc_url.to_string(), score, snippet }
+    }
+
+    // Text of the document from the store of its segment, None if the segment has no store
+    pub fn document_text(&self, doc_id: u32) -> std::io::Result<Option<String>> {
+        // The docid ranges of the segments increase, the document can only be in the last store starting before it
+        let store = self.segments.iter().rev()
+            .filter_map(Segment::doc_store)
+            .find(|store| store.first_doc_id().is_some_and(|first_doc_id| first_doc_id <= doc_id));
+        match store {
+            Some(store) => store.document(doc_id),
+            None => Ok(None),
         }
+    }
 
-        if !shortest_postings.is_empty() {
-            shortest_postings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
-            for (doc_id, score) in shortest_postings.iter().take(10) {
-                results.push(SearchResult {
-                    doc_id: *doc_id,
-                    doc_url: self.doc_url(*doc_id).to_owned(),
-                    score: *score,
-                });
-            }
+    // The live document with the docid, None if it was deleted or its text is not stored
+    pub fn get_document(&self, doc_id: u32) -> std::io::Result<Option<Document>> {
+        let Some(doc_table) = self.doc_table(doc_id) else { return Ok(None) };
+        if self.deleted.contains(doc_id) {
+            return Ok(None);
         }
+        let Some(text) = self.document_text(doc_id)? else { return Ok(None) };
+
+        let (_, title, body) = split_text_content(&text);
+        Ok(Some(Document {
+            doc_id,
+            docno: doc_table.docno(doc_id).unwrap_or_default().to_string(),
+            url: doc_table.url(doc_id).unwrap_or_default().to_string(),
+            title: title.to_string(),
+            body: body.to_string(),
+        }))
+    }
 
-        serde_json::to_string(&QueryResponse {
-            query: query.to_string(),
-            results,
+    // DOCNO of the document, None if the collection gave it none
+    pub fn docno(&self, doc_id: u32) -> Option<&str> {
+        self.doc_table(doc_id)
+            .and_then(|doc_table| doc_table.docno(doc_id))
+            .filter(|docno| !docno.is_empty())
+    }
+
+    pub fn url(&self, doc_id: u32) -> Option<&str> {
+        self.doc_table(doc_id).and_then(|doc_table| doc_table.url(doc_id))
+    }
+
+    // Doc table of the segment holding the document, None if no segment does
+    fn doc_table(&self, doc_id: u32) -> Option<&DocTable> {
+        self.segments.iter()
+            .map(Segment::doc_table)
+            .find(|doc_table| doc_table.contains(doc_id))
+    }
+
+    // Docid of the live document with the DOCNO, the latest copy if it was indexed more than once
+    pub fn doc_id(&self, docno: &str) -> Option<u32> {
+        self.segments.iter().rev()
+            .flat_map(|segment| segment.doc_table().doc_ids(docno).into_iter().rev())
+            .find(|&doc_id| !self.deleted.contains(doc_id))
+    }
+
+    pub fn lookup_docno(&self, docno: &str) -> serde_json::Result<String> {
+        let doc_id = self.doc_id(docno);
+        serde_json::to_string(&LookupResponse {
+            docno: docno.to_string(),
+            doc_id,
+            doc_url: doc_id.and_then(|doc_id| self.url(doc_id)).map(str::to_string),
         })
     }
+}
 
+// Collection-wide statistics of the term of a cursor, see collection_metadata
+fn term_statistics(metadata: &TermMetadata) -> TermStatistics {
+    TermStatistics { doc_freq: metadata.doc_freq, collection_freq: metadata.total_term_freq as u64 }
+}
 
-    pub fn disjunctive_query(&mut self, query: &str) -> serde_json::Result<String> {
-        let query_terms = tokenize(query);
-        let mut doc_scores: HashMap<u32, f32> = HashMap::new();
-        let mut results = Vec::new();
+// Whether the cursors, all on the same document, hold consecutive positions in their order
+fn is_phrase_match(cursors: &mut [PostingCursor]) -> std::io::Result<bool> {
+    let mut positions = Vec::with_capacity(cursors.len());
+    for cursor in cursors.iter_mut() {
+        positions.push(cursor.positions()?.to_vec());
+    }
 
-        // Retrieve postings lists for each term and calculate scores
-        for term in query_terms {
-            match self.query_term_metadata(&term) {
-                Ok(metadata) => {
-                    if let Ok(postings) = self.query_term_all_postings(&term) {
-                        for (doc_id, freq) in postings {
-                            let bm25_score = self.bm25(freq, metadata.doc_freq, doc_id);
-                            doc_scores.entry(doc_id).and_modify(|e| *e += bm25_score).or_insert(bm25_score);
-                        }
-                    }
-                }
-                // Continue on error, you may choose to handle it differently
-                _ => continue,
-            }
+    // The i-th following term must appear exactly i positions after the first one
+    let (first_positions, rest) = positions.split_first().unwrap();
+    Ok(first_positions.iter().any(|&start| {
+        rest.iter().enumerate()
+            .all(|(i, term_positions)| term_positions.binary_search(&(start + i as u32 + 1)).is_ok())
+    }))
+}
+
+// Documents present in both lists, with summed scores
+fn intersect_scores(a: &[(u32, f32)], b: &[(u32, f32)]) -> Vec<(u32, f32)> {
+    let mut result = Vec::with_capacity(a.len().min(b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].0.cmp(&b[j].0) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                result.push((a[i].0, a[i].1 + b[j].1));
+                i += 1;
+                j += 1;
+            },
         }
+    }
+    result
+}
 
-        // Sort and output results
-        let mut sorted_docs: Vec<_> = doc_scores.into_iter().collect();
-        sorted_docs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+// Documents present in either list, with summed scores
+fn union_scores(a: &[(u32, f32)], b: &[(u32, f32)]) -> Vec<(u32, f32)> {
+    let mut result = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i].0 < b[j].0) {
+            result.push(a[i]);
+            i += 1;
+        } else if i == a.len() || b[j].0 < a[i].0 {
+            result.push(b[j]);
+            j += 1;
+        } else {
+            result.push((a[i].0, a[i].1 + b[j].1));
+            i += 1;
+            j += 1;
+        }
+    }
+    result
+}
 
-        for (doc_id, score) in sorted_docs.iter().take(10) {
-            results.push(SearchResult {
-                doc_id: *doc_id,
-                doc_url: self.doc_url(*doc_id).to_owned(),
-                score: *score,
-            });
+// Add the scores of the extra matches to the documents already in the base list
+fn add_scores(base: &mut [(u32, f32)], extra: &[(u32, f32)]) {
+    for &(doc_id, score) in extra {
+        if let Ok(i) = base.binary_search_by_key(&doc_id, |&(d, _)| d) {
+            base[i].1 += score;
         }
+    }
+}
 
-        serde_json::to_string(&QueryResponse {
-            query: query.to_string(),
-            results,
-        })
+// The k best of the documents collected, in a heap that never holds more than k + 1 of them
+struct TopK {
+    k: usize,
+    heap: BinaryHeap<Reverse<ScoredDoc>>,
+    // Number of documents collected
+    matches: usize,
+}
+
+impl TopK {
+    fn new(k: usize) -> Self {
+        Self { k, heap: BinaryHeap::with_capacity(k + 1), matches: 0 }
+    }
+
+    fn collect(&mut self, doc_id: u32, score: f32) {
+        self.matches += 1;
+        self.heap.push(Reverse(ScoredDoc { score, doc_id }));
+        if self.heap.len() > self.k {
+            self.heap.pop();
+        }
     }
 
-    pub fn bm25(&mut self, tf: u32, df: u32, doc_id: u32) -> f32 {
+    fn is_full(&self) -> bool {
+        self.heap.len() >= self.k
+    }
 
-        let k1: f32 = BM25_K1;
-        let b: f32 = BM25_B;
-        let doc_len = self.doc_metadata.get(&doc_id).unwrap().1;
-        let idf = ((self.total_docs as f32 - df as f32 + 0.5) / (df as f32 + 0.5)).ln() + 1.0;
-        let term_freq_component = (tf as f32) * (k1 + 1.0);
-        let denominator = tf as f32 + k1 * (1.0 - b + b * (doc_len as f32 / self.avg_doc_len as f32));
+    // Score of the k-th best document, 0 until k documents were collected
+    fn threshold(&self) -> f32 {
+        match self.heap.peek() {
+            Some(Reverse(doc)) if self.is_full() => doc.score,
+            _ => 0.0,
+        }
+    }
 
-        idf * (term_freq_component / denominator)
+    // The documents, best first
+    fn into_docs(self) -> Vec<(u32, f32)> {
+        self.heap.into_sorted_vec().into_iter()
+            .map(|Reverse(doc)| (doc.doc_id, doc.score))
+            .collect()
     }
+}
 
-    pub fn doc_url(&self, doc_id: u32) -> &String {
-        static DEFAULT_URL: String = String::new();
+// A document and its score, ordered by score so that it can be kept in a heap
+#[derive(Clone, Copy, Debug)]
+struct ScoredDoc {
+    score: f32,
+    doc_id: u32,
+}
 
-        self.doc_metadata.get(&doc_id).map(|(url, _)| url).unwrap_or(&DEFAULT_URL)
+impl PartialEq for ScoredDoc {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
     }
+}
+
+impl Eq for ScoredDoc {}
 
+impl Ord for ScoredDoc {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // Among equal scores the smaller docid ranks higher
+        self.score.total_cmp(&other.score).then_with(|| other.doc_id.cmp(&self.doc_id))
+    }
+}
 
+impl PartialOrd for ScoredDoc {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
-fn delta_decoding(encoded_docids: &[u32]) -> Vec<u32> {
-    let mut decoded_docids = Vec::with_capacity(encoded_docids.len());
-    let mut last_doc_id = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::Path;
+    use crate::sources::{open_source, InputFormat, JsonlFields};
+
+    const WORDS: [&str; 24] = ["apple", "bridge", "cable", "delta", "engine", "forest", "garden", "harbor", "island",
+        "jungle", "kettle", "lemon", "marble", "needle", "orbit", "pepper", "quartz", "river", "saddle", "timber",
+        "umbrella", "valley", "walnut", "zephyr"];
+
+    // TREC documents D0, D1... with words drawn from a skewed distribution, so that the postings lists range from
+    // a few documents to nearly all of them
+    fn documents(count: usize) -> Vec<String> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut random = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 11) as f64 / (1u64 << 53) as f64
+        };
+        let mut word = || WORDS[(WORDS.len() as f64 * random().powi(3)) as usize];
+
+        (0..count).map(|i| {
+            let title = format!("{} {}", word(), word());
+            let length = 5 + (i * 37) % 60;
+            let body: Vec<_> = (0..length).map(|_| word()).collect();
+            format!("<DOC>\n<DOCNO>D{}</DOCNO>\n<LANG>en</LANG>\n<TEXT>\nhttp://example.com/{}\n{}\n{}\n</TEXT>\n</DOC>\n",
+                    i, word(), title, body.join(" "))
+        }).collect()
+    }
+
+    // Small blocks, for Block-Max WAND to skip many of them
+    fn config() -> IndexConfig {
+        IndexConfig { block_size: 8, threads: Some(1), ..IndexConfig::default() }
+    }
 
-    for &encoded_docid in encoded_docids {
-        let docid = if last_doc_id == 0 { // The first docid is not a delta
-            encoded_docid
+    // Index the documents as a new index in the directory, or as a segment added to it
+    fn index_documents(dir: &Path, documents: &[String], add: bool) -> IndexPaths {
+        let input = dir.join("collection.trec");
+        fs::write(&input, documents.concat()).unwrap();
+        let paths = IndexPaths::new(&dir.join("index"));
+        let fields = JsonlFields { id: "id".to_string(), text: "contents".to_string(), title: None, url: None };
+        let source = open_source(&input, InputFormat::Trec, &fields).unwrap();
+        if add {
+            crate::add_segment(source, &paths, &config()).unwrap();
         } else {
-            last_doc_id + encoded_docid
+            crate::build_index(source, &paths, &config()).unwrap();
+        }
+        paths
+    }
+
+    // An index of 600 documents, every seventh of them deleted
+    fn build_index(dir: &Path) -> (IndexPaths, IndexConfig) {
+        let paths = index_documents(dir, &documents(600), false);
+        let mut tombstones = Tombstones::load(&paths.deleted_docs()).unwrap();
+        for doc_id in (0..600).step_by(7) {
+            tombstones.insert(doc_id);
+        }
+        tombstones.save(&paths.deleted_docs()).unwrap();
+        (paths, config())
+    }
+
+    const QUERIES: [(QueryMode, &str); 5] = [(QueryMode::And, "apple bridge"), (QueryMode::Or, "zephyr walnut umbrella"),
+        (QueryMode::Phrase, "apple apple"), (QueryMode::Search, "(apple OR cable) AND NOT bridge"),
+        (QueryMode::Search, "title:apple zephyr")];
+
+    // Match count and (DOCNO, score) of every match of each of the queries
+    fn results(processor: &TermQueryProcessor) -> Vec<(usize, Vec<(String, f32)>)> {
+        QUERIES.iter()
+            .map(|&(mode, query)| {
+                let top_docs = processor.top_k(mode, query, 1000, None, Some(Language::English)).unwrap();
+                let docs = top_docs.docs.into_iter()
+                    .map(|(doc_id, score)| (processor.doc_table(doc_id).unwrap().docno(doc_id).unwrap().to_string(), score))
+                    .collect();
+                (top_docs.total_matches, docs)
+            })
+            .collect()
+    }
+
+    // The same documents in the same order, with the same scores up to the rounding errors
+    fn assert_same_results(actual: &[(usize, Vec<(String, f32)>)], expected: &[(usize, Vec<(String, f32)>)]) {
+        for (((total, docs), (expected_total, expected_docs)), (_, query)) in actual.iter().zip(expected).zip(QUERIES) {
+            assert!(!expected_docs.is_empty(), "{} matches nothing", query);
+            assert_eq!(total, expected_total, "{}", query);
+            let docnos: Vec<_> = docs.iter().map(|(docno, _)| docno).collect();
+            let expected_docnos: Vec<_> = expected_docs.iter().map(|(docno, _)| docno).collect();
+            assert_eq!(docnos, expected_docnos, "{}", query);
+            for ((_, score), (docno, expected_score)) in docs.iter().zip(expected_docs) {
+                assert!((score - expected_score).abs() <= 1e-4 * expected_score.abs().max(1.0), "{} {}", query, docno);
+            }
+        }
+    }
+
+    #[test]
+    fn added_segments_are_searched_like_one_index() {
+        let documents = documents(600);
+        let one = tempfile::tempdir().unwrap();
+        let paths = index_documents(one.path(), &documents, false);
+        let expected = results(&TermQueryProcessor::new(&paths, &config()));
+
+        let two = tempfile::tempdir().unwrap();
+        index_documents(two.path(), &documents[..250], false);
+        let paths = index_documents(two.path(), &documents[250..], true);
+        assert_eq!(paths.segments().unwrap().len(), 2);
+        assert_same_results(&results(&TermQueryProcessor::new(&paths, &config())), &expected);
+    }
+
+    #[test]
+    fn deleted_documents_are_dropped_by_compaction() {
+        let documents = documents(600);
+        let dir = tempfile::tempdir().unwrap();
+        index_documents(dir.path(), &documents[..250], false);
+        let paths = index_documents(dir.path(), &documents[250..], true);
+        let undeleted = results(&TermQueryProcessor::new(&paths, &config()));
+
+        // By docid and by DOCNO, in both segments
+        crate::delete_documents(&paths, &[0, 5, 300], &["D17".to_string(), "D451".to_string()]).unwrap();
+        let deleted = ["D0", "D5", "D300", "D17", "D451"];
+        let before = results(&TermQueryProcessor::new(&paths, &config()));
+        // The deleted documents still count in the statistics, the other ones keep their scores
+        let mut dropped = 0;
+        for ((total, docs), (_, undeleted_docs)) in before.iter().zip(&undeleted) {
+            let expected: Vec<_> = undeleted_docs.iter().filter(|(docno, _)| !deleted.contains(&docno.as_str())).cloned().collect();
+            assert_eq!(docs, &expected);
+            assert_eq!(*total, docs.len());
+            dropped += undeleted_docs.len() - docs.len();
+        }
+        assert!(dropped > 0);
+
+        crate::compact_index(&paths, &config()).unwrap();
+        assert_eq!(paths.segments().unwrap().len(), 1);
+        assert!(!paths.compaction().dir().exists());
+        assert_eq!(crate::verify::verify_index(&paths, &config()).unwrap().problems, 0);
+
+        // The same matches, now scored without the deleted documents like in an index that never had them
+        let after = results(&TermQueryProcessor::new(&paths, &config()));
+        for ((total, docs), (total_before, docs_before)) in after.iter().zip(&before) {
+            assert_eq!(total, total_before);
+            let mut docnos: Vec<_> = docs.iter().map(|(docno, _)| docno).collect();
+            let mut docnos_before: Vec<_> = docs_before.iter().map(|(docno, _)| docno).collect();
+            docnos.sort();
+            docnos_before.sort();
+            assert_eq!(docnos, docnos_before);
+        }
+        let live: Vec<String> = documents.iter().enumerate()
+            .filter(|(i, _)| !deleted.contains(&format!("D{}", i).as_str()))
+            .map(|(_, document)| document.clone())
+            .collect();
+        let fresh = tempfile::tempdir().unwrap();
+        let fresh_paths = index_documents(fresh.path(), &live, false);
+        assert_same_results(&after, &results(&TermQueryProcessor::new(&fresh_paths, &config())));
+    }
+
+    #[test]
+    fn top_k_keeps_the_best_documents_smaller_docids_first_between_ties() {
+        let mut top_k = TopK::new(3);
+        assert!(!top_k.is_full());
+        for (doc_id, score) in [(1, 0.5), (2, 2.0), (3, 1.0)] {
+            top_k.collect(doc_id, score);
+        }
+        assert!(top_k.is_full());
+        assert_eq!(top_k.threshold(), 0.5);
+        for (doc_id, score) in [(4, 1.0), (5, 3.0), (6, 0.1), (7, 1.0)] {
+            top_k.collect(doc_id, score);
+        }
+        assert_eq!(top_k.heap.len(), 3);
+        assert_eq!(top_k.threshold(), 1.0);
+        assert_eq!(top_k.matches, 7);
+        assert_eq!(top_k.into_docs(), [(5, 3.0), (2, 2.0), (3, 1.0)]);
+
+        // Only counting
+        let mut top_k = TopK::new(0);
+        top_k.collect(1, 1.0);
+        assert_eq!(top_k.matches, 1);
+        assert!(top_k.into_docs().is_empty());
+    }
+
+    #[test]
+    fn pages_split_the_ranking() {
+        let dir = tempfile::tempdir().unwrap();
+        let (paths, config) = build_index(dir.path());
+        let processor = TermQueryProcessor::new(&paths, &config);
+        let page = |mode, offset, k| -> QueryResponse {
+            serde_json::from_str(&processor.query(mode, "apple bridge", None, Some(Language::English), offset, k).unwrap()).unwrap()
         };
-        decoded_docids.push(docid);
-        last_doc_id = docid;
+
+        for mode in [QueryMode::And, QueryMode::Or, QueryMode::Search] {
+            let ranking = processor.top_k(mode, "apple bridge", 1000, None, Some(Language::English)).unwrap();
+            assert!(ranking.docs.len() > 50);
+            let mut paged = Vec::new();
+            for offset in (0..50).step_by(7) {
+                let response = page(mode, offset, 7);
+                assert_eq!(response.offset, offset);
+                assert_eq!(response.total_matches, ranking.total_matches);
+                paged.extend(response.results.into_iter().map(|result| (result.doc_id, result.score)));
+            }
+            assert_eq!(paged, ranking.docs[..56]);
+
+            // A last page cut short, then past the last match
+            let response = page(mode, ranking.docs.len() - 2, 7);
+            assert_eq!(response.results.len(), 2);
+            let response = page(mode, ranking.docs.len(), 7);
+            assert!(response.results.is_empty());
+            assert_eq!(response.total_matches, ranking.total_matches);
+        }
+
+        // The depth is capped, an unbounded page neither overflows nor allocates a heap of its size
+        let ranking = processor.top_k(QueryMode::And, "zephyr walnut", 1000, None, Some(Language::English)).unwrap();
+        assert!(!ranking.docs.is_empty());
+        let response: QueryResponse = serde_json::from_str(&processor.query(QueryMode::And, "zephyr walnut", None,
+                                                                            Some(Language::English), 0, usize::MAX).unwrap()).unwrap();
+        assert_eq!(response.results.len(), ranking.docs.len());
+        assert!(page(QueryMode::And, usize::MAX, usize::MAX).results.is_empty());
+        assert!(page(QueryMode::And, MAX_RESULT_DEPTH, 1).results.is_empty());
     }
 
-    decoded_docids
+    // Score of every live document matching any of the terms, from all of their postings
+    fn exhaustive_scores(processor: &TermQueryProcessor, terms: &[String], scoring: ScoringFunction) -> HashMap<u32, f32> {
+        let scorer = scoring.scorer(&processor.config);
+        let mut scores = HashMap::new();
+        // Terms missing from the lexicon match nothing
+        for cursors in terms.iter().filter_map(|term| processor.posting_cursors(term, scorer.as_ref()).ok()) {
+            for mut cursor in cursors.into_iter().flatten() {
+                while cursor.doc() != END_OF_POSTINGS {
+                    let doc_id = cursor.doc();
+                    if !processor.deleted.contains(doc_id) {
+                        *scores.entry(doc_id).or_insert(0.0) += processor.score(scorer.as_ref(), &mut cursor, doc_id).unwrap();
+                    }
+                    cursor.next().unwrap();
+                }
+            }
+        }
+        scores
+    }
+
+    // Analyzed terms of the words of the query, the field terms of the ones with a field prefix
+    fn query_terms(processor: &TermQueryProcessor, query: &str) -> Vec<String> {
+        query.split_whitespace()
+            .flat_map(|word| {
+                let (field, word) = match word.split_once(':') {
+                    Some((name, word)) => (Field::from_name(name), word),
+                    None => (None, word),
+                };
+                processor.analyzer.analyze(word, Some(Language::English)).into_iter()
+                    .map(move |term| field.map_or(term.clone(), |field| field.term(&term)))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn wand_returns_the_exhaustive_top_k() {
+        let dir = tempfile::tempdir().unwrap();
+        let (paths, config) = build_index(dir.path());
+        let processor = TermQueryProcessor::new(&paths, &config);
+
+        let queries = ["apple bridge", "zephyr walnut umbrella", "apple zephyr", "cable delta engine forest garden",
+                       "title:apple bridge", "valley unknown", "harbor harbor island"];
+        // The stored BM25F bounds, and the looser ones of the other scoring functions
+        for scoring in [ScoringFunction::Bm25F, ScoringFunction::Bm25, ScoringFunction::TfIdf, ScoringFunction::Dirichlet] {
+            for query in queries {
+                let terms = query_terms(&processor, query);
+                let mut expected: Vec<(u32, f32)> = exhaustive_scores(&processor, &terms, scoring).into_iter().collect();
+                expected.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+                let scores: HashMap<u32, f32> = expected.iter().copied().collect();
+                assert!(!expected.is_empty(), "{} matches nothing", query);
+                assert_eq!(processor.count_matches(&terms).unwrap(), expected.len(), "{}", query);
+
+                for k in [1, 5, 20, 1000] {
+                    for block_max in [true, false] {
+                        let docs = processor.wand_top_k(&terms, k, block_max, scoring).unwrap();
+                        let context = format!("{:?} {} k={} block_max={}", scoring, query, k, block_max);
+                        assert_eq!(docs.len(), k.min(expected.len()), "{}", context);
+                        // The same scores at every rank, the documents may only differ between ties
+                        for (rank, &(doc_id, score)) in docs.iter().enumerate() {
+                            let tolerance = 1e-4 * score.abs().max(1.0);
+                            assert!((score - scores[&doc_id]).abs() <= tolerance, "{} doc {}", context, doc_id);
+                            assert!((score - expected[rank].1).abs() <= tolerance, "{} rank {}", context, rank);
+                        }
+                    }
+                }
+            }
+        }
+    }
 }
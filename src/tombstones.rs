@@ -0,0 +1,59 @@
+use std::fs;
+use std::io;
+
+// Docids of deleted documents, kept as a bitset next to the index. Their postings stay in the index
+// files, the query processor skips them until a compaction rewrites the index without them.
+#[derive(Default)]
+pub struct Tombstones {
+    words: Vec<u64>,
+}
+
+impl Tombstones {
+    // An index without deletions has no tombstones file
+    pub fn load(path: &str) -> io::Result<Self> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+
+        let words = bytes.chunks(8)
+            .map(|chunk| {
+                let mut word = [0u8; 8];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u64::from_le_bytes(word)
+            })
+            .collect();
+        Ok(Self { words })
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let bytes: Vec<u8> = self.words.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        // Replace the previous file at once, a server loading it never sees it half written
+        let temporary_path = format!("{}.tmp", path);
+        fs::write(&temporary_path, bytes)?;
+        fs::rename(temporary_path, path)
+    }
+
+    // Returns false if the document was already deleted
+    pub fn insert(&mut self, doc_id: u32) -> bool {
+        let (word, bit) = (doc_id as usize / 64, doc_id % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+
+        let already_deleted = self.words[word] & (1 << bit) != 0;
+        self.words[word] |= 1 << bit;
+        !already_deleted
+    }
+
+    pub fn contains(&self, doc_id: u32) -> bool {
+        self.words.get(doc_id as usize / 64)
+            .is_some_and(|word| word & (1 << (doc_id % 64)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+}
@@ -1,9 +1,27 @@
-pub const DEBUG_MODE: bool = true;
-pub const DEBUG_DOC_LIMIT: usize = 100000;
-pub const BATCH_SIZE: usize = DEBUG_DOC_LIMIT / 1000;
+// Defaults of the IndexConfig, see config.rs
+pub const BATCH_SIZE: usize = 10000;
 pub const BLOCK_SIZE: usize = 64;
 pub const DIRECTORY_NTH_TERM: u32 = 100;
+pub const STORE_POSITIONS: bool = true;
+pub const STORE_DOCUMENTS: bool = true;
+pub const BLOCK_MAX_WAND: bool = true;
 
 pub const BM25_K1: f32 = 1.2;
 
-pub const BM25_B: f32 = 0.75;
\ No newline at end of file
+pub const BM25_B: f32 = 0.75;
+
+// Parameters of the other scoring functions, see scoring.rs
+pub const BM25_PLUS_DELTA: f32 = 1.0;
+pub const BM25L_DELTA: f32 = 0.5;
+pub const DIRICHLET_MU: f32 = 2000.0;
+pub const JELINEK_MERCER_LAMBDA: f32 = 0.1;
+pub const PL2_C: f32 = 1.0;
+// BM25F weights of the URL, title, heading and body matches, and length normalisation of the URL, title and
+// heading ones, the body ones use BM25_B
+pub const BM25F_URL_WEIGHT: f32 = 1.5;
+pub const BM25F_TITLE_WEIGHT: f32 = 3.0;
+pub const BM25F_HEADING_WEIGHT: f32 = 2.0;
+pub const BM25F_BODY_WEIGHT: f32 = 1.0;
+pub const BM25F_URL_B: f32 = 0.5;
+pub const BM25F_TITLE_B: f32 = 0.5;
+pub const BM25F_HEADING_B: f32 = 0.5;
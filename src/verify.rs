@@ -0,0 +1,447 @@
+use std::fs::File;
+use std::io;
+use byteorder::{ByteOrder, LittleEndian};
+use memmap2::Mmap;
+use stream_vbyte::decode::decode;
+use stream_vbyte::scalar::Scalar;
+use crate::bin_indexer::{TermMetadata, load_directory, read_term_metadata, LEXICON_HAS_POSITIONS, LEXICON_OFFSET_TABLE_POSITION};
+use crate::config::{IndexConfig, IndexPaths};
+use crate::doc_table::DocTable;
+
+// Size of the lexicon header, (total_terms, has_positions, offset table position)
+const LEXICON_HEADER: usize = 16;
+// Bytes of a lexicon entry before its per block arrays: term length, term_id, doc_freq, total_term_freq,
+// term_start_pointer, num_blocks, num_posting_in_last_block, last_doc_id and max_score, the term excluded
+const TERM_METADATA_BYTES: usize = 4 + 4 + 4 + 4 + 8 + 4 + 4 + 4 + 4;
+// Bytes of the per block arrays of a lexicon entry: docids size, offset, maximum and maximum score
+const BLOCK_METADATA_BYTES: usize = 8 + 8 + 4 + 4;
+
+// Totals of a verification, and the number of inconsistencies found
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub segments: usize,
+    pub terms: u64,
+    pub postings: u64,
+    pub problems: usize,
+}
+
+// Check the binary index of every segment against itself and the doc table: the directory entries are sorted and
+// point to the lexicon entries of their terms, the lexicon is sorted, and the blocks of every term decode to
+// strictly increasing docids of documents in the doc table, matching doc_freq, last_doc_id and block_maxima.
+// Every inconsistency is printed, a corrupt block does not stop the check of the other blocks and terms.
+pub fn verify_index(paths: &IndexPaths, config: &IndexConfig) -> io::Result<Verification> {
+    let mut verification = Verification::default();
+    for segment_paths in paths.segments()? {
+        let mut check = SegmentCheck {
+            name: segment_paths.dir().display().to_string(),
+            block_size: config.block_size,
+            verification: &mut verification,
+        };
+        check.segment(&segment_paths);
+        verification.segments += 1;
+    }
+    Ok(verification)
+}
+
+struct SegmentCheck<'a> {
+    // Directory of the segment, the start of every report
+    name: String,
+    block_size: usize,
+    verification: &'a mut Verification,
+}
+
+impl SegmentCheck<'_> {
+    fn report(&mut self, problem: String) {
+        println!("{}: {}", self.name, problem);
+        self.verification.problems += 1;
+    }
+
+    fn segment(&mut self, paths: &IndexPaths) {
+        let doc_table = match DocTable::open(paths) {
+            Ok(doc_table) => Some(doc_table),
+            Err(e) => {
+                self.report(format!("cannot open the doc table, docids are not checked against it: {}", e));
+                None
+            },
+        };
+        // SAFETY: the index files are only read, verify is not meant to run during a build or compaction
+        let lexicon = match File::open(paths.lexicon()).and_then(|file| unsafe { Mmap::map(&file) }) {
+            Ok(lexicon) => lexicon,
+            Err(e) => return self.report(format!("cannot open the lexicon: {}", e)),
+        };
+        let index = match File::open(paths.index()).and_then(|file| unsafe { Mmap::map(&file) }) {
+            Ok(index) => index,
+            Err(e) => return self.report(format!("cannot open the binary index: {}", e)),
+        };
+
+        let Some(lexicon_offsets) = self.lexicon_offsets(&lexicon) else { return };
+        let has_positions = LittleEndian::read_u32(&lexicon[LEXICON_HAS_POSITIONS as usize..]) != 0;
+        let entries_end = LittleEndian::read_u64(&lexicon[LEXICON_OFFSET_TABLE_POSITION as usize..]) as usize;
+        self.directory(paths, &lexicon, &lexicon_offsets);
+
+        let mut previous_term: Option<String> = None;
+        for (term_index, &offset) in lexicon_offsets.iter().enumerate() {
+            // The entry ends where the next one starts, the last one at the offset table
+            let entry_end = lexicon_offsets.get(term_index + 1).map_or(entries_end, |&next| next as usize);
+            let Some((term, metadata)) = self.lexicon_entry(term_index, &lexicon, offset as usize, entry_end, has_positions)
+                else { continue };
+
+            if let Some(previous) = &previous_term {
+                if previous.as_bytes() >= term.as_bytes() {
+                    self.report(format!("lexicon term {} {:?} does not sort after {:?}", term_index, term, previous));
+                }
+            }
+            if metadata.term_id != term_index as u32 {
+                self.report(format!("term {:?} has term_id {} at lexicon index {}", term, metadata.term_id, term_index));
+            }
+            self.postings(&term, &metadata, &index, has_positions, doc_table.as_ref());
+            self.verification.terms += 1;
+            previous_term = Some(term);
+        }
+    }
+
+    // Lexicon offsets of the terms from the offset table, None if the header or the table cannot be read
+    fn lexicon_offsets(&mut self, lexicon: &[u8]) -> Option<Vec<u64>> {
+        if lexicon.len() < LEXICON_HEADER {
+            self.report(format!("lexicon of {} bytes is shorter than its header", lexicon.len()));
+            return None;
+        }
+        let total_terms = LittleEndian::read_u32(&lexicon[0..]) as usize;
+        let has_positions = LittleEndian::read_u32(&lexicon[LEXICON_HAS_POSITIONS as usize..]);
+        let table_position = LittleEndian::read_u64(&lexicon[LEXICON_OFFSET_TABLE_POSITION as usize..]);
+        if has_positions > 1 {
+            self.report(format!("lexicon header has_positions is {}, not 0 or 1", has_positions));
+        }
+
+        let table_end = (table_position as usize).checked_add(8 * total_terms);
+        if table_position < LEXICON_HEADER as u64 || table_end.is_none_or(|end| end > lexicon.len()) {
+            self.report(format!("offset table of {} terms at {} does not fit in the lexicon of {} bytes",
+                                total_terms, table_position, lexicon.len()));
+            return None;
+        }
+        let table_position = table_position as usize;
+        if table_end != Some(lexicon.len()) {
+            self.report(format!("lexicon has {} bytes after its offset table", lexicon.len() - table_position - 8 * total_terms));
+        }
+
+        let offsets: Vec<u64> = lexicon[table_position..table_position + 8 * total_terms]
+            .chunks_exact(8)
+            .map(LittleEndian::read_u64)
+            .collect();
+        // The entries after a bad offset cannot be told apart, the segment is not checked further
+        for (term_index, pair) in offsets.windows(2).enumerate() {
+            if pair[1] <= pair[0] {
+                self.report(format!("lexicon offset {} of term {} does not follow the offset of the previous term",
+                                    pair[1], term_index + 1));
+                return None;
+            }
+        }
+        if let Some((term_index, offset)) = offsets.iter().enumerate()
+            .find(|&(_, &offset)| offset < LEXICON_HEADER as u64 || offset >= table_position as u64) {
+            self.report(format!("lexicon offset {} of term {} is outside of the entries", offset, term_index));
+            return None;
+        }
+        if let Some(&first) = offsets.first() {
+            if first != LEXICON_HEADER as u64 {
+                self.report(format!("first lexicon entry at {} instead of right after the header", first));
+            }
+        }
+        Some(offsets)
+    }
+
+    // The directory holds every stride-th term of the lexicon, sorted, with the offset of its entry
+    fn directory(&mut self, paths: &IndexPaths, lexicon: &[u8], lexicon_offsets: &[u64]) {
+        let (stride, directory) = match load_directory(&paths.directory()) {
+            Ok(directory) => directory,
+            Err(e) => return self.report(format!("cannot read the directory: {}", e)),
+        };
+        if stride == 0 {
+            return self.report("directory stride is 0".to_string());
+        }
+
+        let expected_entries = lexicon_offsets.len().div_ceil(stride as usize);
+        if directory.len() != expected_entries {
+            self.report(format!("directory has {} entries, {} terms with a stride of {} need {}",
+                                directory.len(), lexicon_offsets.len(), stride, expected_entries));
+        }
+        for (entry, (term, offset)) in directory.iter().enumerate() {
+            if entry > 0 && directory[entry - 1].0.as_bytes() >= term.as_bytes() {
+                self.report(format!("directory entry {} {:?} does not sort after {:?}", entry, term, directory[entry - 1].0));
+            }
+            let term_index = entry * stride as usize;
+            let Some(&lexicon_offset) = lexicon_offsets.get(term_index) else {
+                self.report(format!("directory entry {} {:?} is past the {} lexicon terms", entry, term, lexicon_offsets.len()));
+                continue;
+            };
+            if *offset != lexicon_offset {
+                self.report(format!("directory entry {} {:?} points to lexicon offset {} instead of {}, the entry of term {}",
+                                    entry, term, offset, lexicon_offset, term_index));
+            } else if entry_term(lexicon, lexicon_offset as usize) != Some(term.as_bytes()) {
+                self.report(format!("directory entry {} {:?} points to the lexicon entry of another term", entry, term));
+            }
+        }
+    }
+
+    // Term and metadata of the lexicon entry between the offsets
+    fn lexicon_entry(&mut self, term_index: usize, lexicon: &[u8], offset: usize, end: usize,
+                     has_positions: bool) -> Option<(String, TermMetadata)> {
+        let entry = &lexicon[offset..end];
+        // The block arrays are allocated from num_blocks, which must fit in the entry before it is read
+        let term_length = entry.get(..4).map_or(0, LittleEndian::read_u32) as usize;
+        let num_blocks_position = 4 + term_length + 4 * 3 + 8;
+        let block_bytes = BLOCK_METADATA_BYTES + if has_positions { 8 } else { 0 };
+        let fits = entry.get(num_blocks_position..num_blocks_position + 4)
+            .map(|num_blocks| LittleEndian::read_u32(num_blocks) as usize)
+            .and_then(|num_blocks| num_blocks.checked_mul(block_bytes))
+            .and_then(|bytes| bytes.checked_add(TERM_METADATA_BYTES + term_length))
+            .is_some_and(|length| length <= entry.len());
+        if !fits {
+            self.report(format!("lexicon entry {} at {} does not fit in its {} bytes", term_index, offset, entry.len()));
+            return None;
+        }
+
+        let mut reader = entry;
+        match read_term_metadata(&mut reader, has_positions) {
+            Ok((term, metadata)) => {
+                if !reader.is_empty() {
+                    self.report(format!("lexicon entry of {:?} is followed by {} unread bytes", term, reader.len()));
+                }
+                Some((term, metadata))
+            },
+            Err(e) => {
+                self.report(format!("cannot read lexicon entry {} at {}: {}", term_index, offset, e));
+                None
+            },
+        }
+    }
+
+    // Decode every block of the term as a posting cursor would and compare it with the metadata
+    fn postings(&mut self, term: &str, metadata: &TermMetadata, index: &[u8], has_positions: bool,
+                doc_table: Option<&DocTable>) {
+        let num_blocks = metadata.num_blocks as usize;
+        let doc_freq = metadata.doc_freq as usize;
+        if doc_freq == 0 || num_blocks == 0 {
+            return self.report(format!("term {:?} has a doc_freq of {} in {} blocks", term, doc_freq, num_blocks));
+        }
+        let expected_last_block = doc_freq - (doc_freq - 1) / self.block_size * self.block_size;
+        if num_blocks != doc_freq.div_ceil(self.block_size) || metadata.num_posting_in_last_block as usize != expected_last_block {
+            self.report(format!("term {:?} has {} blocks with {} postings in the last one, a doc_freq of {} needs {} with {}",
+                                term, num_blocks, metadata.num_posting_in_last_block, doc_freq,
+                                doc_freq.div_ceil(self.block_size), expected_last_block));
+        }
+        if metadata.term_start_pointer != metadata.block_offsets[0] {
+            self.report(format!("term {:?} starts at {} but its first block is at {}",
+                                term, metadata.term_start_pointer, metadata.block_offsets[0]));
+        }
+
+        let mut postings = 0;
+        let mut total_term_freq = 0u64;
+        let mut last_doc_id: Option<u32> = None;
+        let mut missing_docs = Vec::new();
+        let mut long_docs = Vec::new();
+        let mut block_end = metadata.block_offsets[0];
+        for block_index in 0..num_blocks {
+            let block_postings = if block_index == num_blocks - 1 {
+                metadata.num_posting_in_last_block as usize
+            } else {
+                self.block_size
+            };
+            let block_offset = metadata.block_offsets[block_index];
+            if block_offset != block_end {
+                self.report(format!("block {} of term {:?} is at {} instead of {}, right after the previous block",
+                                    block_index, term, block_offset, block_end));
+            }
+
+            // The next blocks of the term are still checked past an unreadable one, their docids following its maximum
+            let next_block = metadata.block_offsets.get(block_index + 1).copied().unwrap_or(block_end);
+            let docids_size = metadata.compressed_docids_per_block[block_index];
+            let Some(docids) = self.encoded_block(&format!("docids of block {} of term {:?}", block_index, term), index,
+                                                      block_offset, docids_size, block_postings) else {
+                (last_doc_id, block_end) = (Some(metadata.block_maxima[block_index]), next_block);
+                continue;
+            };
+            let frequencies_offset = block_offset + docids_size;
+            let Some(frequencies) = slice(index, frequencies_offset, 4 * block_postings as u64) else {
+                self.report(format!("frequencies of block {} of term {:?} are past the end of the index", block_index, term));
+                (last_doc_id, block_end) = (Some(metadata.block_maxima[block_index]), next_block);
+                continue;
+            };
+            let frequencies: Vec<u32> = frequencies.chunks_exact(4).map(LittleEndian::read_u32).collect();
+            block_end = frequencies_offset + 4 * block_postings as u64;
+
+            // Gaps from the previous docid of the term, the first docid and the ones after a docid 0 are not gaps.
+            // Like in the query processor, the first docid of a block follows the maximum of the previous block,
+            // so a corrupt block does not shift the docids of the next ones.
+            let mut block_doc_ids = Vec::with_capacity(block_postings);
+            let mut previous_doc_id = block_index.checked_sub(1).map(|previous| metadata.block_maxima[previous]);
+            for (&gap, &freq) in docids.iter().zip(&frequencies) {
+                let doc_id = match previous_doc_id {
+                    Some(previous) if previous != 0 => previous.wrapping_add(gap),
+                    _ => gap,
+                };
+                previous_doc_id = Some(doc_id);
+                // Every docid out of order is reported, the check goes on with the next ones
+                if let Some(previous) = last_doc_id.filter(|&previous| doc_id <= previous) {
+                    self.report(format!("block {} of term {:?} has docid {} after docid {}, docids are not strictly increasing",
+                                        block_index, term, doc_id, previous));
+                }
+                if freq == 0 {
+                    self.report(format!("docid {} of term {:?} has a frequency of 0", doc_id, term));
+                }
+                // A term occurs at most once per position of the document
+                match doc_table.map(|doc_table| doc_table.length(doc_id)) {
+                    Some(None) => missing_docs.push(doc_id),
+                    Some(Some(length)) if freq > length => long_docs.push(doc_id),
+                    _ => {},
+                }
+                total_term_freq += freq as u64;
+                last_doc_id = Some(doc_id);
+                block_doc_ids.push(doc_id);
+                postings += 1;
+            }
+            if let Some(&block_last_doc_id) = block_doc_ids.last() {
+                if metadata.block_maxima[block_index] != block_last_doc_id {
+                    self.report(format!("block {} of term {:?} has a maximum of {} but ends with docid {}",
+                                        block_index, term, metadata.block_maxima[block_index], block_last_doc_id));
+                }
+            }
+
+            if has_positions {
+                let positions_size = metadata.compressed_positions_per_block[block_index];
+                let total_positions = frequencies.iter().map(|&freq| freq as usize).sum();
+                // The docids do not depend on the positions, the next blocks are still checked
+                let gaps = self.encoded_block(&format!("positions of block {} of term {:?}", block_index, term), index,
+                                              block_end, positions_size, total_positions);
+                block_end += positions_size;
+
+                if let Some(gaps) = gaps {
+                    let mut start = 0;
+                    for (&freq, doc_id) in frequencies.iter().zip(block_doc_ids) {
+                        // The first position may be 0, the gaps after it may not
+                        if gaps[start..start + freq as usize].iter().skip(1).any(|&gap| gap == 0) {
+                            self.report(format!("positions of docid {} in term {:?} are not strictly increasing", doc_id, term));
+                        }
+                        start += freq as usize;
+                    }
+                }
+            }
+        }
+
+        if postings != doc_freq {
+            self.report(format!("term {:?} decodes to {} postings but has a doc_freq of {}", term, postings, doc_freq));
+        }
+        if last_doc_id != Some(metadata.last_doc_id) {
+            self.report(format!("term {:?} ends with docid {} but has a last_doc_id of {}",
+                                term, last_doc_id.unwrap_or_default(), metadata.last_doc_id));
+        }
+        if total_term_freq != metadata.total_term_freq as u64 {
+            self.report(format!("term {:?} has frequencies summing to {} but a total_term_freq of {}",
+                                term, total_term_freq, metadata.total_term_freq));
+        }
+        if let Some(first) = missing_docs.first() {
+            self.report(format!("term {:?} has {} docids not in the doc table, the first is {}", term, missing_docs.len(), first));
+        }
+        if let Some(first) = long_docs.first() {
+            self.report(format!("term {:?} has {} postings with a frequency above the document length, the first in docid {}",
+                                term, long_docs.len(), first));
+        }
+        self.verification.postings += postings as u64;
+    }
+
+    // The values of a stream vbyte encoded array of the block, None if its size does not match its control bytes
+    fn encoded_block(&mut self, description: &str, index: &[u8], offset: u64, size: u64, count: usize) -> Option<Vec<u32>> {
+        let Some(encoded) = slice(index, offset, size) else {
+            self.report(format!("{} are past the end of the index", description));
+            return None;
+        };
+        match encoded_length(encoded, count) {
+            Some(length) if length == encoded.len() => {
+                let mut values = vec![0u32; count];
+                decode::<Scalar>(encoded, count, &mut values);
+                Some(values)
+            },
+            length => {
+                self.report(format!("{} take {} bytes, {} values need {}", description, size, count,
+                                    length.map_or("more".to_string(), |length| length.to_string())));
+                None
+            },
+        }
+    }
+}
+
+// Bytes of the index at the offset, None past its end
+fn slice(index: &[u8], offset: u64, length: u64) -> Option<&[u8]> {
+    let end = offset.checked_add(length)?;
+    index.get(usize::try_from(offset).ok()?..usize::try_from(end).ok()?)
+}
+
+// Length of the stream vbyte encoding of count values, from its control bytes: two bits per value, the length
+// of the value in bytes minus one. None if the control bytes are cut off.
+fn encoded_length(encoded: &[u8], count: usize) -> Option<usize> {
+    let control_bytes = count.div_ceil(4);
+    let controls = encoded.get(..control_bytes)?;
+    let data_bytes: usize = (0..count)
+        .map(|value| (controls[value / 4] >> (2 * (value % 4)) & 0b11) as usize + 1)
+        .sum();
+    Some(control_bytes + data_bytes)
+}
+
+// Term of the lexicon entry at the offset
+fn entry_term(lexicon: &[u8], offset: usize) -> Option<&[u8]> {
+    let term_length = LittleEndian::read_u32(lexicon.get(offset..offset + 4)?) as usize;
+    lexicon.get(offset + 4..offset + 4 + term_length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::sources::{open_source, InputFormat, JsonlFields};
+
+    // An index of documents sharing the words "common" and "frequent", in many blocks, with a word of their own
+    fn build_index(dir: &std::path::Path) -> (IndexPaths, IndexConfig) {
+        let documents: String = (0..200)
+            .map(|i| format!("<DOC>\n<DOCNO>D{}</DOCNO>\n<TEXT>\nhttp://host{}.org/\nDocument {}\ncommon frequent word{}\n</TEXT>\n</DOC>\n",
+                             i, i, i, i))
+            .collect();
+        let input = dir.join("collection.trec");
+        fs::write(&input, documents).unwrap();
+        let paths = IndexPaths::new(&dir.join("index"));
+        let config = IndexConfig { block_size: 8, threads: Some(1), ..IndexConfig::default() };
+        let fields = JsonlFields { id: "id".to_string(), text: "contents".to_string(), title: None, url: None };
+        crate::build_index(open_source(&input, InputFormat::Trec, &fields).unwrap(), &paths, &config).unwrap();
+        (paths, config)
+    }
+
+    #[test]
+    fn a_corrupted_postings_block_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let (paths, config) = build_index(dir.path());
+        let verification = verify_index(&paths, &config).unwrap();
+        assert_eq!(verification.problems, 0);
+        assert_eq!(verification.segments, 1);
+        assert!(verification.postings >= 400);
+
+        // Flip the bytes of the docids of the first block of a term in several blocks
+        let lexicon = fs::read(paths.lexicon()).unwrap();
+        let has_positions = LittleEndian::read_u32(&lexicon[LEXICON_HAS_POSITIONS as usize..]) != 0;
+        let mut entry = &lexicon[LEXICON_HEADER..];
+        let metadata = loop {
+            let (_, metadata) = read_term_metadata(&mut entry, has_positions).unwrap();
+            if metadata.num_blocks > 1 {
+                break metadata;
+            }
+        };
+        let mut index = fs::read(paths.index()).unwrap();
+        let start = metadata.block_offsets[0] as usize;
+        for byte in &mut index[start..start + metadata.compressed_docids_per_block[0] as usize] {
+            *byte ^= 0xA5;
+        }
+        fs::write(paths.index(), index).unwrap();
+
+        let corrupted = verify_index(&paths, &config).unwrap();
+        assert!(corrupted.problems > 0);
+        // The other terms are still checked
+        assert_eq!(corrupted.terms, verification.terms);
+    }
+}